@@ -1,8 +1,22 @@
-//! This is the main module containing the main functions to pack and unpack and archive.
+//! This is the main module containing the main functions to pack, unpack, list and verify
+//! an archive.
 
-mod file;
+mod atomic;
+mod detect;
+pub(crate) mod file;
+mod gitignore;
+mod index;
+mod list;
+mod owner;
 mod pack;
+mod pattern;
+mod sanitize;
 mod unpack;
+mod verify;
 
-pub use pack::pack;
-pub use unpack::unpack;
+pub use detect::{detect_format, read_prefix, DetectedFormat, DETECT_PREFIX_SIZE};
+pub use list::{list, list_index, list_seekable};
+pub use owner::OwnerMap;
+pub use pack::{pack, pack_with_options, PackOptions};
+pub use unpack::{unpack, unpack_seekable, unpack_with_options, Overwrite, UnpackOptions};
+pub use verify::{verify, VerifyProblem, VerifyReport};
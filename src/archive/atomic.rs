@@ -0,0 +1,117 @@
+//! Atomic unpacking; files (or the whole tree) are written under temporary names, and renamed into
+//! place once they are complete. So an interrupted unpack never leaves a partially written file at
+//! its final path.
+
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context};
+
+/// Number of names tried for a temporary file, before giving up.
+const MAX_ATTEMPTS: u32 = 100;
+
+/// Create a new temporary file next to `path`, named like `.<name>.packer-1a2b3c4d`. Returns the
+/// file, opened for writing, and its path.
+pub(crate) fn create_temp_file(path: &Path) -> anyhow::Result<(File, PathBuf)> {
+    create_unique(path, |temp| {
+        let file = OpenOptions::new().write(true).create_new(true).open(temp)?;
+        Ok((file, temp.to_path_buf()))
+    })
+    .with_context(|| format!("Unable to create a temporary file for {}", path.display()))
+}
+
+/// Create a staging directory next to the output directory `output_path`, to unpack into before
+/// renaming it to the output directory. The output directory has to be empty or not exist, so
+/// that it can be replaced. Returns the path of the staging directory.
+pub(crate) fn create_staging_dir(output_path: &Path) -> anyhow::Result<PathBuf> {
+    let output_path = absolute_path(output_path)?;
+    if let Ok(mut entries) = fs::read_dir(&output_path) {
+        if entries.next().is_some() {
+            bail!(
+                "The output directory {} has to be empty to unpack into a staging directory",
+                output_path.display()
+            );
+        }
+    }
+    create_unique(&output_path, |staging| {
+        fs::create_dir(staging).map(|()| staging.to_path_buf())
+    })
+    .with_context(|| {
+        format!(
+            "Unable to create a staging directory for {}",
+            output_path.display()
+        )
+    })
+}
+
+/// Replace the (empty or missing) output directory with the staging directory.
+pub(crate) fn finish_staging(staging: &Path, output_path: &Path, sync: bool) -> anyhow::Result<()> {
+    let output_path = absolute_path(output_path)?;
+    log::debug!(
+        "Renaming staging directory {} to {}",
+        staging.display(),
+        output_path.display()
+    );
+    fs::rename(staging, &output_path).with_context(|| {
+        format!(
+            "Unable to rename the staging directory {} to {}",
+            staging.display(),
+            output_path.display()
+        )
+    })?;
+    if sync {
+        sync_parent(&output_path)?;
+    }
+    Ok(())
+}
+
+/// Flush the directory containing `path` to disk, so that a rename into it is durable.
+pub(crate) fn sync_parent(path: &Path) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent() {
+        File::open(parent)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Absolute path without `.` or `..`, of a path which may not exist; though its parent has to.
+fn absolute_path(path: &Path) -> anyhow::Result<PathBuf> {
+    if let Ok(path) = path.canonicalize() {
+        return Ok(path);
+    }
+    let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+        bail!("Invalid output path: {}", path.display());
+    };
+    let parent = if parent.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        parent
+    };
+    Ok(parent.canonicalize()?.join(name))
+}
+
+/// Create something new at a unique temporary path next to `path` with `create`; trying other
+/// names while the path already exists.
+fn create_unique<T>(path: &Path, create: impl Fn(&Path) -> io::Result<T>) -> anyhow::Result<T> {
+    let name = path
+        .file_name()
+        .with_context(|| format!("{} has no file name", path.display()))?;
+    let seed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.subsec_nanos())
+        ^ std::process::id().rotate_left(16);
+    for attempt in 0..MAX_ATTEMPTS {
+        let suffix = seed.wrapping_add(attempt.wrapping_mul(0x9e37_79b9));
+        let mut temp_name = OsString::from(".");
+        temp_name.push(name);
+        temp_name.push(format!(".packer-{:08x}", suffix));
+        match create(&path.with_file_name(temp_name)) {
+            Ok(created) => return Ok(created),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(err.into()),
+        }
+    }
+    bail!("All the temporary names are taken")
+}
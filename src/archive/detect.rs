@@ -0,0 +1,89 @@
+//! Detection of the format of an archive, from its first bytes.
+
+use std::io::{self, Read};
+
+/// Number of bytes at the start of an archive, needed to detect its format.
+pub const DETECT_PREFIX_SIZE: usize = 512;
+
+/// Format of an archive, as detected by [`detect_format`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DetectedFormat {
+    /// A BAG archive; starts with the "BAG AF." preamble.
+    Bag,
+    /// A tar archive; has the ustar magic in its first header.
+    Tar,
+    /// A gzip compressed stream.
+    Gzip,
+    /// A zstd compressed stream.
+    Zstd,
+    /// An xz compressed stream.
+    Xz,
+    /// A bzip2 compressed stream.
+    Bzip2,
+    /// A zip archive.
+    Zip,
+}
+
+/// Detect the format of an archive from its first [`DETECT_PREFIX_SIZE`] bytes (or fewer, if the
+/// archive is shorter than that). Returns `None` if the format is not recognized.
+pub fn detect_format(prefix: &[u8]) -> Option<DetectedFormat> {
+    // the magic of both POSIX ustar ("ustar\0") and GNU tar ("ustar ") headers
+    let is_tar = prefix.get(257..262) == Some(b"ustar");
+    if prefix.starts_with(b"BAG AF.") {
+        Some(DetectedFormat::Bag)
+    } else if is_tar {
+        Some(DetectedFormat::Tar)
+    } else if prefix.starts_with(&[0x1f, 0x8b]) {
+        Some(DetectedFormat::Gzip)
+    } else if prefix.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        Some(DetectedFormat::Zstd)
+    } else if prefix.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+        Some(DetectedFormat::Xz)
+    } else if prefix.starts_with(b"BZh") {
+        Some(DetectedFormat::Bzip2)
+    } else if prefix.starts_with(b"PK\x03\x04") || prefix.starts_with(b"PK\x05\x06") {
+        Some(DetectedFormat::Zip)
+    } else {
+        None
+    }
+}
+
+/// Read the first [`DETECT_PREFIX_SIZE`] bytes of an archive from `reader`, for
+/// [`detect_format`]. Fewer bytes are returned only if the archive is shorter than that.
+///
+/// The bytes are consumed from the reader. To read the archive after this, either seek back to
+/// the start, or chain the reader after the returned bytes.
+pub fn read_prefix<R: Read>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let mut prefix = Vec::with_capacity(DETECT_PREFIX_SIZE);
+    reader
+        .by_ref()
+        .take(DETECT_PREFIX_SIZE as u64)
+        .read_to_end(&mut prefix)?;
+    Ok(prefix)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_format() {
+        let mut tar = vec![0u8; 512];
+        tar[257..263].copy_from_slice(b"ustar\0");
+        assert_eq!(detect_format(&tar), Some(DetectedFormat::Tar));
+        tar[257..263].copy_from_slice(b"ustar ");
+        assert_eq!(detect_format(&tar), Some(DetectedFormat::Tar));
+
+        assert_eq!(detect_format(b"BAG AF.\x01"), Some(DetectedFormat::Bag));
+        assert_eq!(detect_format(&[0x1f, 0x8b, 8]), Some(DetectedFormat::Gzip));
+        assert_eq!(
+            detect_format(&[0x28, 0xb5, 0x2f, 0xfd]),
+            Some(DetectedFormat::Zstd)
+        );
+        assert_eq!(detect_format(b"\xfd7zXZ\0"), Some(DetectedFormat::Xz));
+        assert_eq!(detect_format(b"BZh9"), Some(DetectedFormat::Bzip2));
+        assert_eq!(detect_format(b"PK\x03\x04"), Some(DetectedFormat::Zip));
+        assert_eq!(detect_format(b"hello"), None);
+        assert_eq!(detect_format(&[]), None);
+    }
+}
@@ -0,0 +1,158 @@
+//! Ignore files, like `.gitignore`, respected while packing; see
+//! [`PackOptions::respect_gitignore`](super::PackOptions::respect_gitignore).
+
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use ignore::gitignore::{gitconfig_excludes_path, Gitignore, GitignoreBuilder};
+use ignore::Match;
+
+/// Ignore files of each directory, from the highest precedence.
+const IGNORE_FILES: &[&str] = &[".ignore", ".gitignore"];
+
+/// The ignore files of the directories being walked while packing; which decide the files to leave
+/// out, with the semantics of git.
+///
+/// The `.gitignore` and `.ignore` (which takes precedence) files of a directory apply to everything
+/// inside it; the ones of deeper directories taking precedence. At the root of a git repository,
+/// `.git/info/exclude` and the global excludes file of git apply as well, with a lower precedence;
+/// and the ignore files of the directories above the repository don't apply inside it.
+pub(crate) struct GitIgnores {
+    /// The global excludes file of git, i.e. `core.excludesFile` or `~/.config/git/ignore`.
+    global: Option<Gitignore>,
+    /// Ignore files of the directories being walked, from the outermost.
+    frames: Vec<Frame>,
+}
+
+/// Ignore files of a directory.
+struct Frame {
+    /// The paths of the files inside the directory are relative to this path; which is the
+    /// directory itself, or the directory containing an input file for the directories above it.
+    base: PathBuf,
+    /// Path of `base`, relative to the directory.
+    prefix: PathBuf,
+    /// Ignore files of the directory, from the highest precedence.
+    matchers: Vec<Gitignore>,
+    /// `.git/info/exclude` and the global excludes; only for the root of a repository.
+    repo_excludes: Option<Vec<Gitignore>>,
+}
+
+impl GitIgnores {
+    pub(crate) fn new() -> Self {
+        Self {
+            global: gitconfig_excludes_path().and_then(|path| load(&path)),
+            frames: vec![],
+        }
+    }
+
+    /// Start walking an input file; loading the ignore files of the directories above it, up to
+    /// the root of its repository (if it is in one).
+    pub(crate) fn enter_input(&mut self, path: &Path) -> anyhow::Result<()> {
+        let parent = path.parent().unwrap_or(Path::new(""));
+        let dir = if parent.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            parent
+        };
+        let dir = dir
+            .canonicalize()
+            .with_context(|| format!("Unable to resolve the directory of {}", path.display()))?;
+        let Some(repo) = dir
+            .ancestors()
+            .find(|ancestor| ancestor.join(".git").exists())
+        else {
+            return Ok(());
+        };
+        let mut ancestors: Vec<&Path> = dir
+            .ancestors()
+            .take_while(|ancestor| ancestor.starts_with(repo))
+            .collect();
+        ancestors.reverse();
+        for ancestor in ancestors {
+            let prefix = dir.strip_prefix(ancestor)?.to_path_buf();
+            let frame = self.frame(ancestor, parent.to_path_buf(), prefix);
+            self.frames.push(frame);
+        }
+        Ok(())
+    }
+
+    /// Done walking an input file.
+    pub(crate) fn leave_input(&mut self) {
+        self.frames.clear();
+    }
+
+    /// Start walking a directory; loading its ignore files.
+    pub(crate) fn enter_dir(&mut self, path: &Path) {
+        let frame = self.frame(path, path.to_path_buf(), PathBuf::new());
+        self.frames.push(frame);
+    }
+
+    /// Done walking a directory.
+    pub(crate) fn leave_dir(&mut self) {
+        self.frames.pop();
+    }
+
+    fn frame(&self, dir: &Path, base: PathBuf, prefix: PathBuf) -> Frame {
+        let matchers = IGNORE_FILES
+            .iter()
+            .filter_map(|name| load(&dir.join(name)))
+            .collect();
+        let git_dir = dir.join(".git");
+        let repo_excludes = git_dir.exists().then(|| {
+            load(&git_dir.join("info/exclude"))
+                .into_iter()
+                .chain(self.global.clone())
+                .collect()
+        });
+        Frame {
+            base,
+            prefix,
+            matchers,
+            repo_excludes,
+        }
+    }
+
+    /// Whether the file at the given path is ignored. The path has to be inside the file being
+    /// walked, in the same form; i.e. joined to its path.
+    pub(crate) fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        for frame in self.frames.iter().rev() {
+            let Ok(relative) = path.strip_prefix(&frame.base) else {
+                continue;
+            };
+            let relative = frame.prefix.join(relative);
+            let excludes = frame.repo_excludes.iter().flatten();
+            for matcher in frame.matchers.iter().chain(excludes) {
+                match matcher.matched(&relative, is_dir) {
+                    Match::Ignore(_) => return true,
+                    Match::Whitelist(_) => return false,
+                    Match::None => {}
+                }
+            }
+            // the ignore files above a repository don't apply inside it
+            if frame.repo_excludes.is_some() {
+                break;
+            }
+        }
+        false
+    }
+}
+
+/// Load an ignore file, if it exists. Invalid patterns in it are skipped with a warning.
+fn load(path: &Path) -> Option<Gitignore> {
+    if !path.is_file() {
+        return None;
+    }
+    // the paths are matched relative to the directory of the ignore file; a root of `.` keeps them
+    // as they are
+    let mut builder = GitignoreBuilder::new(".");
+    if let Some(err) = builder.add(path) {
+        log::warn!("In the ignore file {}: {}", path.display(), err);
+    }
+    match builder.build() {
+        Ok(matcher) => Some(matcher),
+        Err(err) => {
+            log::warn!("Skipping the ignore file {}: {}", path.display(), err);
+            None
+        }
+    }
+}
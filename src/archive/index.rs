@@ -0,0 +1,71 @@
+//! Random access to the files of an archive, using its index.
+
+use std::io::{Read, Seek, SeekFrom};
+
+use anyhow::{bail, Context};
+
+use crate::backend::{AsHeader, IndexEntry, PackerBackend};
+
+/// Read the index of the archive, if the backend supports one and the archive has it. A broken
+/// index is not an error, as the archive can still be read without it; so it is only logged.
+/// `start` is the offset of the start of the archive in the reader; and the prologue is already
+/// read. If there is no index, the reader is left where it was.
+pub(crate) fn read_index<T: PackerBackend, R: Read + Seek>(
+    packer: &T,
+    reader: &mut R,
+    start: u64,
+) -> anyhow::Result<Option<Vec<IndexEntry>>> {
+    let position = reader.stream_position()?;
+    let index = match packer.read_index(reader, start) {
+        Ok(index) => index,
+        Err(err) => {
+            log::warn!(
+                "Unable to use the index of the archive, reading all of it instead: {:#}",
+                err
+            );
+            None
+        }
+    };
+    match &index {
+        Some(index) => log::debug!("Read the index of the archive: {} entries", index.len()),
+        None => {
+            reader.seek(SeekFrom::Start(position))?;
+        }
+    }
+    Ok(index)
+}
+
+/// Read the header of the file at the given index entry. The reader is left at the start of the
+/// data of the file.
+pub(crate) fn read_header_at<T: PackerBackend, R: Read + Seek>(
+    packer: &mut T,
+    reader: &mut R,
+    start: u64,
+    entry: &IndexEntry,
+) -> anyhow::Result<T::Header> {
+    let header = read_header(packer, reader, start + entry.header_offset)
+        .with_context(|| format!("Reading header of {}", entry.file_name.display()))?;
+    if reader.stream_position()? != start + entry.data_offset
+        || header.get_metadata().file_name != entry.file_name
+    {
+        bail!(
+            "The index doesn't match the header of {}",
+            entry.file_name.display()
+        );
+    }
+    Ok(header)
+}
+
+fn read_header<T: PackerBackend, R: Read + Seek>(
+    packer: &mut T,
+    reader: &mut R,
+    offset: u64,
+) -> anyhow::Result<T::Header> {
+    reader.seek(SeekFrom::Start(offset))?;
+    let mut header_buffer = vec![0u8; packer.header_block_size()];
+    reader.read_exact(&mut header_buffer)?;
+    if packer.is_eoa(reader, &header_buffer) {
+        bail!("Found the end of archive marker instead");
+    }
+    packer.unpack_header(reader, &header_buffer)
+}
@@ -0,0 +1,188 @@
+use std::io::{Read, Seek, SeekFrom};
+
+use anyhow::{self, Context};
+
+use super::index;
+use crate::backend::{AsHeader, FileMetadata, IndexEntry, PackerBackend};
+
+/// List the contents of an archive read from `reader` using the given packer backend, without
+/// unpacking it. Gives the metadata of each file in the archive, in the order they are stored.
+///
+/// The data of the files is read and discarded; so this works with any reader, like a pipe. For
+/// seekable readers use [`list_seekable`], which seeks past the data instead.
+pub fn list<T: PackerBackend, R: Read>(
+    packer: &mut T,
+    reader: R,
+) -> anyhow::Result<Vec<FileMetadata>> {
+    walk_headers(packer, reader, |packer, reader, header| {
+        packer.skip_data(reader, header)
+    })
+}
+
+/// List the contents of an archive read from a seekable `reader`, like a file, using the given
+/// packer backend. Same as [`list`], except that the data of the files is not read, if the backend
+/// supports seeking past it.
+pub fn list_seekable<T: PackerBackend, R: Read + Seek>(
+    packer: &mut T,
+    reader: R,
+) -> anyhow::Result<Vec<FileMetadata>> {
+    walk_headers(packer, reader, |packer, reader, header| {
+        packer.seek_data(reader, header)
+    })
+}
+
+/// List the files of an archive read from a seekable `reader` using its index, if the backend
+/// supports one and the archive has it. Only the index is read, with a single seek to it; not the
+/// headers of the files. So it gives only the path, type and size of each file; use
+/// [`list_seekable`] for all of their metadata.
+///
+/// Returns `None` if there is no index, leaving the reader where it was.
+pub fn list_index<T: PackerBackend, R: Read + Seek>(
+    packer: &mut T,
+    reader: &mut R,
+) -> anyhow::Result<Option<Vec<IndexEntry>>> {
+    let start = reader.stream_position()?;
+    packer.read_prologue(reader)?;
+    let index = index::read_index(packer, reader, start)?;
+    if index.is_none() {
+        reader.seek(SeekFrom::Start(start))?;
+    }
+    Ok(index)
+}
+
+/// Read all the headers of the archive, skipping over the data of each file with `skip_data`.
+fn walk_headers<T: PackerBackend, R: Read>(
+    packer: &mut T,
+    mut reader: R,
+    mut skip_data: impl FnMut(&T, &mut R, &T::Header) -> anyhow::Result<()>,
+) -> anyhow::Result<Vec<FileMetadata>> {
+    packer.read_prologue(&mut reader)?;
+
+    let mut entries = vec![];
+    let mut header_buffer = vec![0u8; packer.header_block_size()];
+    loop {
+        reader
+            .read_exact(&mut header_buffer)
+            .with_context(|| "Reading header")?;
+        if packer.is_eoa(&mut reader, &header_buffer) {
+            break;
+        }
+        let header = packer.unpack_header(&mut reader, &header_buffer)?;
+        skip_data(packer, &mut reader, &header)?;
+        entries.push(header.get_metadata());
+    }
+    packer.read_epilogue(&mut reader)?;
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Cursor;
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::testing::{pack_files, TestDir};
+    use crate::{BagArchive, BagCompression, FileType, TarArchive};
+
+    fn test_list<T: PackerBackend>(mut packer: T, name: &str, indexed: bool) -> anyhow::Result<()> {
+        let dir = TestDir::new(name)?;
+        dir.write_files(&[("sub/file.txt", "hello world")])?;
+        std::os::unix::fs::symlink("sub/file.txt", dir.join("link"))?;
+
+        let archive = pack_files(&packer, &[dir.path().to_path_buf()])?;
+        let streamed = list(&mut packer, archive.as_slice())?;
+        let seeked = list_seekable(&mut packer, Cursor::new(&archive))?;
+
+        // the index gives the path, type and size of the files, in the order they are stored
+        let mut reader = Cursor::new(&archive);
+        let index = list_index(&mut packer, &mut reader)?;
+        assert_eq!(index.is_some(), indexed);
+        if let Some(index) = index {
+            let index: Vec<_> = index
+                .into_iter()
+                .map(|entry| (entry.file_name, entry.file_type, entry.file_size))
+                .collect();
+            let stored: Vec<_> = streamed
+                .iter()
+                .map(|entry| (entry.file_name.clone(), entry.file_type, entry.file_size))
+                .collect();
+            assert_eq!(index, stored);
+        } else {
+            assert_eq!(reader.position(), 0);
+        }
+
+        for entries in [streamed, seeked] {
+            let mut entries: Vec<_> = entries
+                .iter()
+                .map(|entry| (entry.file_name.clone(), entry.file_type, entry.file_size))
+                .collect();
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+            let names = |path: &str| PathBuf::from(name).join(path);
+            assert_eq!(entries.len(), 4);
+            assert_eq!(entries[1], (names("link"), FileType::SymLink, 0));
+            assert_eq!(entries[3], (names("sub/file.txt"), FileType::Regular, 11));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_list_bag() -> anyhow::Result<()> {
+        test_list(BagArchive::new(), "packer_list_bag", true)?;
+        test_list(
+            BagArchive::new().with_index(false),
+            "packer_list_bag_no_index",
+            false,
+        )
+    }
+
+    #[test]
+    fn test_list_bag_compressed() -> anyhow::Result<()> {
+        let packer = BagArchive::with_compression(BagCompression::Zstd, None)?;
+        test_list(packer, "packer_list_bag_compressed", true)
+    }
+
+    /// V1 bag archives store the length of the target as the size of symlinks, with no data after
+    /// their headers.
+    #[test]
+    fn test_list_bag_v1() -> anyhow::Result<()> {
+        let archive = include_bytes!("../../tests/fixtures/v1-symlink.bag");
+        let mut packer = BagArchive::new();
+        let streamed = list(&mut packer, archive.as_slice())?;
+        let seeked = list_seekable(&mut packer, Cursor::new(archive))?;
+        assert!(list_index(&mut packer, &mut Cursor::new(archive))?.is_none());
+        for entries in [streamed, seeked] {
+            let entries: Vec<_> = entries
+                .iter()
+                .map(|entry| (entry.file_name.clone(), entry.file_type))
+                .collect();
+            assert_eq!(
+                entries,
+                [
+                    (PathBuf::from("in/d/z.txt"), FileType::Regular),
+                    (PathBuf::from("in/d/link"), FileType::SymLink),
+                    (PathBuf::from("in/d/a.txt"), FileType::Regular),
+                ]
+            );
+        }
+
+        let report = crate::verify(&mut packer, archive.as_slice());
+        assert!(report.is_ok(), "{:?}", report.problems);
+
+        let dir = TestDir::new("packer_list_bag_v1")?;
+        let output = dir.path().to_path_buf();
+        let options = crate::UnpackOptions {
+            paths: vec!["in/d/a.txt".to_string()],
+            ..Default::default()
+        };
+        crate::unpack_with_options(&mut packer, archive.as_slice(), output.clone(), &options)?;
+        assert_eq!(fs::read(output.join("in/d/a.txt"))?, b"first\n");
+        assert!(!output.join("in/d/z.txt").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_list_tar() -> anyhow::Result<()> {
+        test_list(TarArchive::new(), "packer_list_tar", false)
+    }
+}
@@ -0,0 +1,168 @@
+//! Mapping of the owners and groups of the files in an archive to the users and groups of this
+//! system.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{bail, Context};
+use nix::unistd::{Group, User};
+
+/// An explicit mapping of the users (or groups) of the files in an archive to the ones on this
+/// system; used while unpacking.
+///
+/// It is read from a file with a mapping on each line, like `alice bob` or `+1000 2000`. The first
+/// field is the name in the archive, or its numeric ID prefixed with `+`. The second field is the
+/// user (or group) to map it to; a name on this system, or a numeric ID. Empty lines and lines
+/// starting with `#` are ignored.
+#[derive(Debug, Default, Clone)]
+pub struct OwnerMap {
+    names: HashMap<String, u32>,
+    ids: HashMap<u32, u32>,
+}
+
+impl OwnerMap {
+    /// Read a mapping of users from the file at `path`.
+    pub fn read_users(path: &Path) -> anyhow::Result<Self> {
+        Self::read(path, user_id)
+    }
+
+    /// Read a mapping of groups from the file at `path`.
+    pub fn read_groups(path: &Path) -> anyhow::Result<Self> {
+        Self::read(path, group_id)
+    }
+
+    fn read(path: &Path, lookup: fn(&str) -> Option<u32>) -> anyhow::Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Reading the mapping file {}", path.display()))?;
+        Self::parse(&text, lookup).with_context(|| format!("In {}", path.display()))
+    }
+
+    fn parse(text: &str, lookup: fn(&str) -> Option<u32>) -> anyhow::Result<Self> {
+        let mut map = Self::default();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [source, target] = fields[..] else {
+                bail!("Line {}: expected two fields, like 'alice bob'", number + 1);
+            };
+            let target = match target.parse() {
+                Ok(id) => id,
+                Err(_) => lookup(target)
+                    .with_context(|| format!("Line {}: no such name {}", number + 1, target))?,
+            };
+            match source.strip_prefix('+') {
+                Some(id) => {
+                    let id = id
+                        .parse()
+                        .with_context(|| format!("Line {}: invalid ID {}", number + 1, source))?;
+                    map.ids.insert(id, target);
+                }
+                None => {
+                    map.names.insert(source.to_string(), target);
+                }
+            }
+        }
+        Ok(map)
+    }
+
+    /// The ID to map the given name or ID to. A mapping of the name takes precedence.
+    fn get(&self, name: Option<&str>, id: u32) -> Option<u32> {
+        name.and_then(|name| self.names.get(name))
+            .or_else(|| self.ids.get(&id))
+            .copied()
+    }
+}
+
+/// Resolves the owners (or groups) of the files in an archive to the IDs on this system.
+pub(crate) struct OwnerResolver {
+    map: OwnerMap,
+    numeric: bool,
+    lookup: fn(&str) -> Option<u32>,
+    /// IDs of the names on this system, looked up only once per name. `None` for names which
+    /// don't exist here.
+    cache: HashMap<String, Option<u32>>,
+}
+
+impl OwnerResolver {
+    /// Resolver of users; with an explicit mapping, and whether to ignore the names otherwise.
+    pub(crate) fn users(map: OwnerMap, numeric: bool) -> Self {
+        Self::new(map, numeric, user_id)
+    }
+
+    /// Resolver of groups; with an explicit mapping, and whether to ignore the names otherwise.
+    pub(crate) fn groups(map: OwnerMap, numeric: bool) -> Self {
+        Self::new(map, numeric, group_id)
+    }
+
+    fn new(map: OwnerMap, numeric: bool, lookup: fn(&str) -> Option<u32>) -> Self {
+        Self {
+            map,
+            numeric,
+            lookup,
+            cache: HashMap::new(),
+        }
+    }
+
+    /// The ID on this system for the given name and ID in the archive. The explicit mapping is
+    /// used first; then the name, if it exists on this system; and the ID as it is otherwise.
+    pub(crate) fn resolve(&mut self, name: Option<&str>, id: u32) -> u32 {
+        if let Some(mapped) = self.map.get(name, id) {
+            return mapped;
+        }
+        match name {
+            Some(name) if !self.numeric => {
+                let lookup = self.lookup;
+                let local = self
+                    .cache
+                    .entry(name.to_string())
+                    .or_insert_with(|| lookup(name));
+                local.unwrap_or(id)
+            }
+            _ => id,
+        }
+    }
+}
+
+fn user_id(name: &str) -> Option<u32> {
+    User::from_name(name)
+        .ok()
+        .flatten()
+        .map(|user| user.uid.as_raw())
+}
+
+fn group_id(name: &str) -> Option<u32> {
+    Group::from_name(name)
+        .ok()
+        .flatten()
+        .map(|group| group.gid.as_raw())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_owner_resolver() -> anyhow::Result<()> {
+        let text = "# comment\n\nalice root\n+1000 2000\n";
+        let map = OwnerMap::parse(text, user_id)?;
+        let mut users = OwnerResolver::users(map.clone(), false);
+        assert_eq!(users.resolve(Some("alice"), 1234), 0);
+        assert_eq!(users.resolve(None, 1000), 2000);
+        assert_eq!(users.resolve(Some("root"), 1234), 0);
+        assert_eq!(users.resolve(Some("packer-no-such-user"), 1234), 1234);
+
+        // the mapping still applies to numeric owners
+        let mut users = OwnerResolver::users(map, true);
+        assert_eq!(users.resolve(Some("alice"), 1234), 0);
+        assert_eq!(users.resolve(Some("root"), 1234), 1234);
+
+        assert!(OwnerMap::parse("alice", user_id).is_err());
+        assert!(OwnerMap::parse("alice packer-no-such-user", user_id).is_err());
+        assert!(OwnerMap::parse("+alice 1", user_id).is_err());
+        Ok(())
+    }
+}
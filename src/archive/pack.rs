@@ -1,21 +1,82 @@
-use std::fs::File;
-use std::fs::{self};
-use std::io::{BufWriter, Write};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufReader, Write};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
 use std::path::{Path, PathBuf};
 
 use anyhow::{self, bail, Context};
 
-use crate::archive::file::read_file_chunked;
-use crate::backend::{FilePath, PackerBackend};
+use super::gitignore::GitIgnores;
+use super::pattern::FilterPatterns;
+use crate::backend::{FilePath, FileType, PackerBackend};
 
-pub fn pack<T: PackerBackend>(
+/// Directories skipped by [`PackOptions::exclude_vcs`]; the ones of version control systems, and
+/// the build output of Cargo.
+const VCS_PATTERNS: &[&str] = &[".git/", ".hg/", ".svn/", ".bzr/", "target/"];
+
+/// Options to choose which files are packed; see [`pack_with_options`].
+///
+/// The patterns are globs matched against the paths of the files in the archive, like
+/// `mydir/logs/*.log`. A pattern without a `/`, like `*.log` or `.cache`, matches a file by its
+/// name at any depth. A pattern ending with `/` matches only directories. A matching directory
+/// covers everything inside it.
+#[derive(Debug, Default, Clone)]
+pub struct PackOptions {
+    /// Patterns of the files to leave out. The directories they match are not read at all.
+    pub exclude: Vec<String>,
+    /// Patterns of the files to pack; if any are given, the other files are left out. Directories
+    /// are still walked to find matching files, but they are packed only if something in them is.
+    /// The exclude patterns take precedence.
+    pub include: Vec<String>,
+    /// Leave out the directories of version control systems (`.git`, `.hg`, `.svn`, `.bzr`) and
+    /// build outputs (`target`).
+    pub exclude_vcs: bool,
+    /// Leave out the files ignored by git; by the `.gitignore` and `.ignore` files of the
+    /// directories, `.git/info/exclude` and the global excludes file of git. The input files
+    /// themselves are always packed.
+    pub respect_gitignore: bool,
+}
+
+impl PackOptions {
+    /// Add the exclude patterns in the file at `path`; one pattern on each line. Empty lines and
+    /// lines starting with `#` are ignored.
+    pub fn read_exclude_file(&mut self, path: &Path) -> anyhow::Result<()> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Reading the exclude file {}", path.display()))?;
+        let patterns = text
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::to_string);
+        self.exclude.extend(patterns);
+        Ok(())
+    }
+}
+
+/// Create an archive using the given packer backend, writing it to `writer` and packing up the
+/// given list of files. Directories are packed recursively. Each file is stored in the archive with
+/// its file name as the root, i.e. `/some/path/mydir/file.txt` is stored as `mydir/file.txt`.
+///
+/// The writer can be anything that implements [`Write`]; a file, an in-memory buffer, a pipe etc.
+/// For files it is recommended to wrap them in a [`std::io::BufWriter`].
+///
+/// Files with multiple hard links are stored once; any other path linking to the same file is
+/// stored as a hard link entry to the first one.
+pub fn pack<T: PackerBackend, W: Write>(
     packer: &T,
-    archive_path: PathBuf,
+    writer: W,
     files: &[PathBuf],
 ) -> anyhow::Result<()> {
-    let outfile = File::create(archive_path)?;
-    let mut writer = BufWriter::new(outfile);
+    pack_with_options(packer, writer, files, &PackOptions::default())
+}
 
+/// Same as [`pack`], but packing only the files chosen by the given options.
+pub fn pack_with_options<T: PackerBackend, W: Write>(
+    packer: &T,
+    mut writer: W,
+    files: &[PathBuf],
+    options: &PackOptions,
+) -> anyhow::Result<()> {
     let file_defs = files
         .iter()
         .map(|fp| {
@@ -30,27 +91,84 @@ pub fn pack<T: PackerBackend>(
         })
         .collect::<Result<Vec<_>, anyhow::Error>>()?;
 
+    let mut packing = Packing::new(options)?;
     packer.write_prologue(&mut writer)?;
-    process_files(packer, &mut writer, &file_defs)?;
+    for file_def in &file_defs {
+        if let Some(gitignores) = &mut packing.gitignores {
+            gitignores.enter_input(&file_def.system_path)?;
+        }
+        process_file(packer, &mut writer, file_def, &mut packing, false)?;
+        if let Some(gitignores) = &mut packing.gitignores {
+            gitignores.leave_input();
+        }
+    }
     packer.write_epilogue(&mut writer)?;
+    writer.flush()?;
     Ok(())
 }
 
-fn process_files<T: PackerBackend>(
+/// State of packing the files of an archive.
+struct Packing {
+    exclude: FilterPatterns,
+    include: FilterPatterns,
+    /// Ignore files of the directories being walked, if they are respected.
+    gitignores: Option<GitIgnores>,
+    /// archive paths of the files with multiple hard links packed so far, by (device, inode)
+    hard_links: HashMap<(u64, u64), PathBuf>,
+    /// Directories being walked, which are not packed yet; as nothing inside them is included so
+    /// far.
+    pending_dirs: Vec<(FilePath, fs::Metadata)>,
+}
+
+impl Packing {
+    fn new(options: &PackOptions) -> anyhow::Result<Self> {
+        let mut exclude = FilterPatterns::new(&options.exclude)?;
+        if options.exclude_vcs {
+            let vcs: Vec<String> = VCS_PATTERNS.iter().map(|p| p.to_string()).collect();
+            exclude.extend(FilterPatterns::new(&vcs)?);
+        }
+        Ok(Self {
+            exclude,
+            include: FilterPatterns::new(&options.include)?,
+            gitignores: options.respect_gitignore.then(GitIgnores::new),
+            hard_links: HashMap::new(),
+            pending_dirs: Vec::new(),
+        })
+    }
+
+    /// Pack the directories which are not packed yet, as something inside them is about to be.
+    fn pack_pending_dirs<T: PackerBackend, W: Write>(
+        &mut self,
+        packer: &T,
+        writer: &mut W,
+    ) -> anyhow::Result<()> {
+        for (file_def, metadata) in self.pending_dirs.drain(..) {
+            packer.pack_header(writer, &file_def, metadata, FileType::Directory, None)?;
+        }
+        Ok(())
+    }
+}
+
+fn process_files<T: PackerBackend, W: Write>(
     packer: &T,
-    writer: &mut BufWriter<File>,
+    writer: &mut W,
     filepaths: &[FilePath],
+    packing: &mut Packing,
+    // whether a parent directory matches an include pattern
+    included: bool,
 ) -> anyhow::Result<()> {
     for filepath in filepaths {
-        process_file(packer, writer, filepath)?;
+        process_file(packer, writer, filepath, packing, included)?;
     }
     Ok(())
 }
 
-fn process_file<T: PackerBackend>(
+fn process_file<T: PackerBackend, W: Write>(
     packer: &T,
-    writer: &mut BufWriter<File>,
+    writer: &mut W,
     file_def: &FilePath,
+    packing: &mut Packing,
+    included: bool,
 ) -> anyhow::Result<()> {
     log::debug!("Processing file: {}", file_def.archive_path.display());
     // read file metadata
@@ -61,9 +179,45 @@ fn process_file<T: PackerBackend>(
         )
     })?;
 
-    // if the file is a directory, get the top-level files, and recursively
-    // process those files.
+    // excluded files are skipped before anything else; so excluded directories are never read
+    if packing
+        .exclude
+        .is_match(&file_def.archive_path, metadata.is_dir())
+    {
+        log::debug!("Excluding: {}", file_def.archive_path.display());
+        return Ok(());
+    }
+    // the input files themselves are packed even if they are ignored
+    let is_input = file_def.archive_path.components().count() == 1;
+    let ignored = packing
+        .gitignores
+        .as_ref()
+        .is_some_and(|gitignores| gitignores.is_ignored(&file_def.system_path, metadata.is_dir()));
+    if ignored && !is_input {
+        log::debug!("Ignoring: {}", file_def.archive_path.display());
+        return Ok(());
+    }
+    let included = included
+        || packing.include.is_empty()
+        || packing
+            .include
+            .is_match(&file_def.archive_path, metadata.is_dir());
+
+    // if the file is a directory, pack an entry for the directory itself; then get the top-level
+    // files, and recursively process those files. A directory which is not included itself is
+    // packed only once something inside it is.
     if metadata.is_dir() {
+        let dir_path = file_def.archive_path.clone();
+        if included {
+            packing.pack_pending_dirs(packer, writer)?;
+            packer.pack_header(writer, file_def, metadata, FileType::Directory, None)?;
+        } else {
+            let pending = FilePath {
+                archive_path: file_def.archive_path.clone(),
+                system_path: file_def.system_path.clone(),
+            };
+            packing.pending_dirs.push((pending, metadata));
+        }
         let mut sub_paths: Vec<FilePath> = vec![];
         for entry in fs::read_dir(&file_def.system_path)? {
             let entry = entry?;
@@ -79,9 +233,30 @@ fn process_file<T: PackerBackend>(
                 system_path: entry.path().to_owned(),
             });
         }
-        process_files(packer, writer, &sub_paths)?;
+        if let Some(gitignores) = &mut packing.gitignores {
+            gitignores.enter_dir(&file_def.system_path);
+        }
+        process_files(packer, writer, &sub_paths, packing, included)?;
+        if let Some(gitignores) = &mut packing.gitignores {
+            gitignores.leave_dir();
+        }
+        // nothing inside the directory was packed; so it is left out as well
+        if packing
+            .pending_dirs
+            .last()
+            .is_some_and(|(pending, _)| pending.archive_path == dir_path)
+        {
+            packing.pending_dirs.pop();
+        }
+        return Ok(());
+    }
+    if !included {
+        return Ok(());
+    }
+    packing.pack_pending_dirs(packer, writer)?;
+
     // if file is a symlink
-    } else if metadata.is_symlink() {
+    if metadata.is_symlink() {
         // To handle symlinks; two possible options -
         // ### Tar style
         // - During archive creation - it stores only the target name of the symlink and symlink
@@ -100,21 +275,326 @@ fn process_file<T: PackerBackend>(
         // --------
         // Going for TAR style as the first implementation.
         let target = fs::read_link(&file_def.system_path)?;
-        let _file_size = packer.pack_header(writer, file_def, metadata, Some(target))?;
+        packer.pack_header(writer, file_def, metadata, FileType::SymLink, Some(target))?;
     // if file is a regular file, then proceed with the base case
     } else if metadata.is_file() {
-        let file_size = packer.pack_header(writer, file_def, metadata, None)?;
+        // if the file has multiple hard links, and one of them is already packed; then only store
+        // a link to it.
+        if metadata.nlink() > 1 {
+            let key = (metadata.dev(), metadata.ino());
+            if let Some(target) = packing.hard_links.get(&key) {
+                log::debug!("Hard link to: {}", target.display());
+                let target = Some(target.clone());
+                packer.pack_header(writer, file_def, metadata, FileType::HardLink, target)?;
+                return Ok(());
+            }
+            packing
+                .hard_links
+                .insert(key, file_def.archive_path.clone());
+        }
+        let file_size = packer.pack_header(writer, file_def, metadata, FileType::Regular, None)?;
         // once header is packed; pack the source file into the archive.
 
-        // log::trace!("Open file for reading data..");
+        log::trace!("Open file for reading data..");
         // open the current file for reading
-        read_file_chunked(&file_def.system_path, file_size, |data| {
-            writer.write_all(data)?;
-            log::trace!("Wrote data to file..");
-            Ok(())
-        })?;
+        let mut reader = BufReader::new(File::open(&file_def.system_path)?);
+        packer.pack_data(writer, &mut reader, file_size)?;
+    // if file is a special file, only its header is packed
+    } else if let Some(file_type) = special_file_type(&metadata) {
+        packer.pack_header(writer, file_def, metadata, file_type, None)?;
+    // sockets only make sense while the process that created them is running, so they are skipped
+    } else if metadata.file_type().is_socket() {
+        log::warn!("Skipping socket: {}", file_def.system_path.display());
     } else {
-        bail!("Unknown file type. Only regular files, directories and symlinks are supported.");
+        bail!("Unknown file type of: {}", file_def.system_path.display());
     }
     Ok(())
 }
+
+/// Get the type of a FIFO or a character/block device.
+fn special_file_type(metadata: &fs::Metadata) -> Option<FileType> {
+    let file_type = metadata.file_type();
+    if file_type.is_fifo() {
+        Some(FileType::Fifo)
+    } else if file_type.is_char_device() {
+        Some(FileType::CharDevice)
+    } else if file_type.is_block_device() {
+        Some(FileType::BlockDevice)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+    use super::*;
+    use crate::testing::{archive_paths, pack_files, roundtrip, TestDir};
+    use crate::{unpack, BagArchive, BagCompression, TarArchive};
+
+    fn test_hard_links_roundtrip<T: PackerBackend>(
+        mut packer: T,
+        name: &str,
+    ) -> anyhow::Result<()> {
+        let dir = TestDir::new(name)?;
+        dir.write_files(&[("input/file.txt", "hello world")])?;
+        let input = dir.join("input");
+        fs::create_dir(input.join("sub"))?;
+        fs::hard_link(input.join("file.txt"), input.join("sub/link.txt"))?;
+
+        let output = dir.join("output");
+        let archive = roundtrip(&mut packer, &input, &output)?;
+        // the data is stored only once
+        let occurrences = archive
+            .windows(b"hello world".len())
+            .filter(|window| window == b"hello world")
+            .count();
+        assert_eq!(occurrences, 1);
+
+        let file = fs::metadata(output.join("input/file.txt"))?;
+        let link = fs::metadata(output.join("input/sub/link.txt"))?;
+        assert_eq!(file.ino(), link.ino());
+        assert_eq!(file.nlink(), 2);
+        assert_eq!(fs::read(output.join("input/sub/link.txt"))?, b"hello world");
+        Ok(())
+    }
+
+    fn test_fifo_roundtrip<T: PackerBackend>(mut packer: T, name: &str) -> anyhow::Result<()> {
+        let dir = TestDir::new(name)?;
+        let input = dir.join("input");
+        fs::create_dir(&input)?;
+        nix::unistd::mkfifo(
+            &input.join("fifo"),
+            nix::sys::stat::Mode::from_bits_truncate(0o640),
+        )?;
+
+        let output = dir.join("output");
+        roundtrip(&mut packer, &input, &output)?;
+        let fifo = fs::symlink_metadata(output.join("input/fifo"))?;
+        assert!(fifo.file_type().is_fifo());
+        assert_eq!(fifo.mode() & 0o7777, 0o640);
+        Ok(())
+    }
+
+    /// Pack the files with the options, and get the paths of the entries of the archive.
+    fn packed_paths<T: PackerBackend>(
+        packer: &mut T,
+        input: &Path,
+        options: &PackOptions,
+    ) -> anyhow::Result<Vec<String>> {
+        let mut archive = Vec::new();
+        pack_with_options(packer, &mut archive, &[input.to_path_buf()], options)?;
+        archive_paths(packer, &archive)
+    }
+
+    fn test_filters<T: PackerBackend>(mut packer: T, name: &str) -> anyhow::Result<()> {
+        let dir = TestDir::new(name)?;
+        let files = [
+            "input/src/lib.rs",
+            "input/src/bin/main.rs",
+            "input/src/notes.txt",
+            "input/.git/HEAD",
+            "input/target/debug/app",
+            "input/docs/target",
+            "input/docs/app.log",
+            "input/logs/today.txt",
+        ];
+        dir.write_files(&files.map(|file| (file, file)))?;
+        fs::create_dir(dir.join("input/.git/objects"))?;
+        let input = dir.join("input");
+
+        let mut options = PackOptions {
+            exclude: vec!["*.log".to_string(), "input/logs".to_string()],
+            exclude_vcs: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            packed_paths(&mut packer, &input, &options)?,
+            [
+                "input",
+                "input/docs",
+                // `target/` matches only directories
+                "input/docs/target",
+                "input/src",
+                "input/src/bin",
+                "input/src/bin/main.rs",
+                "input/src/lib.rs",
+                "input/src/notes.txt",
+            ]
+        );
+
+        // only the included files are packed, with the directories leading to them
+        options.include = vec!["*.rs".to_string(), "input/docs".to_string()];
+        assert_eq!(
+            packed_paths(&mut packer, &input, &options)?,
+            [
+                "input",
+                "input/docs",
+                "input/docs/target",
+                "input/src",
+                "input/src/bin",
+                "input/src/bin/main.rs",
+                "input/src/lib.rs",
+            ]
+        );
+
+        dir.write_files(&[("exclude", "# sources\nsrc/\n\ndocs\n")])?;
+        let mut options = PackOptions::default();
+        options.read_exclude_file(&dir.join("exclude"))?;
+        assert_eq!(
+            packed_paths(&mut packer, &input, &options)?,
+            [
+                "input",
+                "input/.git",
+                "input/.git/HEAD",
+                "input/.git/objects",
+                "input/logs",
+                "input/logs/today.txt",
+                "input/target",
+                "input/target/debug",
+                "input/target/debug/app",
+            ]
+        );
+        Ok(())
+    }
+
+    fn test_gitignore<T: PackerBackend>(mut packer: T, name: &str) -> anyhow::Result<()> {
+        let dir = TestDir::new(name)?;
+        let files = [
+            "repo/src/main.rs",
+            "repo/src/debug.log",
+            "repo/src/keep.log",
+            "repo/src/build/out",
+            "repo/build/out",
+            "repo/target/app",
+            "repo/secret.txt",
+            "repo/sub/trace.log",
+            "repo/sub/scratch.tmp",
+            "repo/nested/app.log",
+        ];
+        dir.write_files(&files.map(|file| (file, file)))?;
+        dir.write_files(&[
+            ("repo/.git/info/exclude", "secret.txt\n"),
+            ("repo/.gitignore", "target/\n*.log\n!keep.log\n/build\n"),
+            // `.ignore` takes precedence over `.gitignore`
+            ("repo/sub/.gitignore", "*.tmp\n"),
+            ("repo/sub/.ignore", "!*.log\n"),
+        ])?;
+        fs::create_dir(dir.join("repo/nested/.git"))?;
+        let repo = dir.join("repo");
+
+        let options = PackOptions {
+            exclude: vec![".git".to_string()],
+            respect_gitignore: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            packed_paths(&mut packer, &repo, &options)?,
+            [
+                "repo",
+                "repo/.gitignore",
+                "repo/nested",
+                // the ignore files outside a repository don't apply inside it
+                "repo/nested/app.log",
+                "repo/src",
+                // `/build` matches only at the root
+                "repo/src/build",
+                "repo/src/build/out",
+                "repo/src/keep.log",
+                "repo/src/main.rs",
+                "repo/sub",
+                "repo/sub/.gitignore",
+                "repo/sub/.ignore",
+                "repo/sub/trace.log",
+            ]
+        );
+        // the ignore files of the directories above an input file apply too
+        assert_eq!(
+            packed_paths(&mut packer, &repo.join("src"), &options)?,
+            [
+                "src",
+                "src/build",
+                "src/build/out",
+                "src/keep.log",
+                "src/main.rs"
+            ]
+        );
+        // but not to the input file itself
+        assert_eq!(
+            packed_paths(&mut packer, &repo.join("target"), &options)?,
+            ["target", "target/app"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_compressed_bag_roundtrip() -> anyhow::Result<()> {
+        let dir = TestDir::new("packer_compressed_bag")?;
+        let data = "hello world\n".repeat(10_000);
+        dir.write_files(&[("input/file.txt", data.as_str()), ("input/empty.txt", "")])?;
+        let input = dir.join("input");
+
+        for compression in [
+            BagCompression::Zstd,
+            BagCompression::Deflate,
+            BagCompression::Xz,
+            BagCompression::Lz4,
+        ] {
+            let mut packer = BagArchive::with_compression(compression, None)?;
+            let archive = pack_files(&packer, std::slice::from_ref(&input))?;
+            assert!(archive.len() < data.len(), "{:?}", compression);
+
+            // the compression is read from the archive, not taken from the packer
+            let output = dir.join(format!("{:?}", compression));
+            fs::create_dir_all(&output)?;
+            let mut unpacker = BagArchive::new();
+            unpack(&mut unpacker, archive.as_slice(), output.clone())?;
+            assert_eq!(fs::read_to_string(output.join("input/file.txt"))?, data);
+            assert_eq!(fs::read(output.join("input/empty.txt"))?, b"");
+
+            unpack(&mut packer, archive.as_slice(), output)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_hard_links_bag() -> anyhow::Result<()> {
+        test_hard_links_roundtrip(BagArchive::new(), "packer_hard_links_bag")
+    }
+
+    #[test]
+    fn test_hard_links_tar() -> anyhow::Result<()> {
+        test_hard_links_roundtrip(TarArchive::new(), "packer_hard_links_tar")
+    }
+
+    #[test]
+    fn test_fifo_bag() -> anyhow::Result<()> {
+        test_fifo_roundtrip(BagArchive::new(), "packer_fifo_bag")
+    }
+
+    #[test]
+    fn test_fifo_tar() -> anyhow::Result<()> {
+        test_fifo_roundtrip(TarArchive::new(), "packer_fifo_tar")
+    }
+
+    #[test]
+    fn test_filters_bag() -> anyhow::Result<()> {
+        test_filters(BagArchive::new(), "packer_filters_bag")
+    }
+
+    #[test]
+    fn test_filters_tar() -> anyhow::Result<()> {
+        test_filters(TarArchive::new(), "packer_filters_tar")
+    }
+
+    #[test]
+    fn test_gitignore_bag() -> anyhow::Result<()> {
+        test_gitignore(BagArchive::new(), "packer_gitignore_bag")
+    }
+
+    #[test]
+    fn test_gitignore_tar() -> anyhow::Result<()> {
+        test_gitignore(TarArchive::new(), "packer_gitignore_tar")
+    }
+}
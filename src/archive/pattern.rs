@@ -0,0 +1,175 @@
+//! Matching of file paths in an archive against path and glob patterns.
+
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{self, Context};
+use globset::{GlobBuilder, GlobMatcher};
+
+/// A list of path or glob patterns, like `etc/app.conf` or `**/*.conf`. A pattern matches a path
+/// if it matches the path itself, or one of its parent directories; so a pattern of a directory
+/// matches everything inside it.
+#[derive(Debug)]
+pub(crate) struct PathPatterns {
+    patterns: Vec<(String, GlobMatcher)>,
+}
+
+impl PathPatterns {
+    pub(crate) fn new(patterns: &[String]) -> anyhow::Result<Self> {
+        let patterns = patterns
+            .iter()
+            .map(|pattern| {
+                let normalized = normalize(Path::new(pattern));
+                let glob = GlobBuilder::new(&normalized.to_string_lossy())
+                    // `*` and `?` don't match across directories
+                    .literal_separator(true)
+                    .build()
+                    .with_context(|| format!("Invalid pattern: {}", pattern))?;
+                Ok((pattern.clone(), glob.compile_matcher()))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(Self { patterns })
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+
+    /// Get the index of the first pattern matching the path, if any.
+    pub(crate) fn find_match(&self, path: &Path) -> Option<usize> {
+        let path = normalize(path);
+        path.ancestors()
+            .filter(|ancestor| !ancestor.as_os_str().is_empty())
+            .find_map(|ancestor| {
+                self.patterns
+                    .iter()
+                    .position(|(_, matcher)| matcher.is_match(ancestor))
+            })
+    }
+
+    /// Get the pattern at the given index, as it was given.
+    pub(crate) fn pattern(&self, index: usize) -> &str {
+        &self.patterns[index].0
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.patterns.len()
+    }
+}
+
+/// Patterns to filter the files while packing, like `*.log`, `mydir/logs` or `target/`.
+///
+/// A pattern without a `/` matches a file by its name, at any depth; other patterns match the whole
+/// path of a file in the archive. A pattern ending with `/` matches only directories. As the
+/// patterns are checked while walking the directories, a matching directory also covers everything
+/// inside it.
+#[derive(Debug, Default)]
+pub(crate) struct FilterPatterns {
+    patterns: Vec<FilterPattern>,
+}
+
+#[derive(Debug)]
+struct FilterPattern {
+    matcher: GlobMatcher,
+    /// Whether the pattern matches only the file name, instead of the whole path.
+    name_only: bool,
+    dir_only: bool,
+}
+
+impl FilterPatterns {
+    pub(crate) fn new(patterns: &[String]) -> anyhow::Result<Self> {
+        let patterns = patterns
+            .iter()
+            .map(|pattern| {
+                let dir_only = pattern.ends_with('/');
+                let normalized = normalize(Path::new(pattern));
+                let normalized = normalized.to_string_lossy();
+                let glob = GlobBuilder::new(&normalized)
+                    .literal_separator(true)
+                    .build()
+                    .with_context(|| format!("Invalid pattern: {}", pattern))?;
+                Ok(FilterPattern {
+                    matcher: glob.compile_matcher(),
+                    name_only: !normalized.contains('/'),
+                    dir_only,
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(Self { patterns })
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+
+    /// Add more patterns, like the ones of [`FilterPatterns::new`].
+    pub(crate) fn extend(&mut self, other: FilterPatterns) {
+        self.patterns.extend(other.patterns);
+    }
+
+    /// Whether any pattern matches the file at the given path in the archive.
+    pub(crate) fn is_match(&self, path: &Path, is_dir: bool) -> bool {
+        let path = normalize(path);
+        let name = path.file_name().map(Path::new);
+        self.patterns.iter().any(|pattern| {
+            if pattern.dir_only && !is_dir {
+                return false;
+            }
+            if pattern.name_only {
+                name.is_some_and(|name| pattern.matcher.is_match(name))
+            } else {
+                pattern.matcher.is_match(&path)
+            }
+        })
+    }
+}
+
+/// Normalize a path for matching; removing `.` components, any leading `/` and any trailing `/`.
+fn normalize(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|component| !matches!(component, Component::CurDir | Component::RootDir))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_path_patterns() -> anyhow::Result<()> {
+        let patterns = PathPatterns::new(&[
+            "app/config.toml".to_string(),
+            "./logs/".to_string(),
+            "*/*.conf".to_string(),
+        ])?;
+        assert_eq!(patterns.find_match(Path::new("app/config.toml")), Some(0));
+        assert_eq!(patterns.find_match(Path::new("app/other.toml")), None);
+        // everything inside a matching directory matches
+        assert_eq!(patterns.find_match(Path::new("logs/")), Some(1));
+        assert_eq!(patterns.find_match(Path::new("logs/2024/app.log")), Some(1));
+        assert_eq!(patterns.find_match(Path::new("app/nginx.conf")), Some(2));
+        // `*` doesn't match across directories
+        assert_eq!(patterns.find_match(Path::new("nginx.conf")), None);
+        assert_eq!(patterns.find_match(Path::new("a/b/nginx.conf")), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_filter_patterns() -> anyhow::Result<()> {
+        let patterns = FilterPatterns::new(&[
+            "*.log".to_string(),
+            "mydir/cache".to_string(),
+            "target/".to_string(),
+        ])?;
+        // patterns without a `/` match the name at any depth
+        assert!(patterns.is_match(Path::new("app.log"), false));
+        assert!(patterns.is_match(Path::new("mydir/logs/app.log"), false));
+        assert!(!patterns.is_match(Path::new("mydir/app.log.gz"), false));
+        // other patterns match the whole path
+        assert!(patterns.is_match(Path::new("mydir/cache"), true));
+        assert!(!patterns.is_match(Path::new("other/mydir/cache"), true));
+        // patterns ending with `/` match only directories
+        assert!(patterns.is_match(Path::new("mydir/target"), true));
+        assert!(!patterns.is_match(Path::new("mydir/target"), false));
+        Ok(())
+    }
+}
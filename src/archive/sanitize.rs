@@ -0,0 +1,62 @@
+//! Sanitization of the paths in an archive, so that unpacking it can't write outside of the output
+//! directory.
+
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::bail;
+
+/// Make a path of a file in an archive safe to unpack; giving the path relative to the output
+/// directory, and whether a leading `/` was removed from it. Paths with `..` components or NUL bytes
+/// are an error. `.` components are dropped, and so the path of the archive's root directory (like
+/// `./`) is empty.
+pub(crate) fn sanitize_path(path: &Path) -> anyhow::Result<(PathBuf, bool)> {
+    if path.as_os_str().as_bytes().contains(&0) {
+        bail!("The path {:?} contains a NUL byte", path);
+    }
+    let mut sanitized = PathBuf::new();
+    let mut stripped_root = false;
+    for component in path.components() {
+        match component {
+            Component::RootDir | Component::Prefix(_) => stripped_root = true,
+            Component::CurDir => {}
+            Component::ParentDir => bail!("The path {} contains '..'", path.display()),
+            Component::Normal(name) => sanitized.push(name),
+        }
+    }
+    Ok((sanitized, stripped_root))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sanitize_path() -> anyhow::Result<()> {
+        let sanitize = |path: &str| sanitize_path(Path::new(path));
+        assert_eq!(sanitize("dir/file")?, (PathBuf::from("dir/file"), false));
+        assert_eq!(
+            sanitize("./dir/./file")?,
+            (PathBuf::from("dir/file"), false)
+        );
+        assert_eq!(
+            sanitize("/etc/passwd")?,
+            (PathBuf::from("etc/passwd"), true)
+        );
+        assert_eq!(
+            sanitize("//etc/passwd")?,
+            (PathBuf::from("etc/passwd"), true)
+        );
+        assert_eq!(sanitize("./")?, (PathBuf::new(), false));
+        assert!(sanitize("../etc/passwd").is_err());
+        assert!(sanitize("dir/../../etc/passwd").is_err());
+        assert!(sanitize("dir/..").is_err());
+        assert!(sanitize("dir/fi\0le").is_err());
+        // `..` as part of a name is fine
+        assert_eq!(
+            sanitize("dir/..file")?,
+            (PathBuf::from("dir/..file"), false)
+        );
+        Ok(())
+    }
+}
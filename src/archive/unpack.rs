@@ -1,27 +1,443 @@
-use std::fs::File;
-use std::fs::{self, OpenOptions};
-use std::io::{BufReader, BufWriter, Read, Write};
-use std::os::unix::fs::PermissionsExt;
+use std::collections::HashSet;
+use std::fs;
+use std::io::{BufWriter, Read, Seek, SeekFrom};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
 use std::path::{Path, PathBuf};
 
-use anyhow::{self, Context};
+use anyhow::{self, bail, Context};
 use filetime::FileTime;
+use nix::errno::Errno;
+use nix::fcntl::AtFlags;
+use nix::sys::stat::{self, Mode, SFlag};
 use nix::unistd;
 
-use crate::archive::file::read_file_slice_chunked;
-use crate::backend::{AsHeader, PackerBackend};
+use super::atomic;
+use super::index;
+use super::owner::{OwnerMap, OwnerResolver};
+use super::pattern::PathPatterns;
+use super::sanitize;
+use crate::backend::{AsHeader, FileMetadata, FileType, IndexEntry, PackerBackend};
 
-pub fn unpack<T: PackerBackend>(
-    packer: &T,
-    input_path: PathBuf,
+/// Options to control unpacking of an archive.
+#[derive(Debug, Default, Clone)]
+pub struct UnpackOptions {
+    /// Paths or glob patterns (like `etc/*.conf`) of the files to unpack. A pattern of a directory
+    /// also matches everything inside it. If empty, all files are unpacked.
+    ///
+    /// A hard link whose target is not unpacked gets a copy of the data of the target instead,
+    /// when the archive is read with its index (see [`unpack_seekable`]). Otherwise it is skipped
+    /// with a warning.
+    pub paths: Vec<String>,
+    /// Unpack the paths of the archive as they are; even if they have `..` components or are
+    /// absolute, and so can be written outside of the output directory. Also allows writing
+    /// through symlinks unpacked from the archive. Only for archives that are trusted.
+    pub allow_unsafe_paths: bool,
+    /// Whether to restore the owner and group of the unpacked files. By default (`None`) they are
+    /// only restored when running as root, like tar does; as other users can't give files away.
+    /// Otherwise the files are owned by the user unpacking them.
+    pub same_owner: Option<bool>,
+    /// Restore the owner and group by the numeric IDs stored in the archive, ignoring the user and
+    /// group names. By default the names are looked up on this system, falling back to the IDs
+    /// for names that don't exist.
+    pub numeric_owner: bool,
+    /// Explicit mapping of the owners in the archive to the users of this system. It takes
+    /// precedence over the names and IDs in the archive.
+    pub owner_map: OwnerMap,
+    /// Explicit mapping of the groups in the archive to the groups of this system. It takes
+    /// precedence over the names and IDs in the archive.
+    pub group_map: OwnerMap,
+    /// Warn about every file whose owner or group can't be restored for lack of permission. Such
+    /// failures are never fatal; by default they are only logged at debug level.
+    pub warn_owner: bool,
+    /// What to do with files which already exist in the output directory.
+    pub overwrite: Overwrite,
+    /// Flush each regular file to disk before renaming it into place; so that a crash doesn't
+    /// leave an empty or partial file behind, even at its final path. This is slower.
+    pub sync: bool,
+    /// Unpack into a staging directory next to the output directory, and rename it to the output
+    /// directory once everything is unpacked; so that the whole tree appears at once, or not at
+    /// all. The output directory has to be empty or not exist, and is replaced by the staging
+    /// directory. The staging directory is removed if unpacking fails.
+    pub staging: bool,
+}
+
+/// What to do with a file which already exists at the path a file of the archive is unpacked to.
+/// Existing files are never written through; they are removed (or renamed) and a new file is
+/// created in their place. So an existing symlink is replaced itself, not its target.
+///
+/// Existing directories are not replaced by directories of the archive; the files are unpacked
+/// into them. Their metadata is restored only when a file would be replaced by the policy.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Overwrite {
+    /// Replace existing files. A non-empty directory is not replaced by a file; that is an error.
+    #[default]
+    Always,
+    /// Keep existing files, skipping the files of the archive.
+    Never,
+    /// Replace existing files only if the file in the archive is newer, by its last modified time.
+    Newer,
+    /// Rename existing files to `<name>.orig` (or `<name>.orig.1`, `<name>.orig.2` and so on, if
+    /// that exists too), before unpacking the files of the archive.
+    Backup,
+    /// Fail if a file already exists.
+    Error,
+}
+
+/// State of unpacking an archive into the output directory.
+struct Extraction<'a> {
+    output_path: &'a Path,
+    allow_unsafe_paths: bool,
+    /// Unpacked directories along with their metadata. Metadata of directories is set after all of
+    /// their contents are unpacked, otherwise creating the contents would change their modified
+    /// time (or fail, if they are read-only).
+    directories: Vec<(PathBuf, FileMetadata)>,
+    /// Symlinks unpacked so far, relative to the output directory. Nothing is written through
+    /// them, as they can point anywhere.
+    symlinks: HashSet<PathBuf>,
+    /// Regular files unpacked so far, relative to the output directory; which hard links can be
+    /// linked to. Files kept as they were by the overwrite policy are not included, as they don't
+    /// have the data of the archive.
+    regular_files: HashSet<PathBuf>,
+    /// Whether the leading `/` was removed from any path, to warn about it only once.
+    stripped_root: bool,
+    same_owner: bool,
+    warn_owner: bool,
+    overwrite: Overwrite,
+    sync: bool,
+    users: OwnerResolver,
+    groups: OwnerResolver,
+}
+
+impl<'a> Extraction<'a> {
+    fn new(output_path: &'a Path, options: &UnpackOptions) -> Self {
+        Self {
+            output_path,
+            allow_unsafe_paths: options.allow_unsafe_paths,
+            directories: vec![],
+            symlinks: HashSet::new(),
+            regular_files: HashSet::new(),
+            stripped_root: false,
+            same_owner: options
+                .same_owner
+                .unwrap_or_else(|| unistd::geteuid().is_root()),
+            warn_owner: options.warn_owner,
+            overwrite: options.overwrite,
+            sync: options.sync,
+            users: OwnerResolver::users(options.owner_map.clone(), options.numeric_owner),
+            groups: OwnerResolver::groups(options.group_map.clone(), options.numeric_owner),
+        }
+    }
+
+    /// Get the path to unpack a file of the archive to, relative to the output directory. Unsafe
+    /// paths, which could be written outside of the output directory, are an error.
+    fn relative_path(&mut self, path: &Path) -> anyhow::Result<PathBuf> {
+        if self.allow_unsafe_paths {
+            return Ok(path.to_path_buf());
+        }
+        let (relative, stripped_root) = sanitize::sanitize_path(path).with_context(|| {
+            format!(
+                "Refusing to unpack the unsafe path {}; use --allow-unsafe-paths to unpack it anyway",
+                path.display()
+            )
+        })?;
+        if stripped_root && !self.stripped_root {
+            log::warn!("Removing leading '/' from paths in the archive");
+            self.stripped_root = true;
+        }
+        if let Some(symlink) = relative
+            .ancestors()
+            .skip(1)
+            .find(|ancestor| self.symlinks.contains(*ancestor))
+        {
+            bail!(
+                "Refusing to unpack {} through the symlink {} unpacked from the archive",
+                path.display(),
+                symlink.display()
+            );
+        }
+        Ok(relative)
+    }
+
+    /// Get the path to unpack a (non-directory) file to, creating its parent directories. A
+    /// symlink unpacked earlier at the path is removed, so that the file is written in place of
+    /// the symlink instead of through it.
+    fn prepare_file(&mut self, relative_path: &Path) -> anyhow::Result<PathBuf> {
+        let (filename, parent_dirs) = parse_path(relative_path)?;
+        log::trace!(
+            "Parsed path and parent dirs : {} - {}",
+            filename.display(),
+            parent_dirs.display()
+        );
+        let final_path = self.output_path.join(parent_dirs);
+        fs::create_dir_all(&final_path)?;
+        let filepath = final_path.join(filename);
+        log::debug!("Unpacking file to path: {}", filepath.display());
+        if self.symlinks.remove(relative_path) {
+            fs::remove_file(&filepath)?;
+        }
+        Ok(filepath)
+    }
+
+    /// Make room for a file of the archive at `filepath`, where a file may already exist; as per
+    /// the overwrite policy. Returns whether to unpack the file; the existing file is removed (or
+    /// renamed) then.
+    fn make_room(&mut self, filepath: &Path, metadata: &FileMetadata) -> anyhow::Result<bool> {
+        if !self.may_replace(filepath, metadata)? {
+            return Ok(false);
+        }
+        self.clear_path(filepath, false)?;
+        Ok(true)
+    }
+
+    /// Whether the overwrite policy allows unpacking a file of the archive at `filepath`, where a
+    /// file may already exist. It is an error with [`Overwrite::Error`].
+    fn may_replace(&self, filepath: &Path, metadata: &FileMetadata) -> anyhow::Result<bool> {
+        let Ok(existing) = fs::symlink_metadata(filepath) else {
+            return Ok(true);
+        };
+        match self.overwrite {
+            Overwrite::Always | Overwrite::Backup => Ok(true),
+            Overwrite::Never => {
+                log::debug!("Keeping existing file: {}", filepath.display());
+                Ok(false)
+            }
+            Overwrite::Newer => {
+                let newer = is_newer(metadata, &existing);
+                if !newer {
+                    log::debug!("Keeping newer existing file: {}", filepath.display());
+                }
+                Ok(newer)
+            }
+            Overwrite::Error => bail!("{} already exists", filepath.display()),
+        }
+    }
+
+    /// Remove the existing file at `filepath` if any, or rename it with [`Overwrite::Backup`].
+    /// With `rename_over`, an existing file other than a directory is left in place; to be
+    /// replaced atomically by renaming the new file over it.
+    fn clear_path(&self, filepath: &Path, rename_over: bool) -> anyhow::Result<()> {
+        let Ok(existing) = fs::symlink_metadata(filepath) else {
+            return Ok(());
+        };
+        if self.overwrite == Overwrite::Backup {
+            let backup = backup_path(filepath);
+            log::info!(
+                "Backing up existing file {} to {}",
+                filepath.display(),
+                backup.display()
+            );
+            fs::rename(filepath, &backup)?;
+            return Ok(());
+        }
+        log::debug!("Replacing existing file: {}", filepath.display());
+        if existing.is_dir() {
+            fs::remove_dir(filepath).with_context(|| {
+                format!("Unable to replace the directory {}", filepath.display())
+            })?;
+        } else if !rename_over {
+            fs::remove_file(filepath)?;
+        }
+        Ok(())
+    }
+
+    /// Whether to restore the metadata of a directory of the archive, which already exists.
+    fn restore_existing_directory(&self, metadata: &FileMetadata, existing: &fs::Metadata) -> bool {
+        match self.overwrite {
+            Overwrite::Always | Overwrite::Backup => true,
+            Overwrite::Newer => is_newer(metadata, existing),
+            Overwrite::Never | Overwrite::Error => false,
+        }
+    }
+
+    /// Set the ownership, permissions and timestamps of the unpacked file. Those of a symlink are
+    /// set on the link itself, not on its target; symlinks have no permissions of their own.
+    fn set_metadata(&mut self, filepath: &Path, metadata: &FileMetadata) -> anyhow::Result<()> {
+        let is_symlink = metadata.file_type == FileType::SymLink;
+        // ownership is set first, as changing it clears the setuid and setgid bits
+        if self.same_owner {
+            self.set_owner(filepath, metadata, is_symlink)?;
+        }
+
+        // Set permissions
+        if !is_symlink {
+            let mut permissions = fs::metadata(filepath)?.permissions();
+            permissions.set_mode(metadata.file_mode);
+            fs::set_permissions(filepath, permissions)?;
+        }
+
+        // Set created and modification times
+        let created_time = FileTime::from_unix_time(metadata.created_at, 0);
+        let modified_time =
+            FileTime::from_unix_time(metadata.last_modified, metadata.last_modified_nanos);
+        if is_symlink {
+            filetime::set_symlink_file_times(filepath, created_time, modified_time)?;
+        } else {
+            filetime::set_file_times(filepath, created_time, modified_time)?;
+        }
+        Ok(())
+    }
+
+    /// Set the owner and group of the unpacked file. Not being permitted to do so is not an error.
+    /// For a symlink (`is_symlink`), the ownership of the link itself is changed.
+    fn set_owner(
+        &mut self,
+        filepath: &Path,
+        metadata: &FileMetadata,
+        is_symlink: bool,
+    ) -> anyhow::Result<()> {
+        let user_id = self
+            .users
+            .resolve(metadata.user_name.as_deref(), metadata.user_id);
+        let group_id = self
+            .groups
+            .resolve(metadata.group_name.as_deref(), metadata.group_id);
+
+        let uid = unistd::Uid::from_raw(user_id);
+        let gid = unistd::Gid::from_raw(group_id);
+        let flags = if is_symlink {
+            AtFlags::AT_SYMLINK_NOFOLLOW
+        } else {
+            AtFlags::empty()
+        };
+        match unistd::fchownat(None, filepath, Some(uid), Some(gid), flags) {
+            Ok(()) => Ok(()),
+            Err(Errno::EPERM) => {
+                let message = format!(
+                    "Not permitted to change the ownership of {} to {}:{}",
+                    filepath.display(),
+                    user_id,
+                    group_id
+                );
+                if self.warn_owner {
+                    log::warn!("{}", message);
+                } else {
+                    log::debug!("{}", message);
+                }
+                Ok(())
+            }
+            Err(err) => Err(err)
+                .with_context(|| format!("Failed to change ownership of {}", filepath.display())),
+        }
+    }
+
+    /// Set the metadata of the unpacked directories, and check that all the patterns matched
+    /// some file.
+    fn finish(mut self, patterns: &PathPatterns, matched: &[bool]) -> anyhow::Result<()> {
+        // directories are in the order they were packed, i.e. parents before their children. So
+        // set metadata in reverse order, so that the children are done before their parents.
+        for (dirpath, metadata) in std::mem::take(&mut self.directories).iter().rev() {
+            self.set_metadata(dirpath, metadata)?;
+        }
+
+        let unmatched: Vec<&str> = (0..patterns.len())
+            .filter(|&index| !matched[index])
+            .map(|index| patterns.pattern(index))
+            .collect();
+        if !unmatched.is_empty() {
+            bail!("Not found in archive: {}", unmatched.join(", "));
+        }
+        Ok(())
+    }
+}
+
+/// Unpack an archive read from `reader` using the given packer backend, into the destination
+/// directory given by `output_path`. File metadata like permissions, ownership and timestamps are
+/// restored.
+///
+/// The reader can be anything that implements [`Read`]; a file, a byte slice, a pipe etc. For files
+/// it is recommended to wrap them in a [`std::io::BufReader`].
+pub fn unpack<T: PackerBackend, R: Read>(
+    packer: &mut T,
+    reader: R,
+    output_path: PathBuf,
+) -> anyhow::Result<()> {
+    unpack_with_options(packer, reader, output_path, &UnpackOptions::default())
+}
+
+/// Same as [`unpack`], with the given options. The data of files which are not unpacked is read
+/// and discarded.
+pub fn unpack_with_options<T: PackerBackend, R: Read>(
+    packer: &mut T,
+    reader: R,
+    output_path: PathBuf,
+    options: &UnpackOptions,
+) -> anyhow::Result<()> {
+    staged(output_path, options, |output_path| {
+        unpack_entries(
+            packer,
+            reader,
+            output_path,
+            options,
+            |packer, reader, header| packer.skip_data(reader, header),
+        )
+    })
+}
+
+/// Same as [`unpack_with_options`], for a seekable `reader` like a file. The data of files which
+/// are not unpacked is skipped by seeking past it, if the backend supports it; so extracting a few
+/// files out of a large archive is cheap. If only some of the files are unpacked and the archive
+/// has an index, the files are found using it, without reading the rest of the archive.
+pub fn unpack_seekable<T: PackerBackend, R: Read + Seek>(
+    packer: &mut T,
+    mut reader: R,
+    output_path: PathBuf,
+    options: &UnpackOptions,
+) -> anyhow::Result<()> {
+    staged(output_path, options, |output_path| {
+        if !options.paths.is_empty() {
+            let start = reader.stream_position()?;
+            packer.read_prologue(&mut reader)?;
+            if let Some(index) = index::read_index(packer, &mut reader, start)? {
+                return unpack_indexed(packer, reader, start, &index, output_path, options);
+            }
+            reader.seek(SeekFrom::Start(start))?;
+        }
+        unpack_entries(
+            packer,
+            reader,
+            output_path,
+            options,
+            |packer, reader, header| packer.seek_data(reader, header),
+        )
+    })
+}
+
+/// Run `unpack` with the output directory; or with a staging directory which is renamed to the
+/// output directory afterwards, if the options ask for it.
+fn staged(
+    output_path: PathBuf,
+    options: &UnpackOptions,
+    unpack: impl FnOnce(PathBuf) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    if !options.staging {
+        return unpack(output_path);
+    }
+    let staging = atomic::create_staging_dir(&output_path)?;
+    log::debug!("Unpacking into staging directory: {}", staging.display());
+    let result = unpack(staging.clone())
+        .and_then(|()| atomic::finish_staging(&staging, &output_path, options.sync));
+    if result.is_err() {
+        let _ = fs::remove_dir_all(&staging);
+    }
+    result
+}
+
+/// Unpack all the entries of the archive matching the options, skipping over the data of the rest
+/// with `skip_data`.
+fn unpack_entries<T: PackerBackend, R: Read>(
+    packer: &mut T,
+    mut reader: R,
     output_path: PathBuf,
+    options: &UnpackOptions,
+    mut skip_data: impl FnMut(&T, &mut R, &T::Header) -> anyhow::Result<()>,
 ) -> anyhow::Result<()> {
-    // 1. file open and start reading the binary file
-    let archive_file = File::open(input_path)?;
-    let mut reader = BufReader::new(archive_file);
+    let patterns = PathPatterns::new(&options.paths)?;
+    let mut matched = vec![false; patterns.len()];
 
+    // 1. start reading the binary archive
     packer.read_prologue(&mut reader)?;
 
+    let mut extraction = Extraction::new(&output_path, options);
     let mut header_buffer = vec![0u8; packer.header_block_size()];
     loop {
         // 2. read first `block_size` bytes; this is the header
@@ -37,93 +453,352 @@ pub fn unpack<T: PackerBackend>(
             // log::trace!(">>EOA<<");
             break;
         }
-        process_file(packer, &mut reader, &header_buffer, &output_path)?;
+
+        // 3. deserialize into header, this gives all the file metadata.
+        let header = packer.unpack_header(&mut reader, &header_buffer)?;
+        let metadata = header.get_metadata();
+        if !patterns.is_empty() {
+            match patterns.find_match(&metadata.file_name) {
+                Some(index) => matched[index] = true,
+                None => {
+                    log::trace!("Skipping file: {}", metadata.file_name.display());
+                    skip_data(packer, &mut reader, &header)?;
+                    continue;
+                }
+            }
+        }
+        process_file(packer, &mut reader, &header, metadata, &mut extraction)?;
+    }
+    packer.read_epilogue(&mut reader)?;
+    extraction.finish(&patterns, &matched)
+}
+
+/// Unpack the entries of the index matching the options, by seeking to each of them.
+fn unpack_indexed<T: PackerBackend, R: Read + Seek>(
+    packer: &mut T,
+    mut reader: R,
+    start: u64,
+    index: &[IndexEntry],
+    output_path: PathBuf,
+    options: &UnpackOptions,
+) -> anyhow::Result<()> {
+    let patterns = PathPatterns::new(&options.paths)?;
+    let mut matched = vec![false; patterns.len()];
+    let mut extraction = Extraction::new(&output_path, options);
+    for entry in index {
+        let Some(pattern_index) = patterns.find_match(&entry.file_name) else {
+            continue;
+        };
+        matched[pattern_index] = true;
+        let header = index::read_header_at(packer, &mut reader, start, entry)?;
+        let metadata = header.get_metadata();
+        // a hard link to a file which is not unpacked gets a copy of its data instead
+        if let Some(target) = unlinked_target(&metadata, index, &mut extraction)? {
+            log::debug!(
+                "Unpacking the data of {} for the hard link {}",
+                target.file_name.display(),
+                metadata.file_name.display()
+            );
+            let target_header = index::read_header_at(packer, &mut reader, start, target)?;
+            let target_metadata = FileMetadata {
+                file_name: metadata.file_name,
+                ..target_header.get_metadata()
+            };
+            process_file(
+                packer,
+                &mut reader,
+                &target_header,
+                target_metadata,
+                &mut extraction,
+            )?;
+            continue;
+        }
+        process_file(packer, &mut reader, &header, metadata, &mut extraction)?;
+    }
+    extraction.finish(&patterns, &matched)
+}
+
+/// Get the index entry of the target of a hard link, if the target is not unpacked.
+fn unlinked_target<'i>(
+    metadata: &FileMetadata,
+    index: &'i [IndexEntry],
+    extraction: &mut Extraction,
+) -> anyhow::Result<Option<&'i IndexEntry>> {
+    let (FileType::HardLink, Some(link_name)) = (metadata.file_type, &metadata.link_name) else {
+        return Ok(None);
+    };
+    let target = extraction.relative_path(link_name)?;
+    if extraction.regular_files.contains(&target) {
+        return Ok(None);
+    }
+    Ok(index
+        .iter()
+        .find(|entry| entry.file_type == FileType::Regular && entry.file_name == *link_name))
+}
+
+/// Unpack a file of the archive, according to its type. The reader is at the data of the file.
+fn process_file<T: PackerBackend, R: Read>(
+    packer: &mut T,
+    reader: &mut R,
+    header: &T::Header,
+    metadata: FileMetadata,
+    extraction: &mut Extraction,
+) -> anyhow::Result<()> {
+    let relative_path = extraction.relative_path(&metadata.file_name)?;
+    match metadata.file_type {
+        FileType::Regular => extract_regular(
+            packer,
+            reader,
+            header,
+            &metadata,
+            &relative_path,
+            extraction,
+        ),
+        FileType::Directory => extract_directory(metadata, &relative_path, extraction),
+        FileType::SymLink => extract_symlink(&metadata, relative_path, extraction),
+        FileType::HardLink => extract_hard_link(&metadata, &relative_path, extraction),
+        FileType::Fifo | FileType::CharDevice | FileType::BlockDevice => {
+            extract_special(&metadata, &relative_path, extraction)
+        }
+    }
+}
+
+/// Create a directory (along with any parent directories). Its metadata is set at the end.
+fn extract_directory(
+    metadata: FileMetadata,
+    relative_path: &Path,
+    extraction: &mut Extraction,
+) -> anyhow::Result<()> {
+    if extraction.symlinks.contains(relative_path) {
+        bail!(
+            "Refusing to unpack the directory {} over a symlink unpacked from the archive",
+            metadata.file_name.display()
+        );
+    }
+    let dirpath = extraction.output_path.join(relative_path);
+    match fs::symlink_metadata(&dirpath) {
+        Ok(existing) if existing.is_dir() => {
+            log::debug!("Unpacking into existing directory: {}", dirpath.display());
+            if extraction.restore_existing_directory(&metadata, &existing) {
+                extraction.directories.push((dirpath, metadata));
+            }
+            return Ok(());
+        }
+        // a file in place of the directory
+        Ok(_) => {
+            if !extraction.make_room(&dirpath, &metadata)? {
+                return Ok(());
+            }
+        }
+        Err(_) => {}
     }
+    log::debug!("Creating directory: {}", dirpath.display());
+    fs::create_dir_all(&dirpath)?;
+    extraction.directories.push((dirpath, metadata));
     Ok(())
 }
 
-fn process_file<T: PackerBackend>(
-    packer: &T,
-    reader: &mut BufReader<File>,
-    header_buffer: &[u8],
-    output_path: &Path,
+/// Write the data of a regular file from the archive.
+fn extract_regular<T: PackerBackend, R: Read>(
+    packer: &mut T,
+    reader: &mut R,
+    header: &T::Header,
+    metadata: &FileMetadata,
+    relative_path: &Path,
+    extraction: &mut Extraction,
 ) -> anyhow::Result<()> {
-    // 3. deserialize into header, this gives all the file metadata.
-    let header = packer.unpack_header(reader, header_buffer)?;
-    let metadata = header.get_metadata();
-
-    // 4. parse path to check if this directory; if yes you get a list of dirs and a filepath,
-    // otherwise only a filepath
-    log::trace!("Parsed header for file : {:?}", metadata.file_name);
-    let (filename, parent_dirs) = parse_path(&metadata.file_name)?;
-    log::trace!(
-        "Parsed path and parent dirs : {} - {}",
-        filename.display(),
-        parent_dirs.display()
-    );
+    let filepath = extraction.prepare_file(relative_path)?;
+    if !extraction.may_replace(&filepath, metadata)? {
+        return packer.skip_data(reader, header);
+    }
+    // the file is written to a temporary file next to it, and renamed into place once it is
+    // complete; so it is never seen partially written
+    let (file, temp_path) = atomic::create_temp_file(&filepath)?;
+    let result = write_regular(
+        packer, reader, header, metadata, file, &temp_path, extraction,
+    )
+    .and_then(|()| extraction.clear_path(&filepath, true))
+    .and_then(|()| {
+        fs::rename(&temp_path, &filepath).with_context(|| {
+            format!(
+                "Unable to rename {} to {}",
+                temp_path.display(),
+                filepath.display()
+            )
+        })
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result?;
+    if extraction.sync {
+        atomic::sync_parent(&filepath)?;
+    }
+    extraction.regular_files.insert(relative_path.to_path_buf());
+    Ok(())
+}
 
-    // 5. if dir, create all empty dirs, in the correct path location
-    let final_path;
-    if !parent_dirs.as_os_str().is_empty() {
-        final_path = output_path.join(parent_dirs);
-        fs::create_dir_all(&final_path)?;
-    } else {
-        final_path = output_path.to_path_buf();
+/// Write the data and metadata of a regular file to the (temporary) `file` at `path`.
+fn write_regular<T: PackerBackend, R: Read>(
+    packer: &mut T,
+    reader: &mut R,
+    header: &T::Header,
+    metadata: &FileMetadata,
+    file: fs::File,
+    path: &Path,
+    extraction: &mut Extraction,
+) -> anyhow::Result<()> {
+    let mut writer = BufWriter::new(file);
+    log::trace!("File size {}.", metadata.file_size);
+    packer.unpack_data(reader, header, &mut writer)?;
+    let file = writer.into_inner().map_err(|err| err.into_error())?;
+    extraction.set_metadata(path, metadata)?;
+    if extraction.sync {
+        file.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Create a symlink. Its target is stored as it is; it is not resolved or checked.
+fn extract_symlink(
+    metadata: &FileMetadata,
+    relative_path: PathBuf,
+    extraction: &mut Extraction,
+) -> anyhow::Result<()> {
+    let link_name = metadata
+        .link_name
+        .as_ref()
+        .with_context(|| format!("Symlink {} has no target", metadata.file_name.display()))?;
+    let filepath = extraction.prepare_file(&relative_path)?;
+    if !extraction.make_room(&filepath, metadata)? {
+        return Ok(());
     }
     log::debug!(
-        "Writing file {} to path: {}",
-        filename.display(),
-        final_path.display()
+        "Creating symlink: {} -> {}",
+        filepath.display(),
+        link_name.display()
     );
+    create_symlink(link_name, &filepath).with_context(|| {
+        format!(
+            "Unable to create symlink: '{} -> {}'",
+            filepath.display(),
+            link_name.display()
+        )
+    })?;
+    if !extraction.allow_unsafe_paths {
+        extraction.symlinks.insert(relative_path);
+    }
+    extraction.set_metadata(&filepath, metadata)
+}
 
-    // 6. create an empty file with the above metadata, in the correct path location
-    let filepath = final_path.join(filename);
-    log::trace!("Effective destination file path: {}", filepath.display());
-    let file = OpenOptions::new()
-        .create(true)
-        .write(true)
-        .truncate(true)
-        .open(&filepath)?;
-    let mut writer = BufWriter::new(file);
-    log::trace!("File size {}.", metadata.file_size);
+/// Link a hard link to the already unpacked target. The link shares the data and metadata of the
+/// target, so there is nothing else to do. Hard links to a target which is not unpacked (like when
+/// only some of the files are unpacked) are skipped.
+fn extract_hard_link(
+    metadata: &FileMetadata,
+    relative_path: &Path,
+    extraction: &mut Extraction,
+) -> anyhow::Result<()> {
+    let link_name = metadata
+        .link_name
+        .as_ref()
+        .with_context(|| format!("Hard link {} has no target", metadata.file_name.display()))?;
+    let target = extraction.relative_path(link_name)?;
+    if !extraction.regular_files.contains(&target) {
+        log::warn!(
+            "Skipping hard link {}, as its target {} is not unpacked",
+            metadata.file_name.display(),
+            link_name.display()
+        );
+        return Ok(());
+    }
+    let target = extraction.output_path.join(target);
+    let filepath = extraction.prepare_file(relative_path)?;
+    if !extraction.make_room(&filepath, metadata)? {
+        return Ok(());
+    }
+    fs::hard_link(&target, &filepath).with_context(|| {
+        format!(
+            "Unable to create hard link: '{} => {}'",
+            filepath.display(),
+            target.display()
+        )
+    })
+}
 
-    // 7.1. if file is a symlink, set up a symlink
-    if let Some(link_name) = metadata.link_name {
-        if let Err(err) = create_symlink(&link_name, &filepath) {
-            log::warn!(
-                "Unable to set up symlink: '{} -> {}'. Error: {}",
-                filepath.display(),
-                link_name.display(),
-                err
-            );
-            log::warn!("Symlink file created with invalid target.");
-        }
-    // 7.2. else process the file data from archive
-    } else {
-        // 8. read X number of bytes given by file size in metadata; write those bytes into file
-        // created in 6.
-        read_file_slice_chunked(reader, metadata.file_size, |data| {
-            writer.write_all(data)?;
-            Ok(())
-        })?;
+/// Create a FIFO or a device.
+fn extract_special(
+    metadata: &FileMetadata,
+    relative_path: &Path,
+    extraction: &mut Extraction,
+) -> anyhow::Result<()> {
+    let filepath = extraction.prepare_file(relative_path)?;
+    if !extraction.make_room(&filepath, metadata)? {
+        return Ok(());
+    }
+    if create_special_file(&filepath, metadata)? {
+        extraction.set_metadata(&filepath, metadata)?;
     }
+    Ok(())
+}
 
-    // 9. set file metadata
-    // Set permissions
-    let mut permissions = fs::metadata(&filepath)?.permissions();
-    permissions.set_mode(metadata.file_mode);
-    fs::set_permissions(&filepath, permissions)?;
+/// Whether the file in the archive is newer than the existing file, by their last modified time.
+fn is_newer(metadata: &FileMetadata, existing: &fs::Metadata) -> bool {
+    let stored = (metadata.last_modified, metadata.last_modified_nanos as i64);
+    stored > (existing.mtime(), existing.mtime_nsec())
+}
 
-    // Set UID and GID
-    let uid = unistd::Uid::from_raw(metadata.user_id); // Replace with desired UID
-    let gid = unistd::Gid::from_raw(metadata.group_id); // Replace with desired GID
-    unistd::chown(&filepath, Some(uid), Some(gid)).with_context(|| "Failed to change ownership")?;
+/// Path to rename an existing file to, to back it up: `<name>.orig`, or `<name>.orig.<n>` with
+/// the first number not taken already.
+fn backup_path(filepath: &Path) -> PathBuf {
+    let mut backup = filepath.as_os_str().to_owned();
+    backup.push(".orig");
+    let mut backup = PathBuf::from(backup);
+    let mut number = 0;
+    while fs::symlink_metadata(&backup).is_ok() {
+        number += 1;
+        let mut numbered = filepath.as_os_str().to_owned();
+        numbered.push(format!(".orig.{}", number));
+        backup = PathBuf::from(numbered);
+    }
+    backup
+}
 
-    // Set created and modification times
-    let created_time = FileTime::from_unix_time(metadata.created_at, 0);
-    let modified_time = FileTime::from_unix_time(metadata.last_modified, 0);
-    filetime::set_file_times(filepath, created_time, modified_time)?;
-    Ok(())
+/// Create a FIFO or a character/block device. Creating devices requires privileges, so if that is
+/// not permitted the device is skipped with a warning. Returns whether the file was created.
+fn create_special_file(filepath: &Path, metadata: &FileMetadata) -> anyhow::Result<bool> {
+    let mode = Mode::from_bits_truncate(metadata.file_mode & 0o7777);
+    let kind = match metadata.file_type {
+        FileType::Fifo => {
+            log::debug!("Creating FIFO: {}", filepath.display());
+            unistd::mkfifo(filepath, mode)
+                .with_context(|| format!("Unable to create FIFO: {}", filepath.display()))?;
+            return Ok(true);
+        }
+        FileType::CharDevice => SFlag::S_IFCHR,
+        FileType::BlockDevice => SFlag::S_IFBLK,
+        _ => bail!("{} is not a special file", filepath.display()),
+    };
+    log::debug!(
+        "Creating device {},{}: {}",
+        metadata.dev_major,
+        metadata.dev_minor,
+        filepath.display()
+    );
+    let device = stat::makedev(metadata.dev_major as u64, metadata.dev_minor as u64);
+    match stat::mknod(filepath, kind, mode, device) {
+        Ok(()) => Ok(true),
+        Err(Errno::EPERM) => {
+            log::warn!(
+                "Skipping device {}: creating devices requires privileges",
+                filepath.display()
+            );
+            Ok(false)
+        }
+        Err(err) => {
+            Err(err).with_context(|| format!("Unable to create device: {}", filepath.display()))
+        }
+    }
 }
 
 /// Takes a path, returns the filename and any parent directories. For example, given
@@ -153,3 +828,669 @@ fn create_symlink<P: AsRef<Path>, Q: AsRef<Path>>(original: P, link: Q) -> anyho
     std::os::windows::fs::symlink_file(original, link)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::os::unix::fs::MetadataExt;
+
+    use super::*;
+    use crate::testing::{pack_files, roundtrip, TestDir};
+    use crate::{pack, BagArchive, BagCompression, BagDigest, FilePath, TarArchive};
+
+    fn test_selective_unpack<T: PackerBackend>(mut packer: T, name: &str) -> anyhow::Result<()> {
+        let dir = TestDir::new(name)?;
+        dir.write_files(&[
+            ("input/etc/app/app.conf", "conf"),
+            ("input/etc/other.conf", "other"),
+            ("input/var/data", "data"),
+        ])?;
+        let archive = pack_files(&packer, &[dir.join("input")])?;
+        let output = dir.join("output");
+        fs::create_dir(&output)?;
+        let options = UnpackOptions {
+            paths: vec!["input/etc/app".to_string(), "input/*/other.*".to_string()],
+            ..Default::default()
+        };
+        unpack_seekable(&mut packer, Cursor::new(&archive), output.clone(), &options)?;
+        assert_eq!(fs::read(output.join("input/etc/app/app.conf"))?, b"conf");
+        assert_eq!(fs::read(output.join("input/etc/other.conf"))?, b"other");
+        assert!(!output.join("input/var").exists());
+
+        // patterns which don't match anything are an error
+        let options = UnpackOptions {
+            paths: vec!["input/missing".to_string()],
+            ..Default::default()
+        };
+        assert!(unpack_with_options(&mut packer, archive.as_slice(), output, &options).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_selective_unpack_bag() -> anyhow::Result<()> {
+        test_selective_unpack(BagArchive::new(), "packer_selective_unpack_bag")
+    }
+
+    #[test]
+    fn test_selective_unpack_tar() -> anyhow::Result<()> {
+        test_selective_unpack(TarArchive::new(), "packer_selective_unpack_tar")
+    }
+
+    fn test_selective_hard_link<T: PackerBackend>(
+        mut packer: T,
+        name: &str,
+        indexed: bool,
+    ) -> anyhow::Result<()> {
+        let dir = TestDir::new(name)?;
+        dir.write_files(&[("input/file.txt", "hello world")])?;
+        let input = dir.join("input");
+        fs::create_dir(input.join("sub"))?;
+        fs::hard_link(input.join("file.txt"), input.join("sub/link.txt"))?;
+
+        let archive = pack_files(&packer, &[input])?;
+        // either path can be the one stored as the hard link, by the order of the directory
+        let link = crate::list(&mut packer, archive.as_slice())?
+            .into_iter()
+            .find(|entry| entry.file_type == FileType::HardLink)
+            .context("No hard link in the archive")?
+            .file_name;
+        let options = UnpackOptions {
+            paths: vec![link.display().to_string()],
+            ..Default::default()
+        };
+
+        // the link is skipped when its target is not unpacked
+        let output = dir.join("output");
+        fs::create_dir(&output)?;
+        unpack_with_options(&mut packer, archive.as_slice(), output.clone(), &options)?;
+        assert!(!output.join(&link).exists());
+
+        // or gets a copy of its data, if it can be read using the index
+        let output = dir.join("indexed");
+        fs::create_dir(&output)?;
+        unpack_seekable(&mut packer, Cursor::new(&archive), output.clone(), &options)?;
+        if indexed {
+            assert_eq!(fs::read(output.join(&link))?, b"hello world");
+        } else {
+            assert!(!output.join(&link).exists());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_selective_hard_link_bag() -> anyhow::Result<()> {
+        test_selective_hard_link(BagArchive::new(), "packer_selective_hard_link_bag", true)
+    }
+
+    #[test]
+    fn test_selective_hard_link_tar() -> anyhow::Result<()> {
+        test_selective_hard_link(TarArchive::new(), "packer_selective_hard_link_tar", false)
+    }
+
+    fn test_hard_link_overwrite_never<T: PackerBackend>(
+        mut packer: T,
+        name: &str,
+        indexed: bool,
+    ) -> anyhow::Result<()> {
+        let dir = TestDir::new(name)?;
+        dir.write_files(&[("input/file.txt", "hello world")])?;
+        let input = dir.join("input");
+        fs::create_dir(input.join("sub"))?;
+        fs::hard_link(input.join("file.txt"), input.join("sub/link.txt"))?;
+
+        let archive = pack_files(&packer, &[input])?;
+        let link = crate::list(&mut packer, archive.as_slice())?
+            .into_iter()
+            .find(|entry| entry.file_type == FileType::HardLink)
+            .context("No hard link in the archive")?;
+        let target = link.link_name.context("Hard link has no target")?;
+        // selecting the files makes the backends with an index read the archive using it
+        let options = UnpackOptions {
+            paths: vec!["input".to_string()],
+            overwrite: Overwrite::Never,
+            ..Default::default()
+        };
+
+        // the existing target is kept, so the link is not linked to it
+        for (output, seekable) in [("output", false), ("indexed", true)] {
+            dir.write_files(&[(&format!("{}/{}", output, target.display()), "existing")])?;
+            let output = dir.join(output);
+            if seekable {
+                unpack_seekable(&mut packer, Cursor::new(&archive), output.clone(), &options)?;
+            } else {
+                unpack_with_options(&mut packer, archive.as_slice(), output.clone(), &options)?;
+            }
+            assert_eq!(fs::read(output.join(&target))?, b"existing");
+            if seekable && indexed {
+                assert_eq!(fs::read(output.join(&link.file_name))?, b"hello world");
+            } else {
+                assert!(!output.join(&link.file_name).exists());
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_hard_link_overwrite_never_bag() -> anyhow::Result<()> {
+        test_hard_link_overwrite_never(BagArchive::new(), "packer_hard_link_never_bag", true)
+    }
+
+    #[test]
+    fn test_hard_link_overwrite_never_tar() -> anyhow::Result<()> {
+        test_hard_link_overwrite_never(TarArchive::new(), "packer_hard_link_never_tar", false)
+    }
+
+    #[test]
+    fn test_damaged_bag_data() -> anyhow::Result<()> {
+        let dir = TestDir::new("packer_damaged_bag_data")?;
+        dir.write_files(&[("input/file.txt", &"hello world\n".repeat(100))])?;
+        let input = dir.join("input");
+
+        for digest in [BagDigest::Crc32c, BagDigest::Blake3, BagDigest::Sha256] {
+            for compression in [BagCompression::None, BagCompression::Zstd] {
+                let mut packer = BagArchive::with_compression(compression, None)?
+                    .with_digest(digest)
+                    .with_index(false);
+                let output = dir.join("output");
+                let mut archive = roundtrip(&mut packer, &input, &output)?;
+
+                // flip a bit of the stored digest, which follows the data of the only file
+                let end = archive.len() - 128;
+                archive[end - 1] ^= 1;
+                let err = unpack(&mut packer, archive.as_slice(), output.clone()).unwrap_err();
+                assert!(
+                    format!("{:#}", err).contains("input/file.txt is damaged"),
+                    "{:#}",
+                    err
+                );
+                // listing only skips the data, without checking it
+                crate::list(&mut packer, archive.as_slice())?;
+            }
+        }
+
+        // flip a bit of the uncompressed data
+        let mut packer = BagArchive::new().with_index(false);
+        let mut archive = pack_files(&packer, &[input])?;
+        let position = archive.windows(5).position(|w| w == b"hello").unwrap();
+        archive[position] ^= 1;
+        let err = unpack(&mut packer, archive.as_slice(), dir.join("output")).unwrap_err();
+        assert!(format!("{:#}", err).contains("input/file.txt is damaged"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_indexed_unpack_bag() -> anyhow::Result<()> {
+        let dir = TestDir::new("packer_indexed_unpack_bag")?;
+        dir.write_files(&[("input/a.txt", "aaaa"), ("input/b.txt", "bbbb")])?;
+
+        let mut packer = BagArchive::new();
+        let mut archive = pack_files(&packer, &[dir.join("input")])?;
+        // corrupt the header of a.txt; which is not read when only b.txt is unpacked using the
+        // index, but breaks reading the archive sequentially
+        let name = archive.windows(5).position(|w| w == b"a.txt").unwrap();
+        archive[name - 64 + 8] ^= 0xff;
+
+        let output = dir.join("output");
+        fs::create_dir(&output)?;
+        let options = UnpackOptions {
+            paths: vec!["input/b.txt".to_string()],
+            ..Default::default()
+        };
+        unpack_seekable(&mut packer, Cursor::new(&archive), output.clone(), &options)?;
+        assert_eq!(fs::read(output.join("input/b.txt"))?, b"bbbb");
+        assert!(!output.join("input/a.txt").exists());
+        assert!(unpack_with_options(&mut packer, archive.as_slice(), output, &options).is_err());
+        Ok(())
+    }
+
+    /// Craft an archive with the given entries of (path in the archive, type, link name); which
+    /// `pack` would never write. The metadata and data of the entries are of `data_file`.
+    fn craft_archive<T: PackerBackend>(
+        packer: &T,
+        data_file: &Path,
+        entries: &[(&str, FileType, Option<&str>)],
+    ) -> anyhow::Result<Vec<u8>> {
+        let mut archive = Vec::new();
+        packer.write_prologue(&mut archive)?;
+        for (path, file_type, link_name) in entries {
+            let file = FilePath {
+                archive_path: PathBuf::from(path),
+                system_path: data_file.to_path_buf(),
+            };
+            let metadata = fs::metadata(data_file)?;
+            let link_name = link_name.map(PathBuf::from);
+            let file_size =
+                packer.pack_header(&mut archive, &file, metadata, *file_type, link_name)?;
+            if *file_type == FileType::Regular {
+                packer.pack_data(&mut archive, &mut fs::File::open(data_file)?, file_size)?;
+            }
+        }
+        packer.write_epilogue(&mut archive)?;
+        Ok(archive)
+    }
+
+    fn test_unsafe_paths<T: PackerBackend>(mut packer: T, name: &str) -> anyhow::Result<()> {
+        let dir = TestDir::new(name)?;
+        dir.write_files(&[("data.txt", "evil")])?;
+        let data_file = dir.join("data.txt");
+        let output = dir.join("output");
+        fs::create_dir(&output)?;
+        let mut unpack_crafted = |entries: &[(&str, FileType, Option<&str>)],
+                                  options: &UnpackOptions| {
+            let archive = craft_archive(&packer, &data_file, entries)?;
+            unpack_with_options(&mut packer, archive.as_slice(), output.clone(), options)
+        };
+        let options = UnpackOptions::default();
+
+        // `..` is refused, and nothing is written outside of the output directory
+        let entries = [("../evil.txt", FileType::Regular, None)];
+        let err = unpack_crafted(&entries, &options).unwrap_err();
+        assert!(format!("{:#}", err).contains("unsafe path"), "{:#}", err);
+        assert!(!dir.join("evil.txt").exists());
+
+        // the leading `/` is removed
+        let entries = [("/abs/evil.txt", FileType::Regular, None)];
+        unpack_crafted(&entries, &options)?;
+        assert_eq!(fs::read(output.join("abs/evil.txt"))?, b"evil");
+
+        // nothing is written through a symlink unpacked from the archive
+        let entries = [
+            ("link", FileType::SymLink, dir.path().to_str()),
+            ("link/evil.txt", FileType::Regular, None),
+        ];
+        let err = unpack_crafted(&entries, &options).unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("through the symlink"),
+            "{:#}",
+            err
+        );
+        assert!(!dir.join("evil.txt").exists());
+
+        // hard links can't point outside of the output directory either
+        let entries = [("hard", FileType::HardLink, Some("../data.txt"))];
+        assert!(unpack_crafted(&entries, &options).is_err());
+        assert!(!output.join("hard").exists());
+
+        // unless unsafe paths are explicitly allowed
+        let options = UnpackOptions {
+            allow_unsafe_paths: true,
+            ..Default::default()
+        };
+        let entries = [("../evil.txt", FileType::Regular, None)];
+        unpack_crafted(&entries, &options)?;
+        assert_eq!(fs::read(dir.join("evil.txt"))?, b"evil");
+        Ok(())
+    }
+
+    #[test]
+    fn test_unsafe_paths_bag() -> anyhow::Result<()> {
+        test_unsafe_paths(BagArchive::new(), "packer_unsafe_paths_bag")
+    }
+
+    #[test]
+    fn test_unsafe_paths_tar() -> anyhow::Result<()> {
+        test_unsafe_paths(TarArchive::new(), "packer_unsafe_paths_tar")
+    }
+
+    #[test]
+    fn test_same_owner() -> anyhow::Result<()> {
+        // changing the ownership to other users requires root
+        if !unistd::geteuid().is_root() {
+            return Ok(());
+        }
+        let dir = TestDir::new("packer_same_owner")?;
+        dir.write_files(&[("input/file.txt", "data")])?;
+        let input = dir.join("input");
+        let uid = unistd::Uid::from_raw(54321);
+        let gid = unistd::Gid::from_raw(54321);
+        unistd::chown(&input.join("file.txt"), Some(uid), Some(gid))?;
+        let mut packer = BagArchive::new();
+        let output = dir.join("output");
+        let archive = roundtrip(&mut packer, &input, &output)?;
+
+        let owner = |path: &Path| -> anyhow::Result<(u32, u32)> {
+            let metadata = fs::metadata(path)?;
+            Ok((metadata.uid(), metadata.gid()))
+        };
+        assert_eq!(owner(&output.join("input/file.txt"))?, (54321, 54321));
+        let options = UnpackOptions {
+            same_owner: Some(false),
+            ..Default::default()
+        };
+        let own_output = dir.join("own_output");
+        fs::create_dir(&own_output)?;
+        unpack_with_options(
+            &mut packer,
+            archive.as_slice(),
+            own_output.clone(),
+            &options,
+        )?;
+        assert_eq!(owner(&own_output.join("input/file.txt"))?, (0, 0));
+
+        // names are stored along with the IDs, where they exist on this system
+        let entries = crate::list(&mut packer, archive.as_slice())?;
+        assert_eq!(entries[0].user_name.as_deref(), Some("root"));
+        assert_eq!(entries[1].user_name, None);
+
+        // an explicit mapping takes precedence
+        dir.write_files(&[("owner_map", "+54321 root\n")])?;
+        let options = UnpackOptions {
+            owner_map: OwnerMap::read_users(&dir.join("owner_map"))?,
+            ..Default::default()
+        };
+        let mapped_output = dir.join("mapped_output");
+        fs::create_dir(&mapped_output)?;
+        unpack_with_options(
+            &mut packer,
+            archive.as_slice(),
+            mapped_output.clone(),
+            &options,
+        )?;
+        assert_eq!(owner(&mapped_output.join("input/file.txt"))?, (0, 54321));
+
+        // names are looked up, unless the numeric IDs are asked for
+        let file = output.join("input/file.txt");
+        let metadata = FileMetadata {
+            file_name: PathBuf::from("input/file.txt"),
+            file_type: FileType::Regular,
+            file_size: 4,
+            file_mode: 0o644,
+            user_id: 54321,
+            group_id: 54321,
+            user_name: Some("root".to_string()),
+            group_name: Some("packer-no-such-group".to_string()),
+            created_at: 0,
+            last_modified: 0,
+            last_modified_nanos: 0,
+            link_name: None,
+            dev_major: 0,
+            dev_minor: 0,
+        };
+        let mut extraction = Extraction::new(&output, &UnpackOptions::default());
+        extraction.set_owner(&file, &metadata, false)?;
+        assert_eq!(owner(&file)?, (0, 54321));
+        let options = UnpackOptions {
+            numeric_owner: true,
+            ..Default::default()
+        };
+        let mut extraction = Extraction::new(&output, &options);
+        extraction.set_owner(&file, &metadata, false)?;
+        assert_eq!(owner(&file)?, (54321, 54321));
+        Ok(())
+    }
+
+    /// Archives are written and read sequentially, so they can be streamed through a pipe; which
+    /// can't be seeked, like stdin and stdout.
+    fn test_pipe_roundtrip<T: PackerBackend>(
+        new_packer: fn() -> T,
+        name: &str,
+    ) -> anyhow::Result<()> {
+        let dir = TestDir::new(name)?;
+        let data = "hello world\n".repeat(10_000);
+        dir.write_files(&[("input/file.txt", &data), ("input/sub/other.txt", "other")])?;
+        let input = dir.join("input");
+        let output = dir.join("output");
+        fs::create_dir(&output)?;
+
+        let (reader, writer) = std::io::pipe()?;
+        std::thread::scope(|scope| {
+            let packing = scope.spawn(|| pack(&new_packer(), writer, std::slice::from_ref(&input)));
+            unpack(&mut new_packer(), reader, output.clone())?;
+            packing.join().unwrap()
+        })?;
+        assert_eq!(fs::read_to_string(output.join("input/file.txt"))?, data);
+        assert_eq!(fs::read(output.join("input/sub/other.txt"))?, b"other");
+        Ok(())
+    }
+
+    #[test]
+    fn test_pipe_roundtrip_bag() -> anyhow::Result<()> {
+        test_pipe_roundtrip(BagArchive::new, "packer_pipe_roundtrip_bag")
+    }
+
+    #[test]
+    fn test_pipe_roundtrip_tar() -> anyhow::Result<()> {
+        test_pipe_roundtrip(TarArchive::new, "packer_pipe_roundtrip_tar")
+    }
+
+    fn test_directory_roundtrip<T: PackerBackend>(mut packer: T, name: &str) -> anyhow::Result<()> {
+        let dir = TestDir::new(name)?;
+        dir.write_files(&[("input/locked/file.txt", "data")])?;
+        let input = dir.join("input");
+        fs::create_dir(input.join("empty"))?;
+        let set_mode =
+            |path: &Path, mode: u32| fs::set_permissions(path, fs::Permissions::from_mode(mode));
+        // the metadata of a directory has to be set after its contents are unpacked, or writing
+        // them would fail (for other users than root) and change its modified time
+        let dir_time = FileTime::from_unix_time(1_000_000_000, 0);
+        filetime::set_file_times(input.join("locked"), dir_time, dir_time)?;
+        set_mode(&input.join("locked"), 0o555)?;
+
+        let output = dir.join("output");
+        let result = roundtrip(&mut packer, &input, &output);
+        set_mode(&input.join("locked"), 0o755)?;
+        result?;
+
+        let unpacked = output.join("input");
+        assert!(fs::metadata(unpacked.join("empty"))?.is_dir());
+        assert_eq!(fs::read_dir(unpacked.join("empty"))?.count(), 0);
+        let locked = fs::metadata(unpacked.join("locked"))?;
+        assert_eq!(locked.mode() & 0o7777, 0o555);
+        assert_eq!(locked.mtime(), 1_000_000_000);
+        assert_eq!(fs::read(unpacked.join("locked/file.txt"))?, b"data");
+        set_mode(&unpacked.join("locked"), 0o755)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_directory_roundtrip_bag() -> anyhow::Result<()> {
+        test_directory_roundtrip(BagArchive::new(), "packer_directory_roundtrip_bag")
+    }
+
+    #[test]
+    fn test_directory_roundtrip_tar() -> anyhow::Result<()> {
+        test_directory_roundtrip(TarArchive::new(), "packer_directory_roundtrip_tar")
+    }
+
+    fn test_symlink_roundtrip<T: PackerBackend>(mut packer: T, name: &str) -> anyhow::Result<()> {
+        let dir = TestDir::new(name)?;
+        dir.write_files(&[("input/file.txt", "data")])?;
+        let input = dir.join("input");
+        create_symlink("file.txt", input.join("link"))?;
+        create_symlink("missing", input.join("dangling"))?;
+        create_symlink("/etc/hostname", input.join("absolute"))?;
+        // the metadata of the link itself is restored, not of its target
+        let link_time = FileTime::from_unix_time(1_600_000_000, 0);
+        filetime::set_symlink_file_times(input.join("link"), link_time, link_time)?;
+        let is_root = unistd::geteuid().is_root();
+        if is_root {
+            let uid = unistd::Uid::from_raw(54321);
+            unistd::fchownat(
+                None,
+                &input.join("link"),
+                Some(uid),
+                None,
+                AtFlags::AT_SYMLINK_NOFOLLOW,
+            )?;
+        }
+
+        let output = dir.join("output");
+        let archive = roundtrip(&mut packer, &input, &output)?;
+
+        let unpacked = output.join("input");
+        for (link, target) in [
+            ("link", "file.txt"),
+            ("dangling", "missing"),
+            ("absolute", "/etc/hostname"),
+        ] {
+            let metadata = fs::symlink_metadata(unpacked.join(link))?;
+            assert!(
+                metadata.file_type().is_symlink(),
+                "{} is not a symlink",
+                link
+            );
+            assert_eq!(fs::read_link(unpacked.join(link))?, Path::new(target));
+        }
+        assert_eq!(fs::read(unpacked.join("link"))?, b"data");
+        let link_metadata = fs::symlink_metadata(unpacked.join("link"))?;
+        assert_eq!(link_metadata.mtime(), 1_600_000_000);
+        let file_metadata = fs::metadata(unpacked.join("file.txt"))?;
+        assert_ne!(file_metadata.mtime(), 1_600_000_000);
+        if is_root {
+            assert_eq!(link_metadata.uid(), 54321);
+            assert_eq!(file_metadata.uid(), 0);
+        }
+
+        // unpacking again replaces the symlinks
+        unpack(&mut packer, archive.as_slice(), output.clone())?;
+        assert_eq!(fs::read_link(unpacked.join("link"))?, Path::new("file.txt"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_symlink_roundtrip_bag() -> anyhow::Result<()> {
+        test_symlink_roundtrip(BagArchive::new(), "packer_symlink_roundtrip_bag")
+    }
+
+    #[test]
+    fn test_symlink_roundtrip_tar() -> anyhow::Result<()> {
+        test_symlink_roundtrip(TarArchive::new(), "packer_symlink_roundtrip_tar")
+    }
+
+    fn test_overwrite<T: PackerBackend>(mut packer: T, name: &str) -> anyhow::Result<()> {
+        let dir = TestDir::new(name)?;
+        dir.write_files(&[("input/old.txt", "archive"), ("input/new.txt", "archive")])?;
+        let input = dir.join("input");
+        let set_mtime = |path: &Path, time: i64| {
+            let time = FileTime::from_unix_time(time, 0);
+            filetime::set_symlink_file_times(path, time, time)
+        };
+        set_mtime(&input.join("old.txt"), 1000)?;
+        set_mtime(&input.join("new.txt"), 3000)?;
+        create_symlink("new.txt", input.join("link"))?;
+        set_mtime(&input.join("link"), 3000)?;
+        let archive = pack_files(&packer, &[input])?;
+
+        let output = dir.join("output");
+        let outside = dir.join("outside.txt");
+        // the output directory with existing files; all modified at 2000
+        let prepare_output = || -> anyhow::Result<PathBuf> {
+            let _ = fs::remove_dir_all(&output);
+            let existing = output.join("input");
+            fs::create_dir_all(&existing)?;
+            fs::write(&outside, b"outside")?;
+            create_symlink(&outside, existing.join("old.txt"))?;
+            fs::write(existing.join("new.txt"), b"existing")?;
+            fs::write(existing.join("link"), b"existing")?;
+            for file in ["old.txt", "new.txt", "link"] {
+                set_mtime(&existing.join(file), 2000)?;
+            }
+            Ok(existing)
+        };
+        let mut unpack_overwrite = |overwrite| {
+            let options = UnpackOptions {
+                overwrite,
+                ..Default::default()
+            };
+            unpack_with_options(&mut packer, archive.as_slice(), output.clone(), &options)
+        };
+
+        // existing files are replaced, never written through
+        let existing = prepare_output()?;
+        unpack_overwrite(Overwrite::Always)?;
+        assert_eq!(fs::read(existing.join("old.txt"))?, b"archive");
+        assert!(!fs::symlink_metadata(existing.join("old.txt"))?.is_symlink());
+        assert_eq!(fs::read(&outside)?, b"outside");
+        assert_eq!(fs::read_link(existing.join("link"))?, Path::new("new.txt"));
+
+        let existing = prepare_output()?;
+        unpack_overwrite(Overwrite::Never)?;
+        assert_eq!(fs::read_link(existing.join("old.txt"))?, outside);
+        assert_eq!(fs::read(existing.join("new.txt"))?, b"existing");
+        assert_eq!(fs::read(existing.join("link"))?, b"existing");
+
+        let existing = prepare_output()?;
+        unpack_overwrite(Overwrite::Newer)?;
+        assert_eq!(fs::read_link(existing.join("old.txt"))?, outside);
+        assert_eq!(fs::read(existing.join("new.txt"))?, b"archive");
+        assert_eq!(fs::read_link(existing.join("link"))?, Path::new("new.txt"));
+
+        prepare_output()?;
+        let err = unpack_overwrite(Overwrite::Error).unwrap_err();
+        assert!(format!("{:#}", err).contains("already exists"), "{:#}", err);
+
+        // existing directories are unpacked into, not backed up
+        let existing = prepare_output()?;
+        unpack_overwrite(Overwrite::Backup)?;
+        unpack_overwrite(Overwrite::Backup)?;
+        assert_eq!(fs::read(existing.join("new.txt"))?, b"archive");
+        assert_eq!(fs::read(existing.join("new.txt.orig"))?, b"existing");
+        assert_eq!(fs::read(existing.join("new.txt.orig.1"))?, b"archive");
+        assert_eq!(fs::read_link(existing.join("old.txt.orig"))?, outside);
+        assert_eq!(fs::read(existing.join("link.orig"))?, b"existing");
+        assert!(!output.join("input.orig").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_overwrite_bag() -> anyhow::Result<()> {
+        test_overwrite(BagArchive::new(), "packer_overwrite_bag")
+    }
+
+    #[test]
+    fn test_overwrite_tar() -> anyhow::Result<()> {
+        test_overwrite(TarArchive::new(), "packer_overwrite_tar")
+    }
+
+    #[test]
+    fn test_atomic_unpack() -> anyhow::Result<()> {
+        let dir = TestDir::new("packer_atomic_unpack")?;
+        dir.write_files(&[("input/file.txt", &"hello world\n".repeat(100))])?;
+        let mut packer = BagArchive::new().with_index(false);
+        let archive = pack_files(&packer, &[dir.join("input")])?;
+        // damage the data, which is only found after all of it is written out
+        let mut damaged = archive.clone();
+        let end = damaged.len() - 128;
+        damaged[end - 1] ^= 1;
+        let file_names = |dir: &Path| -> anyhow::Result<Vec<String>> {
+            let mut names = fs::read_dir(dir)?
+                .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            names.sort();
+            Ok(names)
+        };
+
+        // an existing file is left as it is, and no temporary file is left behind
+        dir.write_files(&[("output/input/file.txt", "previous")])?;
+        let output = dir.join("output");
+        assert!(unpack(&mut packer, damaged.as_slice(), output.clone()).is_err());
+        assert_eq!(fs::read(output.join("input/file.txt"))?, b"previous");
+        assert_eq!(file_names(&output.join("input"))?, ["file.txt"]);
+        let options = UnpackOptions {
+            sync: true,
+            ..Default::default()
+        };
+        unpack_with_options(&mut packer, archive.as_slice(), output.clone(), &options)?;
+        assert_eq!(fs::read(output.join("input/file.txt"))?.len(), 1200);
+        assert_eq!(file_names(&output.join("input"))?, ["file.txt"]);
+
+        // the whole tree appears at once with a staging directory
+        let options = UnpackOptions {
+            staging: true,
+            ..Default::default()
+        };
+        let staged = dir.join("staged");
+        assert!(
+            unpack_with_options(&mut packer, damaged.as_slice(), staged.clone(), &options).is_err()
+        );
+        assert!(!staged.exists());
+        unpack_with_options(&mut packer, archive.as_slice(), staged.clone(), &options)?;
+        assert_eq!(fs::read(staged.join("input/file.txt"))?.len(), 1200);
+        // which replaces only an empty output directory
+        assert!(
+            unpack_with_options(&mut packer, archive.as_slice(), staged.clone(), &options).is_err()
+        );
+        assert_eq!(file_names(dir.path())?, ["input", "output", "staged"]);
+        Ok(())
+    }
+}
@@ -0,0 +1,226 @@
+//! Verification of the integrity of an archive, without unpacking it.
+
+use std::io::{self, Read};
+use std::path::PathBuf;
+
+use anyhow::{bail, Context};
+
+use crate::backend::{AsHeader, DamagedData, FileMetadata, FileType, PackerBackend};
+
+/// A problem found in an archive by [`verify`].
+#[derive(Debug)]
+pub struct VerifyProblem {
+    /// Path of the file the problem is in. `None` for problems of the archive as a whole, like a
+    /// missing end of archive marker.
+    pub file_name: Option<PathBuf>,
+    /// Description of the problem.
+    pub message: String,
+}
+
+/// Result of verifying an archive with [`verify`].
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    /// Metadata of the entries that were read, in the order they are stored.
+    pub entries: Vec<FileMetadata>,
+    /// Problems found in the archive. Empty if the archive is intact.
+    pub problems: Vec<VerifyProblem>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Verify an archive read from `reader` using the given packer backend, without unpacking it.
+///
+/// The global header, all the file headers (along with their checksums), the length of the data
+/// of each file and its digest (for formats storing one) are checked. The archive has to end with
+/// the end of archive marker; only zeros (as written by tar to fill the last record) may follow
+/// it.
+///
+/// Damaged file data is reported, and the rest of the archive is verified. Any other problem,
+/// like a corrupted header or a truncated archive, stops the verification; as the rest of the
+/// archive can't be read reliably after that.
+pub fn verify<T: PackerBackend, R: Read>(packer: &mut T, mut reader: R) -> VerifyReport {
+    let mut report = VerifyReport::default();
+    if let Err(problem) = verify_entries(packer, &mut reader, &mut report) {
+        report.problems.push(problem);
+    }
+    report
+}
+
+fn verify_entries<T: PackerBackend, R: Read>(
+    packer: &mut T,
+    reader: &mut R,
+    report: &mut VerifyReport,
+) -> Result<(), VerifyProblem> {
+    let archive_problem = |err: anyhow::Error| VerifyProblem {
+        file_name: None,
+        message: format!("{:#}", err),
+    };
+    packer
+        .read_prologue(reader)
+        .context("Invalid archive header")
+        .map_err(archive_problem)?;
+
+    let mut header_buffer = vec![0u8; packer.header_block_size()];
+    loop {
+        reader
+            .read_exact(&mut header_buffer)
+            .context("The archive is truncated; the end of archive marker is missing")
+            .map_err(archive_problem)?;
+        if packer.is_eoa(reader, &header_buffer) {
+            break;
+        }
+
+        let header = packer
+            .unpack_header(reader, &header_buffer)
+            .with_context(|| match report.entries.last() {
+                Some(previous) => format!("Invalid header after {}", previous.file_name.display()),
+                None => "Invalid header of the first file".to_string(),
+            })
+            .map_err(archive_problem)?;
+        let metadata = header.get_metadata();
+        log::debug!("Verifying: {}", metadata.file_name.display());
+
+        let result = if metadata.file_type == FileType::Regular {
+            packer.unpack_data(reader, &header, &mut io::sink())
+        } else {
+            packer.skip_data(reader, &header)
+        };
+        match result {
+            Ok(()) => {}
+            Err(err) => match err.downcast_ref::<DamagedData>() {
+                Some(damaged) => report.problems.push(VerifyProblem {
+                    file_name: Some(metadata.file_name.clone()),
+                    message: format!("The data is damaged: {}", damaged.reason),
+                }),
+                None => {
+                    let truncated = err
+                        .root_cause()
+                        .downcast_ref::<io::Error>()
+                        .is_some_and(|err| err.kind() == io::ErrorKind::UnexpectedEof);
+                    let message = if truncated {
+                        "The archive is truncated in the data of this file".to_string()
+                    } else {
+                        format!("{:#}", err.context("Reading file data"))
+                    };
+                    return Err(VerifyProblem {
+                        file_name: Some(metadata.file_name),
+                        message,
+                    });
+                }
+            },
+        }
+        report.entries.push(metadata);
+    }
+
+    packer.read_epilogue(reader).map_err(archive_problem)?;
+    check_trailing_data(reader).map_err(archive_problem)
+}
+
+/// Check that nothing but zeros follow the end of the archive.
+fn check_trailing_data<R: Read>(reader: &mut R) -> anyhow::Result<()> {
+    let mut buffer = [0u8; 8192];
+    let mut trailing = 0u64;
+    let mut garbage = false;
+    loop {
+        let read = reader
+            .read(&mut buffer)
+            .context("Reading after the end of archive marker")?;
+        if read == 0 {
+            break;
+        }
+        garbage |= buffer[..read].iter().any(|byte| *byte != 0);
+        trailing += read as u64;
+    }
+    if garbage {
+        bail!(
+            "{} bytes of trailing data after the end of archive marker",
+            trailing
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{pack_files, TestDir};
+    use crate::{BagArchive, TarArchive};
+
+    fn pack_test_files<T: PackerBackend>(packer: &T, name: &str) -> anyhow::Result<Vec<u8>> {
+        let dir = TestDir::new(name)?;
+        dir.write_files(&[
+            ("a.txt", &"aaaa".repeat(100)),
+            ("b.txt", &"bbbb".repeat(100)),
+        ])?;
+        pack_files(packer, &[dir.path().to_path_buf()])
+    }
+
+    #[test]
+    fn test_verify_bag() -> anyhow::Result<()> {
+        let mut packer = BagArchive::new().with_index(false);
+        let archive = pack_test_files(&packer, "packer_verify_bag")?;
+        let report = verify(&mut packer, archive.as_slice());
+        assert!(report.is_ok(), "{:?}", report.problems);
+        assert_eq!(report.entries.len(), 3);
+
+        // damaged data of a file is reported, and the rest is still verified
+        let mut damaged = archive.clone();
+        let position = damaged.windows(4).position(|w| w == b"aaaa").unwrap();
+        damaged[position] = b'x';
+        let report = verify(&mut packer, damaged.as_slice());
+        assert_eq!(report.entries.len(), 3);
+        assert_eq!(report.problems.len(), 1);
+        let problem = &report.problems[0];
+        assert!(problem.file_name.as_ref().unwrap().ends_with("a.txt"));
+
+        // a corrupted header stops the verification
+        let mut corrupted = archive.clone();
+        corrupted[64 + 10] ^= 0xff;
+        let report = verify(&mut packer, corrupted.as_slice());
+        assert!(report.entries.is_empty());
+        assert!(report.problems[0].message.contains("Invalid header"));
+
+        // truncated archive
+        let report = verify(&mut packer, &archive[..archive.len() - 128]);
+        assert!(report.problems[0].message.contains("truncated"));
+
+        // trailing garbage
+        let mut trailing = archive.clone();
+        trailing.extend_from_slice(b"garbage");
+        let report = verify(&mut packer, trailing.as_slice());
+        assert_eq!(report.entries.len(), 3);
+        assert!(report.problems[0]
+            .message
+            .contains("7 bytes of trailing data"));
+
+        // the index is checked too
+        let mut packer = BagArchive::new();
+        let mut archive = pack_test_files(&packer, "packer_verify_bag_index")?;
+        let report = verify(&mut packer, archive.as_slice());
+        assert!(report.is_ok(), "{:?}", report.problems);
+        let end = archive.len() - 40;
+        archive[end] ^= 1;
+        let report = verify(&mut packer, archive.as_slice());
+        assert!(report.problems[0].message.contains("index"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_tar() -> anyhow::Result<()> {
+        let mut packer = TarArchive::new();
+        let mut archive = pack_test_files(&packer, "packer_verify_tar")?;
+        // tar fills up the last record with zeros
+        archive.resize(archive.len().div_ceil(10240) * 10240, 0);
+        let report = verify(&mut packer, archive.as_slice());
+        assert!(report.is_ok(), "{:?}", report.problems);
+        assert_eq!(report.entries.len(), 3);
+
+        let report = verify(&mut packer, &archive[..1024]);
+        assert!(!report.is_ok());
+        Ok(())
+    }
+}
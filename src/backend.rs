@@ -1,11 +1,11 @@
 //! An abstract interface for various archiving backends. Each backend supports different archive formats.
 
 pub mod bag;
+mod owner_names;
 pub mod tar;
 
-use std::fs::File;
-use std::io::{BufReader, BufWriter};
-use std::{fs, path::PathBuf};
+use std::io::{self, Read, Seek, Write};
+use std::{fmt, fs, path::PathBuf};
 
 /// Represent different paths that we care about
 #[derive(Debug)]
@@ -18,21 +18,97 @@ pub struct FilePath {
     pub system_path: PathBuf,
 }
 
+/// Type of a file stored in an archive.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FileType {
+    Regular,
+    HardLink,
+    SymLink,
+    CharDevice,
+    BlockDevice,
+    Directory,
+    Fifo,
+}
+
+/// Metadata of a file as stored in an archive. This is the format-independent view of a header,
+/// obtained via [`AsHeader::get_metadata`].
 #[derive(Debug)]
-#[allow(dead_code)]
 pub struct FileMetadata {
+    /// Path of the file inside the archive.
     pub file_name: PathBuf,
+    /// Type of the file.
+    pub file_type: FileType,
+    /// Size of the file data in bytes.
     pub file_size: u64,
+    /// File permissions or mode.
     pub file_mode: u32,
+    /// uid of the file owner.
     pub user_id: u32,
+    /// gid of the file group.
     pub group_id: u32,
+    /// Name of the file owner, if the format stores it.
+    pub user_name: Option<String>,
+    /// Name of the file group, if the format stores it.
+    pub group_name: Option<String>,
+    /// Created time of the file, as a unix timestamp. Formats that don't store it, set it to 0.
     pub created_at: i64,
+    /// Last modified time of the file, as a unix timestamp.
     pub last_modified: i64,
+    /// Nanoseconds part of the last modified time. Formats that don't store it, set it to 0.
+    pub last_modified_nanos: u32,
+    /// Target of the link, if the file is a symlink or a hard link. The target of a hard link is
+    /// the path of an earlier file inside the archive.
     pub link_name: Option<PathBuf>,
+    /// Major device number, if the file is a character or block device. 0 otherwise.
+    pub dev_major: u32,
+    /// Minor device number, if the file is a character or block device. 0 otherwise.
+    pub dev_minor: u32,
+}
+
+/// An entry of the index of an archive, locating a file in it for random access. See
+/// [`PackerBackend::read_index`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexEntry {
+    /// Path of the file inside the archive.
+    pub file_name: PathBuf,
+    /// Type of the file.
+    pub file_type: FileType,
+    /// Offset of the header of the file, from the start of the archive.
+    pub header_offset: u64,
+    /// Offset of the data of the file, from the start of the archive.
+    pub data_offset: u64,
+    /// Size of the file data in bytes.
+    pub file_size: u64,
 }
 
+/// Error for file data which doesn't match the digest stored along with it. The data has been read
+/// in full when this is returned, so the rest of the archive can still be read.
+#[derive(Debug)]
+pub struct DamagedData {
+    /// Path of the damaged file inside the archive.
+    pub file_name: PathBuf,
+    /// What doesn't match.
+    pub reason: String,
+}
+
+impl fmt::Display for DamagedData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "The data of {} is damaged: {}",
+            self.file_name.display(),
+            self.reason
+        )
+    }
+}
+
+impl std::error::Error for DamagedData {}
+
 /// Indicates a specific packer backend, or in other words a different archive format. Each archive
 /// format is backed by a backend implementation. Currently we support the BAG and TAR formats.
+///
+/// Backends work on any [`Write`] (while packing) or [`Read`] (while unpacking), so archives can be
+/// written to and read from files, in-memory buffers, pipes etc. alike.
 pub trait PackerBackend {
     /// The header type
     type Header: AsHeader;
@@ -40,44 +116,101 @@ pub trait PackerBackend {
     /// End of archive (EOA) marker.
     type EOAMarker;
 
-    /** packing related functions **/
+    /* packing related functions */
 
     /// Write any prologue at the begining of the archive file.
-    fn write_prologue(&self, writer: &mut BufWriter<File>) -> anyhow::Result<()>;
+    fn write_prologue<W: Write>(&self, writer: &mut W) -> anyhow::Result<()>;
 
-    /// Pack a header to the writer.
-    fn pack_header(
+    /// Pack a header to the writer. Returns the size of the data that follows the header; only
+    /// regular files have data.
+    fn pack_header<W: Write>(
         &self,
-        writer: &mut BufWriter<File>,
+        writer: &mut W,
         file: &FilePath,
         metadata: fs::Metadata,
-        // only set if the file is a symlink
+        file_type: FileType,
+        // only set if the file is a symlink or a hard link
         link_name: Option<PathBuf>,
     ) -> anyhow::Result<u64>;
 
+    /// Pack the data of a file, read from `data`, to the writer. `file_size` is the size of the
+    /// data, as returned by `pack_header`.
+    fn pack_data<W: Write, D: Read>(
+        &self,
+        writer: &mut W,
+        data: &mut D,
+        file_size: u64,
+    ) -> anyhow::Result<()>;
+
     /// Write any epilogue at the end of the archive file. For example, this can be used to write
     /// End Of Archive (EOF) markers.
-    fn write_epilogue(&self, writer: &mut BufWriter<File>) -> anyhow::Result<()>;
+    fn write_epilogue<W: Write>(&self, writer: &mut W) -> anyhow::Result<()>;
 
-    /** unpacking related functions **/
+    /* unpacking related functions */
 
-    /// Read any prologue at the begining of the archive file.
-    fn read_prologue(&self, reader: &mut BufReader<File>) -> anyhow::Result<()>;
+    /// Read any prologue at the begining of the archive file. This can record any state required
+    /// for the rest of the archive, like the compression of a bag archive.
+    fn read_prologue<R: Read>(&mut self, reader: &mut R) -> anyhow::Result<()>;
 
-    /// Unpack a header from the reader.
-    fn unpack_header(
-        &self,
-        reader: &mut BufReader<File>,
+    /// Unpack a header from the reader. This can record any state required for the rest of the
+    /// archive, like the attributes of tar global headers.
+    fn unpack_header<R: Read>(
+        &mut self,
+        reader: &mut R,
         header_buffer: &[u8],
     ) -> anyhow::Result<Self::Header>;
 
+    /// Unpack the data of the file described by `header` from the reader, writing it to `out`.
+    fn unpack_data<R: Read, O: Write>(
+        &self,
+        reader: &mut R,
+        header: &Self::Header,
+        out: &mut O,
+    ) -> anyhow::Result<()>;
+
+    /// Skip over the data of the file described by `header`, without unpacking it. By default the
+    /// data is read and discarded.
+    fn skip_data<R: Read>(&self, reader: &mut R, header: &Self::Header) -> anyhow::Result<()> {
+        self.unpack_data(reader, header, &mut io::sink())
+    }
+
+    /// Skip over the data of the file described by `header` in a seekable reader. Backends which
+    /// know the size of the data in the archive, seek past it instead of reading it. By default
+    /// this is the same as [`PackerBackend::skip_data`].
+    fn seek_data<R: Read + Seek>(
+        &self,
+        reader: &mut R,
+        header: &Self::Header,
+    ) -> anyhow::Result<()> {
+        self.skip_data(reader, header)
+    }
+
+    /// Read the index of the archive, for random access to its files; if the format has one, and
+    /// the archive has it. `start` is the offset of the start of the archive in the reader; and the
+    /// prologue is already read. The position of the reader is unspecified after this. By default
+    /// there is no index.
+    fn read_index<R: Read + Seek>(
+        &self,
+        _reader: &mut R,
+        _start: u64,
+    ) -> anyhow::Result<Option<Vec<IndexEntry>>> {
+        Ok(None)
+    }
+
     /// Check if End Of Archive (EOA) is reached
-    fn is_eoa(&self, reader: &mut BufReader<File>, header_buffer: &[u8]) -> bool;
+    fn is_eoa<R: Read>(&self, reader: &mut R, header_buffer: &[u8]) -> bool;
+
+    /// Read any epilogue at the end of the archive file, after the EOA has been reached. This
+    /// consumes the rest of the End Of Archive (EOA) marker, so that the archive is read till its
+    /// end even when it is streamed via a pipe.
+    fn read_epilogue<R: Read>(&self, reader: &mut R) -> anyhow::Result<()>;
 
     /// Get the header block size
     fn header_block_size(&self) -> usize;
 }
 
+/// A header of an archive format, from which file metadata can be obtained.
 pub trait AsHeader {
+    /// Get the metadata of the file described by this header.
     fn get_metadata(&self) -> FileMetadata;
 }
@@ -12,18 +12,23 @@
  * <File2-Data>
  * ...
  * <EOA-MARKER>
+ * <Index>
  * --------------
  *
- * This is all serialized in binary. No compression is performed.
+ * This is all serialized in binary. The file data can optionally be compressed, see the
+ * bag::compression module.
  *
  * - **Global Header** : is a structure containing information about the archive itself, version if
  * required etc. Block of 64 bytes.
  * - **File Header** : For each file to be archived, a file header structure is created containing file
  * metadata like name, size, permissions etc. Block of 64 bytes.
- * - **File data** : The file data verbatim as read from the source as byte array and written into the
- * archive.
+ * - **File data** : The file data as read from the source as byte array and written into the
+ * archive; either verbatim, or compressed with the compression given in the global header. It is
+ * followed by a digest of the data, see the bag::digest module.
  * - **EOA marker** : End of archive marker. A block size of 128 NULL bytes is written at the end to
  * signify EOF of the archive.
+ * - **Index** : Optional. An index of all the files in the archive, for random access, see the
+ * bag::index module.
  *
  * Ordering of files do not matter. If there are nested directories present, the file name is
  * encoded with the nested path.
@@ -32,30 +37,131 @@
  */
 
 mod byteorder;
+mod compression;
+mod digest;
 mod global_header;
 mod header;
+mod index;
 
 use std::{
-    fs::File,
-    io::{BufReader, BufWriter, Read, Write},
+    cell::RefCell,
+    io::{self, Read, Seek, SeekFrom, Write},
     path::PathBuf,
 };
 
-use anyhow::{self, Context};
+use anyhow::{self, bail, Context};
 
-use byteorder::bytes_to_path;
+use crate::archive::file::read_file_slice_chunked;
+use byteorder::{bytes_to_path, bytes_to_u32};
+use digest::{DigestReader, DigestWriter};
 use global_header::GlobalHeader;
-use header::{FileHeader, TypeFlag};
+use header::{FileHeader, TypeFlag, DEVICE_NUMBERS_SIZE};
+use index::{CountingWriter, IndexBuilder};
 
-use super::{AsHeader, PackerBackend};
+pub use compression::BagCompression;
+pub use digest::BagDigest;
+
+use super::owner_names::OwnerNames;
+use super::{AsHeader, DamagedData, FileType, IndexEntry, PackerBackend};
 
 const EOF_MARKER: [u8; 128] = [0; 128];
 
-pub struct BagArchive;
+/// The packer backend for the BAG archive format.
+pub struct BagArchive {
+    /// Compression of the file data, while packing.
+    compression: BagCompression,
+    /// Compression level, while packing. The default level of the compression is used if not set.
+    level: Option<i32>,
+    /// Digest algorithm of the file data, while packing.
+    digest: BagDigest,
+    /// Whether to write an index at the end of the archive, while packing.
+    write_index: bool,
+    /// The index of the archive being packed. Packing only borrows the backend, so this is kept in
+    /// a cell.
+    index: RefCell<IndexBuilder>,
+    /// Names of the owners of the files being packed.
+    owner_names: OwnerNames,
+    /// Compression of the archive being unpacked, as given in its global header.
+    archive_compression: BagCompression,
+    /// Digest algorithm of the archive being unpacked, as given in its global header.
+    archive_digest: BagDigest,
+    /// Whether the archive being unpacked has an index, as given in its global header.
+    archive_has_index: bool,
+    /// Whether the file headers of the archive being unpacked have the user and group names.
+    archive_has_owner_names: bool,
+}
+
+impl Default for BagArchive {
+    fn default() -> Self {
+        Self {
+            compression: BagCompression::default(),
+            level: None,
+            digest: BagDigest::default(),
+            write_index: true,
+            index: RefCell::default(),
+            owner_names: OwnerNames::default(),
+            archive_compression: BagCompression::default(),
+            archive_digest: BagDigest::default(),
+            archive_has_index: false,
+            archive_has_owner_names: true,
+        }
+    }
+}
 
 impl BagArchive {
     pub fn new() -> Self {
-        Self
+        Self::default()
+    }
+
+    /// Create a bag backend, which compresses the file data with the given compression and
+    /// optional level. Fails if the level is not valid for the compression.
+    pub fn with_compression(
+        compression: BagCompression,
+        level: Option<i32>,
+    ) -> anyhow::Result<Self> {
+        if let Some(level) = level {
+            compression.validate_level(level)?;
+        }
+        Ok(Self {
+            compression,
+            level,
+            ..Self::default()
+        })
+    }
+
+    /// Use the given digest algorithm for the file data, instead of the default CRC32C.
+    pub fn with_digest(self, digest: BagDigest) -> Self {
+        Self { digest, ..self }
+    }
+
+    /// Set whether to write an index at the end of the archive. It is written by default.
+    pub fn with_index(self, write_index: bool) -> Self {
+        Self {
+            write_index,
+            ..self
+        }
+    }
+
+    /// Skip over the data of a file, along with its digest. `skip` is called with the number of
+    /// bytes to skip, and has to skip over that many bytes of the reader.
+    fn skip_file_data<R: Read>(
+        &self,
+        reader: &mut R,
+        header: &FileHeader,
+        mut skip: impl FnMut(&mut R, u64) -> io::Result<()>,
+    ) -> anyhow::Result<()> {
+        // only regular files have data; so there is nothing to skip for the rest. Their size may
+        // not be zero though; V1 archives store the length of the target as the size of symlinks.
+        if header.type_flag != TypeFlag::Regular {
+            return Ok(());
+        }
+        if self.archive_compression != BagCompression::None {
+            compression::skip_chunks(reader, &mut skip)?;
+        } else {
+            skip(reader, header.file_size)?;
+        }
+        skip(reader, self.archive_digest.size() as u64)?;
+        Ok(())
     }
 }
 
@@ -63,13 +169,19 @@ impl AsHeader for FileHeader {
     fn get_metadata(&self) -> super::FileMetadata {
         super::FileMetadata {
             file_name: self.file_name.clone(),
+            file_type: self.type_flag.into(),
             file_size: self.file_size,
             file_mode: self.file_mode,
             user_id: self.user_id,
             group_id: self.group_id,
+            user_name: self.user_name.clone(),
+            group_name: self.group_name.clone(),
             created_at: self.created_at,
             last_modified: self.last_modified,
+            last_modified_nanos: 0,
             link_name: self.link_name.clone(),
+            dev_major: self.dev_major,
+            dev_minor: self.dev_minor,
         }
     }
 }
@@ -78,56 +190,116 @@ impl PackerBackend for BagArchive {
     type Header = FileHeader;
     type EOAMarker = [u8; 128];
 
-    fn write_prologue(&self, writer: &mut BufWriter<File>) -> anyhow::Result<()> {
-        let header = GlobalHeader::new();
+    fn write_prologue<W: Write>(&self, writer: &mut W) -> anyhow::Result<()> {
+        let header = GlobalHeader::new(self.compression, self.digest, self.write_index);
         let header_block = header.serialize()?;
         writer.write_all(&header_block)?;
+        self.index.borrow_mut().reset(header_block.len() as u64);
         Ok(())
     }
 
-    fn pack_header(
+    fn pack_header<W: Write>(
         &self,
-        writer: &mut std::io::BufWriter<std::fs::File>,
+        writer: &mut W,
         file: &super::FilePath,
         metadata: std::fs::Metadata,
+        file_type: FileType,
         link_name: Option<PathBuf>,
     ) -> anyhow::Result<u64> {
-        let header = FileHeader::new(&file.archive_path, metadata, link_name)?;
+        let header = FileHeader::new(
+            &file.archive_path,
+            metadata,
+            file_type,
+            link_name,
+            &self.owner_names,
+        )?;
         let file_size = header.file_size;
+        let type_flag = header.type_flag;
         log::trace!("Created header");
         header.pprint();
         log::trace!("Serializing header data..");
         let header_block = header.serialize()?;
         log::trace!("Writing header data..");
         writer.write_all(&header_block.header)?;
-        log::trace!("Writing filename and linkname..");
+        log::trace!("Writing filename, linkname and owner names..");
         writer.write_all(&header_block.file_name)?;
         writer.write_all(&header_block.link_name)?;
+        writer.write_all(&header_block.user_name)?;
+        writer.write_all(&header_block.group_name)?;
+        let header_size = header_block.size();
+        self.index.borrow_mut().add(
+            file.archive_path.clone(),
+            type_flag.into(),
+            file_size,
+            header_size as u64,
+        );
         Ok(file_size)
     }
 
-    fn write_epilogue(&self, writer: &mut BufWriter<File>) -> anyhow::Result<()> {
+    fn pack_data<W: Write, D: Read>(
+        &self,
+        writer: &mut W,
+        data: &mut D,
+        file_size: u64,
+    ) -> anyhow::Result<()> {
+        let mut writer = CountingWriter::new(writer);
+        let mut data = DigestReader::new(data, self.digest);
+        if self.compression != BagCompression::None {
+            compression::compress(
+                self.compression,
+                self.level,
+                &mut writer,
+                &mut data,
+                file_size,
+            )?;
+        } else {
+            read_file_slice_chunked(&mut data, file_size, |buffer| {
+                writer.write_all(buffer)?;
+                log::trace!("Wrote data to file..");
+                Ok(())
+            })?;
+        }
+        writer.write_all(&data.finalize())?;
+        self.index.borrow_mut().advance(writer.count);
+        Ok(())
+    }
+
+    fn write_epilogue<W: Write>(&self, writer: &mut W) -> anyhow::Result<()> {
         writer.write_all(&EOF_MARKER)?;
+        let mut index = self.index.borrow_mut();
+        index.advance(EOF_MARKER.len() as u64);
+        if self.write_index {
+            index.write(writer)?;
+        }
         Ok(())
     }
 
-    fn read_prologue(&self, reader: &mut BufReader<File>) -> anyhow::Result<()> {
+    fn read_prologue<R: Read>(&mut self, reader: &mut R) -> anyhow::Result<()> {
         let mut header_buffer = [0u8; 64];
         reader
             .read_exact(&mut header_buffer)
             .with_context(|| "Reading header")?;
-        GlobalHeader::deserialize(&header_buffer)?;
+        let header = GlobalHeader::deserialize(&header_buffer)?;
+        log::debug!("Archive compression: {:?}", header.compression);
+        log::debug!("Archive digest: {:?}", header.digest);
+        self.archive_compression = header.compression;
+        self.archive_digest = header.digest;
+        self.archive_has_index = header.has_index;
+        self.archive_has_owner_names = header.has_owner_names();
         Ok(())
     }
 
-    fn unpack_header(
-        &self,
-        reader: &mut BufReader<File>,
+    fn unpack_header<R: Read>(
+        &mut self,
+        reader: &mut R,
         header_buffer: &[u8],
     ) -> anyhow::Result<FileHeader> {
         // 3. deserialize into header
         // 4. this gives all the file metadata.
-        let (mut header, filename_size, linkname_size) = FileHeader::deserialize(header_buffer)?;
+        let (mut header, sizes) =
+            FileHeader::deserialize(header_buffer, self.archive_has_owner_names)?;
+        let filename_size = sizes.file_name;
+        let linkname_size = sizes.link_name;
         log::debug!("Parsed header: {:?}", header);
         log::debug!("Filename size: {:?}", filename_size);
         log::debug!("Link name size: {:?}", linkname_size);
@@ -139,25 +311,144 @@ impl PackerBackend for BagArchive {
         header.file_name = bytes_to_path(&filename_buffer)?;
         log::debug!("parsed filename: {:?}", header.file_name);
 
-        if header.type_flag == TypeFlag::SymLink {
-            // read the variable-length link name from the archive
-            let mut linkname_buffer = vec![0; linkname_size as usize];
-            reader.read_exact(&mut linkname_buffer)?;
-            log::trace!("link name raw: {:?}", linkname_buffer);
-            let linkname = bytes_to_path(&linkname_buffer)?;
-            let linkname_exists = !linkname.as_os_str().is_empty();
-            header.link_name = linkname_exists.then_some(linkname);
-            log::debug!("Parsed link name: {:?}", header.link_name);
+        match header.type_flag {
+            TypeFlag::SymLink | TypeFlag::HardLink => {
+                // read the variable-length link name from the archive
+                let mut linkname_buffer = vec![0; linkname_size as usize];
+                reader.read_exact(&mut linkname_buffer)?;
+                log::trace!("link name raw: {:?}", linkname_buffer);
+                let linkname = bytes_to_path(&linkname_buffer)?;
+                let linkname_exists = !linkname.as_os_str().is_empty();
+                header.link_name = linkname_exists.then_some(linkname);
+                log::debug!("Parsed link name: {:?}", header.link_name);
+            }
+            TypeFlag::CharDevice | TypeFlag::BlockDevice => {
+                // devices store their major and minor numbers in place of the link name
+                if linkname_size != DEVICE_NUMBERS_SIZE {
+                    bail!(
+                        "Invalid device numbers size {} for device {}",
+                        linkname_size,
+                        header.file_name.display()
+                    );
+                }
+                let mut device_buffer = [0u8; 8];
+                reader.read_exact(&mut device_buffer)?;
+                header.dev_major = bytes_to_u32(device_buffer[..4].try_into().unwrap());
+                header.dev_minor = bytes_to_u32(device_buffer[4..].try_into().unwrap());
+                log::debug!(
+                    "Parsed device numbers: {},{}",
+                    header.dev_major,
+                    header.dev_minor
+                );
+            }
+            _ => {}
         }
 
+        header.user_name = read_owner_name(reader, sizes.user_name)?;
+        header.group_name = read_owner_name(reader, sizes.group_name)?;
+        log::debug!(
+            "Parsed owner names: {:?} {:?}",
+            header.user_name,
+            header.group_name
+        );
+
         Ok(header)
     }
 
-    fn is_eoa(&self, _reader: &mut BufReader<File>, header_buffer: &[u8]) -> bool {
+    fn unpack_data<R: Read, O: Write>(
+        &self,
+        reader: &mut R,
+        header: &FileHeader,
+        out: &mut O,
+    ) -> anyhow::Result<()> {
+        let mut out = DigestWriter::new(out, self.archive_digest);
+        if self.archive_compression != BagCompression::None {
+            compression::decompress(self.archive_compression, reader, &mut out, header.file_size)
+                .with_context(|| format!("Unpacking data of {}", header.file_name.display()))?;
+        } else {
+            read_file_slice_chunked(reader, header.file_size, |buffer| {
+                out.write_all(buffer)?;
+                Ok(())
+            })?;
+        }
+
+        let digest = out.finalize();
+        let mut stored_digest = vec![0u8; self.archive_digest.size()];
+        reader
+            .read_exact(&mut stored_digest)
+            .with_context(|| format!("Reading digest of {}", header.file_name.display()))?;
+        if digest != stored_digest {
+            return Err(DamagedData {
+                file_name: header.file_name.clone(),
+                reason: format!(
+                    "its {:?} digest is {}, but {} is stored in the archive.",
+                    self.archive_digest,
+                    digest::to_hex(&digest),
+                    digest::to_hex(&stored_digest)
+                ),
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    fn skip_data<R: Read>(&self, reader: &mut R, header: &FileHeader) -> anyhow::Result<()> {
+        self.skip_file_data(reader, header, |reader, size| {
+            let skipped = io::copy(&mut reader.take(size), &mut io::sink())?;
+            if skipped < size {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            Ok(())
+        })
+    }
+
+    fn seek_data<R: Read + Seek>(&self, reader: &mut R, header: &FileHeader) -> anyhow::Result<()> {
+        self.skip_file_data(reader, header, |reader, size| {
+            reader.seek(SeekFrom::Current(size as i64)).map(|_| ())
+        })
+    }
+
+    fn read_index<R: Read + Seek>(
+        &self,
+        reader: &mut R,
+        start: u64,
+    ) -> anyhow::Result<Option<Vec<IndexEntry>>> {
+        if !self.archive_has_index {
+            return Ok(None);
+        }
+        index::read_index(reader, start).map(Some)
+    }
+
+    fn is_eoa<R: Read>(&self, _reader: &mut R, header_buffer: &[u8]) -> bool {
         header_buffer == [0u8; 64]
     }
 
+    fn read_epilogue<R: Read>(&self, reader: &mut R) -> anyhow::Result<()> {
+        // the first 64 bytes of the EOA marker are already read as a header block
+        let mut marker_buffer = [0u8; 64];
+        reader
+            .read_exact(&mut marker_buffer)
+            .with_context(|| "Reading end of archive marker")?;
+        if marker_buffer != EOF_MARKER[64..] {
+            bail!("Invalid end of archive marker. The BAG archive has corrupted data.");
+        }
+        if self.archive_has_index {
+            index::skip_index(reader).context("Reading the index of the archive")?;
+        }
+        Ok(())
+    }
+
     fn header_block_size(&self) -> usize {
         64
     }
 }
+
+/// Read a user or group name of `size` bytes, following the header. An empty name is unknown.
+fn read_owner_name<R: Read>(reader: &mut R, size: u8) -> anyhow::Result<Option<String>> {
+    if size == 0 {
+        return Ok(None);
+    }
+    let mut buffer = vec![0; size as usize];
+    reader.read_exact(&mut buffer)?;
+    Ok(Some(String::from_utf8_lossy(&buffer).into_owned()))
+}
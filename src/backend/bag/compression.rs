@@ -0,0 +1,301 @@
+//! Compression of the file data in a BAG archive.
+//!
+//! Only the data of regular files is compressed; headers, file names and link names are stored as
+//! is, so that the archive can be listed without decompressing anything. The data of each file is
+//! compressed as an independent stream, and the compressed stream is written as a sequence of
+//! chunks -
+//!
+//! --------------
+//! <chunk-size> - 4 bytes, little endian
+//! <chunk-data> - chunk-size bytes
+//! ...
+//! <0> - 4 bytes; a chunk of size 0 ends the data
+//! --------------
+//!
+//! This way the compressed data can be written as it is produced, without knowing its size
+//! upfront; and it can be skipped without decompressing it.
+
+use std::io::{self, Read, Write};
+
+use anyhow::{anyhow, bail};
+
+use super::byteorder::{bytes_to_u32, u32_to_bytes};
+use crate::archive::file::read_file_slice_chunked;
+
+/// Maximum size of a chunk of compressed data.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Compression of the file data in a BAG archive.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum BagCompression {
+    /// File data is stored verbatim.
+    #[default]
+    None,
+    /// Zstandard; a good balance of speed and compression ratio. Levels 1 to 22, defaults to 3.
+    Zstd,
+    /// Raw deflate, as used by gzip and zip. Levels 0 to 9, defaults to 6.
+    Deflate,
+    /// xz (LZMA2); slow, but with a high compression ratio. Levels 0 to 9, defaults to 6.
+    Xz,
+    /// LZ4; very fast, with a lower compression ratio. Has no levels.
+    Lz4,
+}
+
+impl BagCompression {
+    pub(crate) fn as_byte(&self) -> u8 {
+        match self {
+            Self::None => 0,
+            Self::Zstd => 1,
+            Self::Deflate => 2,
+            Self::Xz => 3,
+            Self::Lz4 => 4,
+        }
+    }
+
+    pub(crate) fn from_byte(byte: u8) -> anyhow::Result<Self> {
+        match byte {
+            0 => Ok(Self::None),
+            1 => Ok(Self::Zstd),
+            2 => Ok(Self::Deflate),
+            3 => Ok(Self::Xz),
+            4 => Ok(Self::Lz4),
+            _ => Err(anyhow!("Invalid compression byte: {:?}", byte)),
+        }
+    }
+
+    /// Check that the compression level is valid for this compression.
+    pub(crate) fn validate_level(&self, level: i32) -> anyhow::Result<()> {
+        let valid = match self {
+            Self::Zstd => zstd::compression_level_range().contains(&level),
+            Self::Deflate | Self::Xz => (0..=9).contains(&level),
+            Self::None | Self::Lz4 => bail!("{:?} compression has no levels", self),
+        };
+        if !valid {
+            bail!("Invalid compression level {} for {:?}", level, self);
+        }
+        Ok(())
+    }
+}
+
+/// Compress `file_size` bytes of data read from `data`, and write them as chunks to `writer`.
+pub(crate) fn compress<W: Write, D: Read>(
+    compression: BagCompression,
+    level: Option<i32>,
+    writer: &mut W,
+    data: &mut D,
+    file_size: u64,
+) -> anyhow::Result<()> {
+    let mut chunks = ChunkWriter::new(writer);
+    match compression {
+        BagCompression::None => bail!("Data is not compressed"),
+        BagCompression::Zstd => {
+            let level = level.unwrap_or(zstd::DEFAULT_COMPRESSION_LEVEL);
+            let mut encoder = zstd::Encoder::new(&mut chunks, level)?;
+            copy_data(data, &mut encoder, file_size)?;
+            encoder.finish()?;
+        }
+        BagCompression::Deflate => {
+            let level = flate2::Compression::new(level.unwrap_or(6) as u32);
+            let mut encoder = flate2::write::DeflateEncoder::new(&mut chunks, level);
+            copy_data(data, &mut encoder, file_size)?;
+            encoder.finish()?;
+        }
+        BagCompression::Xz => {
+            let mut encoder = xz2::write::XzEncoder::new(&mut chunks, level.unwrap_or(6) as u32);
+            copy_data(data, &mut encoder, file_size)?;
+            encoder.finish()?;
+        }
+        BagCompression::Lz4 => {
+            let mut encoder = lz4_flex::frame::FrameEncoder::new(&mut chunks);
+            copy_data(data, &mut encoder, file_size)?;
+            encoder.finish()?;
+        }
+    }
+    chunks.finish()
+}
+
+/// Read compressed chunks from `reader`, decompress them and write the data to `out`. Fails if
+/// the decompressed data is not of `file_size` bytes.
+pub(crate) fn decompress<R: Read, O: Write>(
+    compression: BagCompression,
+    reader: &mut R,
+    out: &mut O,
+    file_size: u64,
+) -> anyhow::Result<()> {
+    let mut chunks = ChunkReader::new(reader);
+    let mut decoder: Box<dyn Read + '_> = match compression {
+        BagCompression::None => bail!("Data is not compressed"),
+        BagCompression::Zstd => Box::new(zstd::Decoder::new(&mut chunks)?),
+        BagCompression::Deflate => Box::new(flate2::read::DeflateDecoder::new(&mut chunks)),
+        BagCompression::Xz => Box::new(xz2::read::XzDecoder::new(&mut chunks)),
+        BagCompression::Lz4 => Box::new(lz4_flex::frame::FrameDecoder::new(&mut chunks)),
+    };
+    let size = io::copy(&mut decoder, out)?;
+    drop(decoder);
+    if size != file_size {
+        bail!(
+            "Size of the decompressed data {} doesn't match the file size {}",
+            size,
+            file_size
+        );
+    }
+    // consume the rest of the chunks, till the end of the data
+    io::copy(&mut chunks, &mut io::sink())?;
+    Ok(())
+}
+
+/// Skip over the compressed chunks of a file. `skip` is called with the size of each chunk, and
+/// has to skip over that many bytes of the reader.
+pub(crate) fn skip_chunks<R: Read>(
+    reader: &mut R,
+    mut skip: impl FnMut(&mut R, u64) -> io::Result<()>,
+) -> anyhow::Result<()> {
+    loop {
+        let chunk_size = read_chunk_size(reader)?;
+        if chunk_size == 0 {
+            return Ok(());
+        }
+        skip(reader, chunk_size as u64)?;
+    }
+}
+
+fn copy_data<D: Read, W: Write>(data: &mut D, out: &mut W, file_size: u64) -> anyhow::Result<()> {
+    read_file_slice_chunked(data, file_size, |buffer| {
+        out.write_all(buffer)?;
+        Ok(())
+    })
+}
+
+fn read_chunk_size<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut size = [0u8; 4];
+    reader.read_exact(&mut size)?;
+    Ok(bytes_to_u32(size))
+}
+
+/// Writes data as chunks of at most [`CHUNK_SIZE`] bytes. [`ChunkWriter::finish`] has to be
+/// called at the end, to write the rest of the data and the end marker.
+struct ChunkWriter<'a, W: Write> {
+    writer: &'a mut W,
+    buffer: Vec<u8>,
+}
+
+impl<'a, W: Write> ChunkWriter<'a, W> {
+    fn new(writer: &'a mut W) -> Self {
+        Self {
+            writer,
+            buffer: Vec::with_capacity(CHUNK_SIZE),
+        }
+    }
+
+    fn write_chunk(&mut self, size: usize) -> io::Result<()> {
+        self.writer.write_all(&u32_to_bytes(size as u32))?;
+        self.writer.write_all(&self.buffer[..size])?;
+        self.buffer.drain(..size);
+        Ok(())
+    }
+
+    fn finish(mut self) -> anyhow::Result<()> {
+        if !self.buffer.is_empty() {
+            self.write_chunk(self.buffer.len())?;
+        }
+        self.writer.write_all(&u32_to_bytes(0))?;
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for ChunkWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        while self.buffer.len() >= CHUNK_SIZE {
+            self.write_chunk(CHUNK_SIZE)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // only whole chunks are written; the rest is written by `finish`
+        Ok(())
+    }
+}
+
+/// Reads the data of a sequence of chunks, till the end marker.
+struct ChunkReader<'a, R: Read> {
+    reader: &'a mut R,
+    /// Bytes left to read in the current chunk.
+    remaining: u32,
+    /// Whether the end marker has been read.
+    done: bool,
+}
+
+impl<'a, R: Read> ChunkReader<'a, R> {
+    fn new(reader: &'a mut R) -> Self {
+        Self {
+            reader,
+            remaining: 0,
+            done: false,
+        }
+    }
+}
+
+impl<R: Read> Read for ChunkReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+        if self.remaining == 0 {
+            self.remaining = read_chunk_size(self.reader)?;
+            if self.remaining == 0 {
+                self.done = true;
+                return Ok(0);
+            }
+        }
+        let size = buf.len().min(self.remaining as usize);
+        let read = self.reader.read(&mut buf[..size])?;
+        if read == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.remaining -= read as u32;
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compression_roundtrip() -> anyhow::Result<()> {
+        // large enough to span multiple chunks, even when compressed
+        let data: Vec<u8> = (0..300_000u64).map(|i| (i * i % 251) as u8).collect();
+        for compression in [
+            BagCompression::Zstd,
+            BagCompression::Deflate,
+            BagCompression::Xz,
+            BagCompression::Lz4,
+        ] {
+            let mut archive = Vec::new();
+            let size = data.len() as u64;
+            compress(compression, None, &mut archive, &mut data.as_slice(), size)?;
+            // something after the data, which must not be consumed
+            archive.extend_from_slice(b"next");
+
+            let mut reader = archive.as_slice();
+            let mut out = Vec::new();
+            decompress(compression, &mut reader, &mut out, size)?;
+            assert_eq!(out, data, "{:?}", compression);
+            assert_eq!(reader, b"next");
+
+            let mut reader = archive.as_slice();
+            skip_chunks(&mut reader, |reader, size| {
+                io::copy(&mut reader.take(size), &mut io::sink()).map(|_| ())
+            })?;
+            assert_eq!(reader, b"next");
+
+            // a wrong size is an error
+            let mut out = Vec::new();
+            let result = decompress(compression, &mut archive.as_slice(), &mut out, size + 1);
+            assert!(result.is_err());
+        }
+        Ok(())
+    }
+}
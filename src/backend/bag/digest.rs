@@ -0,0 +1,188 @@
+//! Digests of the file data in a BAG archive, to detect corrupted data.
+//!
+//! The digest is computed over the data of each regular file as it is read from the source, before
+//! any compression; and it is stored right after the (possibly compressed) data of the file -
+//!
+//! --------------
+//! <File-Header>
+//! <File-Data>
+//! <File-Digest> - 4 bytes for CRC32C (little endian), 32 bytes for BLAKE3 and SHA-256
+//! --------------
+//!
+//! The digest algorithm of the archive is given in the global header. V1 archives have no digests.
+
+use std::io::{self, Read, Write};
+
+use anyhow::anyhow;
+use crc_any::CRCu32;
+use sha2::Digest;
+
+/// Algorithm of the digests of the file data in a BAG archive.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum BagDigest {
+    /// No digests are stored.
+    None,
+    /// CRC32C (Castagnoli); fast, and enough to detect accidental corruption.
+    #[default]
+    Crc32c,
+    /// BLAKE3; a fast cryptographic hash.
+    Blake3,
+    /// SHA-256.
+    Sha256,
+}
+
+impl BagDigest {
+    pub(crate) fn as_byte(&self) -> u8 {
+        match self {
+            Self::None => 0,
+            Self::Crc32c => 1,
+            Self::Blake3 => 2,
+            Self::Sha256 => 3,
+        }
+    }
+
+    pub(crate) fn from_byte(byte: u8) -> anyhow::Result<Self> {
+        match byte {
+            0 => Ok(Self::None),
+            1 => Ok(Self::Crc32c),
+            2 => Ok(Self::Blake3),
+            3 => Ok(Self::Sha256),
+            _ => Err(anyhow!("Invalid digest byte: {:?}", byte)),
+        }
+    }
+
+    /// Size of a digest in the archive, in bytes.
+    pub(crate) fn size(&self) -> usize {
+        match self {
+            Self::None => 0,
+            Self::Crc32c => 4,
+            Self::Blake3 | Self::Sha256 => 32,
+        }
+    }
+}
+
+/// Computes a digest of the data given to it.
+pub(crate) enum Hasher {
+    None,
+    Crc32c(Box<CRCu32>),
+    Blake3(Box<blake3::Hasher>),
+    Sha256(sha2::Sha256),
+}
+
+impl Hasher {
+    pub(crate) fn new(digest: BagDigest) -> Self {
+        match digest {
+            BagDigest::None => Self::None,
+            BagDigest::Crc32c => Self::Crc32c(Box::new(CRCu32::crc32c())),
+            BagDigest::Blake3 => Self::Blake3(Box::new(blake3::Hasher::new())),
+            BagDigest::Sha256 => Self::Sha256(sha2::Sha256::new()),
+        }
+    }
+
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::None => {}
+            Self::Crc32c(crc) => crc.digest(data),
+            Self::Blake3(hasher) => {
+                hasher.update(data);
+            }
+            Self::Sha256(hasher) => hasher.update(data),
+        }
+    }
+
+    /// Get the digest, as it is stored in the archive.
+    pub(crate) fn finalize(self) -> Vec<u8> {
+        match self {
+            Self::None => vec![],
+            Self::Crc32c(crc) => crc.get_crc().to_le_bytes().to_vec(),
+            Self::Blake3(hasher) => hasher.finalize().as_bytes().to_vec(),
+            Self::Sha256(hasher) => hasher.finalize().to_vec(),
+        }
+    }
+}
+
+/// A reader which computes the digest of all the data read through it.
+pub(crate) struct DigestReader<'a, R: Read> {
+    reader: &'a mut R,
+    hasher: Hasher,
+}
+
+impl<'a, R: Read> DigestReader<'a, R> {
+    pub(crate) fn new(reader: &'a mut R, digest: BagDigest) -> Self {
+        Self {
+            reader,
+            hasher: Hasher::new(digest),
+        }
+    }
+
+    pub(crate) fn finalize(self) -> Vec<u8> {
+        self.hasher.finalize()
+    }
+}
+
+impl<R: Read> Read for DigestReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.reader.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+/// A writer which computes the digest of all the data written through it.
+pub(crate) struct DigestWriter<'a, W: Write> {
+    writer: &'a mut W,
+    hasher: Hasher,
+}
+
+impl<'a, W: Write> DigestWriter<'a, W> {
+    pub(crate) fn new(writer: &'a mut W, digest: BagDigest) -> Self {
+        Self {
+            writer,
+            hasher: Hasher::new(digest),
+        }
+    }
+
+    pub(crate) fn finalize(self) -> Vec<u8> {
+        self.hasher.finalize()
+    }
+}
+
+impl<W: Write> Write for DigestWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.writer.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Format a digest as hex, for error messages.
+pub(crate) fn to_hex(digest: &[u8]) -> String {
+    digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_digests() {
+        let digest = |algorithm: BagDigest| {
+            let mut hasher = Hasher::new(algorithm);
+            hasher.update(b"1234");
+            hasher.update(b"56789");
+            to_hex(&hasher.finalize())
+        };
+        assert_eq!(digest(BagDigest::None), "");
+        // the check value of CRC32C, stored little endian
+        assert_eq!(digest(BagDigest::Crc32c), "839206e3");
+        assert_eq!(
+            digest(BagDigest::Sha256),
+            "15e2b0d3c33891ebb0f1ef609ec419420c20e320ce94c65fbc8c3312448eb225"
+        );
+        assert_eq!(digest(BagDigest::Blake3).len(), 64);
+    }
+}
@@ -3,21 +3,61 @@ use std::io::Write;
 use anyhow::anyhow;
 use anyhow::bail;
 
+use super::compression::BagCompression;
+use super::digest::BagDigest;
+
+/*
+ * Layout of the global header -
+ *
+ * --------------------+---------------+--------+----------------------------------------------------+
+ * | Field             |  Size(bytes)  | Offset |  Remarks                                           |
+ * +-------------------+---------------+--------+----------------------------------------------------+
+ * | <preamble>        |  7            |  0     |  Always "BAG AF."                                  |
+ * | <version>         |  1            |  7     |  Version of the format                             |
+ * | <compression>     |  1            |  8     |  Compression of the file data. Since V2            |
+ * | <digest>          |  1            |  9     |  Digest algorithm of the file data. Since V2       |
+ * | <flags>           |  1            |  10    |  Bit 0: the archive has an index. Since V2         |
+ * +-------------------+---------------+--------+----------------------------------------------------+
+ *
+ * The rest of the 64 bytes block is padded with 0.
+ *
+ * Since V2, the file headers have the user and group names of the files.
+ */
+
 #[derive(Debug)]
 pub struct GlobalHeader {
     /// A static string. Always: "BAG Archive Format. By Packer. (c) Anon Ray."
     preamble: &'static str,
-    /// Version of the format used. Reserved for future changes.
+    /// Version of the format used.
     version: FormatVersion,
+    /// Compression of the file data. V1 archives are never compressed.
+    pub(crate) compression: BagCompression,
+    /// Algorithm of the digests stored after the file data. V1 archives have no digests.
+    pub(crate) digest: BagDigest,
+    /// Whether an index follows the EOA marker. V1 archives have no index.
+    pub(crate) has_index: bool,
+}
+
+impl GlobalHeader {
+    /// Whether the file headers have the user and group names of the files.
+    pub(crate) fn has_owner_names(&self) -> bool {
+        !matches!(self.version, FormatVersion::V1)
+    }
 }
 
+/// Flag of the global header, for archives with an index.
+const FLAG_INDEX: u8 = 1;
+
 const PREAMBLE: &str = "BAG AF.";
 
 impl GlobalHeader {
-    pub fn new() -> Self {
+    pub fn new(compression: BagCompression, digest: BagDigest, has_index: bool) -> Self {
         Self {
             preamble: PREAMBLE,
-            version: FormatVersion::V1,
+            version: FormatVersion::V2,
+            compression,
+            digest,
+            has_index,
         }
     }
 
@@ -26,44 +66,70 @@ impl GlobalHeader {
         ll.to_bytes()
     }
 
-    pub fn deserialize(bytes: &[u8]) -> anyhow::Result<()> {
+    pub fn deserialize(bytes: &[u8]) -> anyhow::Result<Self> {
         let ll = GlobalHeaderLL::from_bytes(bytes)?;
         let preamble = std::str::from_utf8(&ll.preamble)?;
         if preamble != PREAMBLE {
             bail!("Error: Not a BAG Archive format. Exiting.");
         }
-        let _version = FormatVersion::from_byte(ll.version)?;
-        Ok(())
+        let version = FormatVersion::from_byte(ll.version)?;
+        let (compression, digest, has_index) = match version {
+            FormatVersion::V1 => (BagCompression::None, BagDigest::None, false),
+            FormatVersion::V2 => (
+                BagCompression::from_byte(ll.compression)?,
+                BagDigest::from_byte(ll.digest)?,
+                ll.flags & FLAG_INDEX != 0,
+            ),
+        };
+        Ok(Self {
+            preamble: PREAMBLE,
+            version,
+            compression,
+            digest,
+            has_index,
+        })
     }
 }
 
 #[derive(Debug)]
 enum FormatVersion {
+    /// The initial version.
     V1,
+    /// Adds compression and digests of the file data, the index after the EOA marker, and the user
+    /// and group names to the file headers.
+    V2,
 }
 
 impl FormatVersion {
     fn as_byte(&self) -> u8 {
         match self {
             Self::V1 => 1,
+            Self::V2 => 2,
         }
     }
     fn from_byte(byte: u8) -> anyhow::Result<Self> {
         match byte {
             b'1' | 1 => Ok(Self::V1),
+            b'2' | 2 => Ok(Self::V2),
             _ => Err(anyhow!("Invalid version byte: {:?}", byte)),
         }
     }
 }
 
-/// Low-level repr of the global header. It is 45 bytes. But it is padded with 0s at the end to make
+/// Low-level repr of the global header. It is 11 bytes. But it is padded with 0s at the end to make
 /// the block size of 64 bytes. Headers are read/written as this block of 64 bytes.
 #[derive(Debug)]
 struct GlobalHeaderLL {
     /// A static string. Always: "BAG AF."
     preamble: [u8; 7],
-    /// Version of the format used. Reserved for future changes.
+    /// Version of the format used.
     version: u8,
+    /// Compression of the file data.
+    compression: u8,
+    /// Digest algorithm of the file data.
+    digest: u8,
+    /// Flags of the archive.
+    flags: u8,
 }
 
 impl GlobalHeaderLL {
@@ -75,6 +141,9 @@ impl GlobalHeaderLL {
         Self {
             preamble: buffer,
             version: header.version.as_byte(),
+            compression: header.compression.as_byte(),
+            digest: header.digest.as_byte(),
+            flags: if header.has_index { FLAG_INDEX } else { 0 },
         }
     }
 
@@ -82,9 +151,12 @@ impl GlobalHeaderLL {
         let mut data_buffer = Vec::new();
         data_buffer.write_all(&self.preamble)?;
         data_buffer.write_all(&[self.version])?;
+        data_buffer.write_all(&[self.compression])?;
+        data_buffer.write_all(&[self.digest])?;
+        data_buffer.write_all(&[self.flags])?;
 
         let mut buffer = [0u8; 64];
-        buffer[..8].copy_from_slice(&data_buffer);
+        buffer[..data_buffer.len()].copy_from_slice(&data_buffer);
 
         Ok(buffer)
     }
@@ -95,6 +167,46 @@ impl GlobalHeaderLL {
         }
         let preamble = bytes[0..7].try_into().unwrap();
         let version = bytes[7];
-        Ok(Self { preamble, version })
+        let compression = bytes[8];
+        let digest = bytes[9];
+        let flags = bytes[10];
+        Ok(Self {
+            preamble,
+            version,
+            compression,
+            digest,
+            flags,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_global_header_versions() -> anyhow::Result<()> {
+        let header = GlobalHeader::new(BagCompression::Zstd, BagDigest::Blake3, true);
+        let bytes = header.serialize()?;
+        assert_eq!(&bytes[..11], b"BAG AF.\x02\x01\x02\x01");
+        let header = GlobalHeader::deserialize(&bytes)?;
+        assert_eq!(header.compression, BagCompression::Zstd);
+        assert_eq!(header.digest, BagDigest::Blake3);
+        assert!(header.has_index);
+        assert!(header.has_owner_names());
+
+        // V1 archives have none of them, whatever the rest of the header holds
+        let mut v1 = bytes;
+        v1[7] = 1;
+        let header = GlobalHeader::deserialize(&v1)?;
+        assert_eq!(header.compression, BagCompression::None);
+        assert_eq!(header.digest, BagDigest::None);
+        assert!(!header.has_index);
+        assert!(!header.has_owner_names());
+
+        let mut unknown = bytes;
+        unknown[7] = 3;
+        assert!(GlobalHeader::deserialize(&unknown).is_err());
+        Ok(())
     }
 }
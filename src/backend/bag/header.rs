@@ -14,19 +14,30 @@
  * | <ctime>           |  8            |  28    |  Created time of file                              |
  * | <mtime>           |  8            |  36    |  Last modified time of file                        |
  * | <type-flag>       |  1            |  44    |  Flag indicating file type                         |
- * | <link-name-size>  |  8            |  45    |  Link name if file is symlink                      |
+ * | <link-name-size>  |  8            |  45    |  Size of link name, if file is a symlink/hard link |
  * | <checksum>        |  4            |  53    |  Checksum of this header, with null checksum field |
+ * | <user-name-size>  |  1            |  57    |  Size of the name of the file owner. Since V2      |
+ * | <group-name-size> |  1            |  58    |  Size of the name of the file group. Since V2      |
  * +-------------------+---------------+--------+----------------------------------------------------+
  *
- * This header data is of 57 bytes. But a header block is treated as 64 bytes block. After 57 bytes,
- * the block is padded with 0. Headers should be written and read as this block of 64 bytes.
+ * This header data is of 59 bytes (57 bytes in V1). But a header block is treated as 64 bytes
+ * block. The rest of the block is padded with 0. Headers should be written and read as this block
+ * of 64 bytes.
  *
  * Layout of file header, file name and file data -
  * --------------
  * <file-header> - 64 bytes
  * <file-name> - n bytes
+ * <link-name> - n bytes
+ * <user-name> - n bytes
+ * <group-name> - n bytes
  * <file-data> - n bytes
  * --------------
+ *
+ * The user and group names are empty if they are unknown, or longer than 255 bytes.
+ *
+ * Character and block devices have no link name. Instead the <link-name> area holds their device
+ * numbers; 8 bytes of major (4 bytes) and minor (4 bytes) numbers.
 */
 
 use std::io::Write;
@@ -35,17 +46,45 @@ use std::{fs, os::unix::fs::MetadataExt, path::Path, path::PathBuf};
 use anyhow::anyhow;
 use anyhow::bail;
 use crc_any::CRCu32;
+use nix::sys::stat::{major, minor};
 
 use crate::backend::bag::byteorder::{
     bytes_to_i64, bytes_to_path, bytes_to_u32, bytes_to_u64, i64_to_bytes, path_to_bytes,
     u32_to_bytes, u64_to_bytes,
 };
+use crate::backend::owner_names::OwnerNames;
+use crate::backend::FileType;
+
+/// Size of the device numbers of character and block devices, stored in place of the link name.
+pub(crate) const DEVICE_NUMBERS_SIZE: u64 = 8;
 
 #[derive(Debug)]
 pub struct HeaderBlock {
     pub(crate) header: [u8; 64],
     pub(crate) file_name: Vec<u8>,
     pub(crate) link_name: Vec<u8>,
+    pub(crate) user_name: Vec<u8>,
+    pub(crate) group_name: Vec<u8>,
+}
+
+impl HeaderBlock {
+    /// Size of the header block along with the names following it.
+    pub(crate) fn size(&self) -> usize {
+        self.header.len()
+            + self.file_name.len()
+            + self.link_name.len()
+            + self.user_name.len()
+            + self.group_name.len()
+    }
+}
+
+/// Sizes of the variable-length fields following a header block.
+#[derive(Debug)]
+pub(crate) struct NameSizes {
+    pub(crate) file_name: u64,
+    pub(crate) link_name: u64,
+    pub(crate) user_name: u8,
+    pub(crate) group_name: u8,
 }
 
 /// The binary layout of the File Header. This is what is actually stored in the archive.
@@ -63,6 +102,12 @@ struct FileHeaderLL {
     link_name: Vec<u8>,
     link_name_size: [u8; 8],
     checksum: [u8; 4],
+    /// Whether the header has the user and group names; i.e. the archive is V2 or later.
+    has_owner_names: bool,
+    user_name: Vec<u8>,
+    user_name_size: u8,
+    group_name: Vec<u8>,
+    group_name_size: u8,
 }
 
 impl FileHeaderLL {
@@ -74,21 +119,31 @@ impl FileHeaderLL {
             file_name_bytes,
             file_name_size
         );
-        let (link_name_bytes, link_name_size) = header
-            .link_name
-            .map(|link_name| {
-                let link_name_bytes = path_to_bytes(link_name)?;
-                let link_name_size = safe_usize_to_u64(link_name_bytes.len())?;
-                Ok::<_, anyhow::Error>((link_name_bytes, link_name_size))
-            })
-            .transpose()?
-            .unwrap_or_default();
+        let (link_name_bytes, link_name_size) = match header.type_flag {
+            TypeFlag::CharDevice | TypeFlag::BlockDevice => {
+                let mut device_bytes = u32_to_bytes(header.dev_major).to_vec();
+                device_bytes.extend_from_slice(&u32_to_bytes(header.dev_minor));
+                (device_bytes, DEVICE_NUMBERS_SIZE)
+            }
+            _ => header
+                .link_name
+                .map(|link_name| {
+                    let link_name_bytes = path_to_bytes(link_name)?;
+                    let link_name_size = safe_usize_to_u64(link_name_bytes.len())?;
+                    Ok::<_, anyhow::Error>((link_name_bytes, link_name_size))
+                })
+                .transpose()?
+                .unwrap_or_default(),
+        };
         log::trace!(
             ">>>> Link name: {:?}; link name size: {:?}",
             link_name_bytes,
             link_name_size
         );
 
+        let user_name = owner_name_to_bytes(header.user_name);
+        let group_name = owner_name_to_bytes(header.group_name);
+
         Ok(Self {
             file_name: file_name_bytes,
             file_name_size: u64_to_bytes(file_name_size),
@@ -102,6 +157,11 @@ impl FileHeaderLL {
             link_name: link_name_bytes,
             link_name_size: u64_to_bytes(link_name_size),
             checksum: [0u8; 4],
+            has_owner_names: true,
+            user_name_size: user_name.len() as u8,
+            user_name,
+            group_name_size: group_name.len() as u8,
+            group_name,
         })
     }
 
@@ -121,11 +181,13 @@ impl FileHeaderLL {
     fn serialize(self) -> anyhow::Result<HeaderBlock> {
         let mut buffer = [0u8; 64];
         let bytes = self.to_bytes()?;
-        buffer[..57].copy_from_slice(&bytes);
+        buffer[..bytes.len()].copy_from_slice(&bytes);
         Ok(HeaderBlock {
             header: buffer,
             file_name: self.file_name,
             link_name: self.link_name,
+            user_name: self.user_name,
+            group_name: self.group_name,
         })
     }
 
@@ -141,10 +203,13 @@ impl FileHeaderLL {
         buffer.write_all(&[self.type_flag])?;
         buffer.write_all(&self.link_name_size)?;
         buffer.write_all(&self.checksum)?;
+        if self.has_owner_names {
+            buffer.write_all(&[self.user_name_size, self.group_name_size])?;
+        }
         Ok(buffer)
     }
 
-    fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+    fn from_bytes(bytes: &[u8], has_owner_names: bool) -> anyhow::Result<Self> {
         if bytes.len() != 64 {
             bail!("Invalid header block length: {}; expected 64.", bytes.len());
         }
@@ -158,6 +223,11 @@ impl FileHeaderLL {
         let type_flag = bytes[44];
         let link_name_size = bytes[45..53].try_into().unwrap();
         let checksum = bytes[53..57].try_into().unwrap();
+        let (user_name_size, group_name_size) = if has_owner_names {
+            (bytes[57], bytes[58])
+        } else {
+            (0, 0)
+        };
 
         Ok(Self {
             file_name: Vec::new(),
@@ -172,10 +242,27 @@ impl FileHeaderLL {
             link_name: Vec::new(),
             link_name_size,
             checksum,
+            has_owner_names,
+            user_name: Vec::new(),
+            user_name_size,
+            group_name: Vec::new(),
+            group_name_size,
         })
     }
 }
 
+/// Bytes of a user or group name to store; empty if the name is unknown or too long to store.
+fn owner_name_to_bytes(name: Option<String>) -> Vec<u8> {
+    match name {
+        Some(name) if name.len() <= u8::MAX as usize => name.into_bytes(),
+        Some(name) => {
+            log::debug!("Not storing the name {}, as it is too long", name);
+            Vec::new()
+        }
+        None => Vec::new(),
+    }
+}
+
 fn safe_usize_to_u64(value: usize) -> anyhow::Result<u64> {
     if value > u64::MAX as usize {
         Err(anyhow!("Value exceeds u64 maximum limit"))
@@ -193,36 +280,60 @@ pub struct FileHeader {
     pub(crate) file_mode: u32,
     pub(crate) user_id: u32,
     pub(crate) group_id: u32,
+    pub(crate) user_name: Option<String>,
+    pub(crate) group_name: Option<String>,
     pub(crate) created_at: i64,
     pub(crate) last_modified: i64,
     pub(crate) type_flag: TypeFlag,
     pub(crate) link_name: Option<PathBuf>,
+    pub(crate) dev_major: u32,
+    pub(crate) dev_minor: u32,
 }
 
 impl FileHeader {
     pub(crate) fn new(
         file_name: &Path,
         metadata: fs::Metadata,
+        file_type: FileType,
         link_name: Option<PathBuf>,
+        owner_names: &OwnerNames,
     ) -> anyhow::Result<Self> {
         let file_name = file_name.to_owned();
         let file_mode = metadata.mode();
         let user_id = metadata.uid();
         let group_id = metadata.gid();
-        let file_size = metadata.len();
+        let user_name = owner_names.user_name(user_id);
+        let group_name = owner_names.group_name(group_id);
+        // only regular files have data in the archive
+        let file_size = if file_type == FileType::Regular {
+            metadata.len()
+        } else {
+            0
+        };
         let created_at = metadata.ctime();
         let last_modified = metadata.mtime();
-        let type_flag = TypeFlag::new(metadata);
+        let type_flag = TypeFlag::from(file_type);
+        let (dev_major, dev_minor) = match file_type {
+            FileType::CharDevice | FileType::BlockDevice => {
+                let rdev = metadata.rdev();
+                (major(rdev) as u32, minor(rdev) as u32)
+            }
+            _ => (0, 0),
+        };
         Ok(Self {
             file_name,
             file_size,
             file_mode,
             user_id,
             group_id,
+            user_name,
+            group_name,
             created_at,
             last_modified,
             type_flag,
             link_name,
+            dev_major,
+            dev_minor,
         })
     }
 
@@ -234,9 +345,15 @@ impl FileHeader {
         log::debug!(">> mode: {}", self.file_mode);
         log::debug!(">> uid: {}", self.user_id);
         log::debug!(">> gid: {}", self.group_id);
+        log::debug!(">> user: {}", self.user_name.as_deref().unwrap_or("<N/A>"));
+        log::debug!(
+            ">> group: {}",
+            self.group_name.as_deref().unwrap_or("<N/A>")
+        );
         log::debug!(">> ctime: {}", self.created_at);
         log::debug!(">> mtime: {}", self.last_modified);
         log::debug!(">> typeflag: {:?}", self.type_flag);
+        log::debug!(">> device: {},{}", self.dev_major, self.dev_minor);
         log::debug!(
             ">> link name: {}",
             self.link_name
@@ -257,8 +374,14 @@ impl FileHeader {
         header_ll.serialize()
     }
 
-    pub(crate) fn deserialize(bytes: &[u8]) -> anyhow::Result<(Self, u64, u64)> {
-        let mut ll = FileHeaderLL::from_bytes(bytes)?;
+    /// Deserialize a header block, of an archive which has user and group names in its headers
+    /// or not. Returns the header, along with the sizes of the names following the block; which
+    /// are to be read into the header.
+    pub(crate) fn deserialize(
+        bytes: &[u8],
+        has_owner_names: bool,
+    ) -> anyhow::Result<(Self, NameSizes)> {
+        let mut ll = FileHeaderLL::from_bytes(bytes, has_owner_names)?;
         log::trace!("Low-level file header : {:?}", ll);
         // get the stored checksum
         let stored_checksum = bytes_to_u32(ll.checksum);
@@ -282,43 +405,75 @@ impl FileHeader {
             file_mode: bytes_to_u32(ll.file_mode),
             user_id: bytes_to_u32(ll.user_id),
             group_id: bytes_to_u32(ll.group_id),
+            user_name: None,
+            group_name: None,
             created_at: bytes_to_i64(ll.created_at),
             last_modified: bytes_to_i64(ll.last_modified),
             type_flag,
             link_name: None,
+            dev_major: 0,
+            dev_minor: 0,
         };
-        Ok((
-            header,
-            bytes_to_u64(ll.file_name_size),
-            bytes_to_u64(ll.link_name_size),
-        ))
+        let sizes = NameSizes {
+            file_name: bytes_to_u64(ll.file_name_size),
+            link_name: bytes_to_u64(ll.link_name_size),
+            user_name: ll.user_name_size,
+            group_name: ll.group_name_size,
+        };
+        Ok((header, sizes))
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 #[repr(u8)]
 pub enum TypeFlag {
     Regular = 0,
     HardLink = 1,
     SymLink = 2,
+    CharDevice = 3,
+    BlockDevice = 4,
+    Directory = 5,
+    Fifo = 6,
 }
 
-impl TypeFlag {
-    fn new(metadata: fs::Metadata) -> Self {
-        if metadata.is_symlink() {
-            TypeFlag::SymLink
-        } else if metadata.is_dir() {
-            TypeFlag::HardLink
-        } else {
-            TypeFlag::Regular
+impl From<FileType> for TypeFlag {
+    fn from(file_type: FileType) -> Self {
+        match file_type {
+            FileType::Regular => TypeFlag::Regular,
+            FileType::HardLink => TypeFlag::HardLink,
+            FileType::SymLink => TypeFlag::SymLink,
+            FileType::CharDevice => TypeFlag::CharDevice,
+            FileType::BlockDevice => TypeFlag::BlockDevice,
+            FileType::Directory => TypeFlag::Directory,
+            FileType::Fifo => TypeFlag::Fifo,
+        }
+    }
+}
+
+impl From<TypeFlag> for FileType {
+    fn from(type_flag: TypeFlag) -> Self {
+        match type_flag {
+            TypeFlag::Regular => FileType::Regular,
+            TypeFlag::HardLink => FileType::HardLink,
+            TypeFlag::SymLink => FileType::SymLink,
+            TypeFlag::CharDevice => FileType::CharDevice,
+            TypeFlag::BlockDevice => FileType::BlockDevice,
+            TypeFlag::Directory => FileType::Directory,
+            TypeFlag::Fifo => FileType::Fifo,
         }
     }
+}
 
-    fn from_byte(byte: u8) -> anyhow::Result<Self> {
+impl TypeFlag {
+    pub(crate) fn from_byte(byte: u8) -> anyhow::Result<Self> {
         match byte {
             b'0' | 0 => Ok(TypeFlag::Regular),
             b'1' | 1 => Ok(TypeFlag::HardLink),
             b'2' | 2 => Ok(TypeFlag::SymLink),
+            b'3' | 3 => Ok(TypeFlag::CharDevice),
+            b'4' | 4 => Ok(TypeFlag::BlockDevice),
+            b'5' | 5 => Ok(TypeFlag::Directory),
+            b'6' | 6 => Ok(TypeFlag::Fifo),
             _ => Err(anyhow!("Invalid typeflag byte: {:?}", byte)),
         }
     }
@@ -346,9 +501,11 @@ mod tests {
             let tf1 = TypeFlag::Regular;
             let tf2 = TypeFlag::HardLink;
             let tf3 = TypeFlag::SymLink;
+            let tf4 = TypeFlag::Directory;
             file.write_all(&[tf1 as u8])?;
             file.write_all(&[tf2 as u8])?;
             file.write_all(&[tf3 as u8])?;
+            file.write_all(&[tf4 as u8])?;
             file.flush()?;
             file.sync_all()?;
             Ok(())
@@ -364,6 +521,9 @@ mod tests {
 
         let tf3 = read_typeflag(&mut file)?;
         assert_eq!(tf3, TypeFlag::SymLink);
+
+        let tf4 = read_typeflag(&mut file)?;
+        assert_eq!(tf4, TypeFlag::Directory);
         Ok(())
     }
 
@@ -386,10 +546,14 @@ mod tests {
             file_mode,
             user_id,
             group_id,
+            user_name: Some("user".to_string()),
+            group_name: None,
             created_at,
             last_modified,
             type_flag,
             link_name,
+            dev_major: 0,
+            dev_minor: 0,
         };
 
         // Serialize the header
@@ -399,7 +563,8 @@ mod tests {
             .with_context(|| "Failed to serialize header")?;
 
         // Deserialize the header
-        let (deserialized_header, _, _) = FileHeader::deserialize(&serialized_header.header)?;
+        let (deserialized_header, sizes) =
+            FileHeader::deserialize(&serialized_header.header, true)?;
         // Assert that the original and deserialized headers are equal
         assert_eq!(header.file_size, deserialized_header.file_size);
         assert_eq!(header.file_mode, deserialized_header.file_mode);
@@ -408,6 +573,14 @@ mod tests {
         assert_eq!(header.created_at, deserialized_header.created_at);
         assert_eq!(header.last_modified, deserialized_header.last_modified);
         assert_eq!(header.type_flag, deserialized_header.type_flag);
+        assert_eq!(sizes.user_name, 4);
+        assert_eq!(sizes.group_name, 0);
+        assert_eq!(serialized_header.user_name, b"user");
+
+        // headers of V1 archives have no name sizes, which are part of the checksum
+        let mut old_header = serialized_header.header;
+        old_header[57..59].fill(0);
+        assert!(FileHeader::deserialize(&old_header, false).is_err());
         Ok(())
     }
 }
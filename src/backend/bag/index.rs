@@ -0,0 +1,302 @@
+//! The index of a BAG archive, for random access to its files.
+//!
+//! The index is written after the EOA marker, followed by a trailer of a fixed size at the very end
+//! of the archive. So a reader which can seek, finds the index by reading the trailer; while
+//! readers which don't know about the index, stop at the EOA marker.
+//!
+//! --------------
+//! <EOA-MARKER>
+//! <entry-count> - 8 bytes
+//! <entry1>
+//! <entry2>
+//! ...
+//! <trailer> - 32 bytes
+//! --------------
+//!
+//! Layout of an index entry -
+//!
+//! --------------------+---------------+--------+----------------------------------------------------+
+//! | Field             |  Size(bytes)  | Offset |  Remarks                                           |
+//! +-------------------+---------------+--------+----------------------------------------------------+
+//! | <header-offset>   |  8            |  0     |  Offset of the file header in the archive          |
+//! | <data-offset>     |  8            |  8     |  Offset of the file data in the archive            |
+//! | <file-size>       |  8            |  16    |  Size of the file                                  |
+//! | <type-flag>       |  1            |  24    |  Flag indicating file type                         |
+//! | <file-name-size>  |  4            |  25    |  Size of the file name                             |
+//! | <file-name>       |  n            |  29    |  Path of the file in the archive                   |
+//! +-------------------+---------------+--------+----------------------------------------------------+
+//!
+//! Layout of the trailer -
+//!
+//! --------------------+---------------+--------+----------------------------------------------------+
+//! | Field             |  Size(bytes)  | Offset |  Remarks                                           |
+//! +-------------------+---------------+--------+----------------------------------------------------+
+//! | <index-offset>    |  8            |  0     |  Offset of the index (its entry count)             |
+//! | <index-size>      |  8            |  8     |  Size of the index, without the trailer            |
+//! | <checksum>        |  4            |  16    |  CRC32C of the index                               |
+//! | <reserved>        |  4            |  20    |  Always 0                                          |
+//! | <magic>           |  8            |  24    |  Always "BAG IDX."                                 |
+//! +-------------------+---------------+--------+----------------------------------------------------+
+//!
+//! All offsets are from the start of the archive, i.e. its global header.
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+use anyhow::{bail, Context};
+
+use super::byteorder::{
+    bytes_to_path, bytes_to_u32, bytes_to_u64, path_to_bytes, u32_to_bytes, u64_to_bytes,
+};
+use super::digest::{BagDigest, DigestReader, Hasher};
+use super::header::TypeFlag;
+use crate::backend::{FileType, IndexEntry};
+
+const MAGIC: &[u8; 8] = b"BAG IDX.";
+const TRAILER_SIZE: u64 = 32;
+/// Maximum size of an index read from an archive, to not allocate absurd amounts of memory for a
+/// corrupted trailer.
+const MAX_INDEX_SIZE: u64 = 1 << 32;
+
+/// Records the index entries while an archive is being packed, keeping track of the offset in
+/// the archive as it is written.
+#[derive(Debug, Default)]
+pub(crate) struct IndexBuilder {
+    entries: Vec<IndexEntry>,
+    offset: u64,
+}
+
+impl IndexBuilder {
+    /// Start a new archive; the global header of `size` bytes is already written.
+    pub(crate) fn reset(&mut self, size: u64) {
+        self.entries.clear();
+        self.offset = size;
+    }
+
+    /// Record `size` bytes written to the archive.
+    pub(crate) fn advance(&mut self, size: u64) {
+        self.offset += size;
+    }
+
+    /// Record a file whose header of `header_size` bytes (including the file name and link name)
+    /// is written next.
+    pub(crate) fn add(
+        &mut self,
+        file_name: PathBuf,
+        file_type: FileType,
+        file_size: u64,
+        header_size: u64,
+    ) {
+        self.entries.push(IndexEntry {
+            file_name,
+            file_type,
+            header_offset: self.offset,
+            data_offset: self.offset + header_size,
+            file_size,
+        });
+        self.offset += header_size;
+    }
+
+    /// Write the index and its trailer; this is the end of the archive.
+    pub(crate) fn write<W: Write>(&self, writer: &mut W) -> anyhow::Result<()> {
+        let mut index = u64_to_bytes(self.entries.len() as u64).to_vec();
+        for entry in &self.entries {
+            let file_name = path_to_bytes(entry.file_name.clone())?;
+            index.extend_from_slice(&u64_to_bytes(entry.header_offset));
+            index.extend_from_slice(&u64_to_bytes(entry.data_offset));
+            index.extend_from_slice(&u64_to_bytes(entry.file_size));
+            index.push(TypeFlag::from(entry.file_type) as u8);
+            index.extend_from_slice(&u32_to_bytes(u32::try_from(file_name.len())?));
+            index.extend_from_slice(&file_name);
+        }
+        let mut hasher = Hasher::new(BagDigest::Crc32c);
+        hasher.update(&index);
+
+        let mut trailer = Vec::with_capacity(TRAILER_SIZE as usize);
+        trailer.extend_from_slice(&u64_to_bytes(self.offset));
+        trailer.extend_from_slice(&u64_to_bytes(index.len() as u64));
+        trailer.extend_from_slice(&hasher.finalize());
+        trailer.extend_from_slice(&[0u8; 4]);
+        trailer.extend_from_slice(MAGIC);
+
+        writer.write_all(&index)?;
+        writer.write_all(&trailer)?;
+        Ok(())
+    }
+}
+
+/// Read the index of an archive starting at offset `start` of the reader, using its trailer at the
+/// end of the reader.
+pub(crate) fn read_index<R: Read + Seek>(
+    reader: &mut R,
+    start: u64,
+) -> anyhow::Result<Vec<IndexEntry>> {
+    let end = reader.seek(SeekFrom::End(0))?;
+    if end < start + TRAILER_SIZE {
+        bail!("The archive is too short to have an index");
+    }
+    reader.seek(SeekFrom::Start(end - TRAILER_SIZE))?;
+    let trailer = read_trailer(reader)?;
+    if trailer.offset + trailer.size + TRAILER_SIZE != end - start {
+        bail!("The index trailer is not at the end of the archive");
+    }
+    reader.seek(SeekFrom::Start(start + trailer.offset))?;
+    let mut index = DigestReader::new(reader, BagDigest::Crc32c);
+    let entries = read_entries(&mut index)?;
+    if index.finalize() != trailer.checksum {
+        bail!("Invalid checksum of the index");
+    }
+    Ok(entries)
+}
+
+/// Read the index and its trailer from a reader which can't seek, right after the EOA marker;
+/// checking that it is intact. This reads the archive till its end.
+pub(crate) fn skip_index<R: Read>(reader: &mut R) -> anyhow::Result<()> {
+    let mut index = DigestReader::new(reader, BagDigest::Crc32c);
+    let mut counter = CountingReader::new(&mut index);
+    read_entries(&mut counter)?;
+    let size = counter.count;
+    let checksum = index.finalize();
+    let trailer = read_trailer(reader)?;
+    if trailer.size != size || checksum != trailer.checksum {
+        bail!("The index of the archive is corrupted");
+    }
+    Ok(())
+}
+
+struct Trailer {
+    offset: u64,
+    size: u64,
+    checksum: [u8; 4],
+}
+
+fn read_trailer<R: Read>(reader: &mut R) -> anyhow::Result<Trailer> {
+    let mut buffer = [0u8; TRAILER_SIZE as usize];
+    reader
+        .read_exact(&mut buffer)
+        .context("Reading the index trailer")?;
+    if &buffer[24..] != MAGIC {
+        bail!("Invalid index trailer");
+    }
+    let trailer = Trailer {
+        offset: bytes_to_u64(buffer[..8].try_into().unwrap()),
+        size: bytes_to_u64(buffer[8..16].try_into().unwrap()),
+        checksum: buffer[16..20].try_into().unwrap(),
+    };
+    if trailer.size > MAX_INDEX_SIZE {
+        bail!("Invalid index size: {}", trailer.size);
+    }
+    Ok(trailer)
+}
+
+fn read_entries<R: Read>(reader: &mut R) -> anyhow::Result<Vec<IndexEntry>> {
+    let count = bytes_to_u64(read_array(reader)?);
+    let mut entries = Vec::new();
+    for _ in 0..count {
+        let header_offset = bytes_to_u64(read_array(reader)?);
+        let data_offset = bytes_to_u64(read_array(reader)?);
+        let file_size = bytes_to_u64(read_array(reader)?);
+        let [type_flag] = read_array(reader)?;
+        let file_type = TypeFlag::from_byte(type_flag)?.into();
+        let name_size = bytes_to_u32(read_array(reader)?);
+        let mut file_name = vec![0u8; name_size as usize];
+        reader.read_exact(&mut file_name)?;
+        entries.push(IndexEntry {
+            file_name: bytes_to_path(&file_name)?,
+            file_type,
+            header_offset,
+            data_offset,
+            file_size,
+        });
+    }
+    Ok(entries)
+}
+
+fn read_array<R: Read, const N: usize>(reader: &mut R) -> io::Result<[u8; N]> {
+    let mut buffer = [0u8; N];
+    reader.read_exact(&mut buffer)?;
+    Ok(buffer)
+}
+
+/// A writer which counts the bytes written through it.
+pub(crate) struct CountingWriter<'a, W: Write> {
+    writer: &'a mut W,
+    pub(crate) count: u64,
+}
+
+impl<'a, W: Write> CountingWriter<'a, W> {
+    pub(crate) fn new(writer: &'a mut W) -> Self {
+        Self { writer, count: 0 }
+    }
+}
+
+impl<W: Write> Write for CountingWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.writer.write(buf)?;
+        self.count += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// A reader which counts the bytes read through it.
+struct CountingReader<'a, R: Read> {
+    reader: &'a mut R,
+    count: u64,
+}
+
+impl<'a, R: Read> CountingReader<'a, R> {
+    fn new(reader: &'a mut R) -> Self {
+        Self { reader, count: 0 }
+    }
+}
+
+impl<R: Read> Read for CountingReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.reader.read(buf)?;
+        self.count += read as u64;
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn test_index_roundtrip() -> anyhow::Result<()> {
+        let mut builder = IndexBuilder::default();
+        builder.reset(64);
+        builder.add(PathBuf::from("dir"), FileType::Directory, 0, 67);
+        builder.add(PathBuf::from("dir/file"), FileType::Regular, 5, 72);
+        builder.advance(5 + 4);
+
+        // some archive data, followed by the index
+        let mut archive = vec![1u8; 16];
+        let mut data = vec![7u8; builder.offset as usize];
+        builder.write(&mut data)?;
+        archive.extend_from_slice(&data);
+
+        let entries = read_index(&mut Cursor::new(&archive), 16)?;
+        assert_eq!(entries, builder.entries);
+        assert_eq!(entries[1].header_offset, 64 + 67);
+        assert_eq!(entries[1].data_offset, 64 + 67 + 72);
+
+        let index = &data[64 + 67 + 72 + 9..];
+        skip_index(&mut &index[..])?;
+
+        // a corrupted index
+        let mut corrupted = archive.clone();
+        corrupted[16 + 64 + 67 + 72 + 9 + 8] ^= 1;
+        assert!(read_index(&mut Cursor::new(&corrupted), 16).is_err());
+        assert!(skip_index(&mut &corrupted[16 + 64 + 67 + 72 + 9..]).is_err());
+        // a missing index
+        assert!(read_index(&mut Cursor::new(&archive[..archive.len() - 1]), 16).is_err());
+        Ok(())
+    }
+}
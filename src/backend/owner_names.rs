@@ -0,0 +1,65 @@
+//! Names of the users and groups owning the files being packed.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use nix::unistd::{Gid, Group, Uid, User};
+
+/// Looks up the names of the owners and groups of the files being packed. The names are cached by
+/// ID, as most files share a few owners. Packing only borrows the backend, so the caches are kept
+/// in cells.
+#[derive(Debug, Default)]
+pub(crate) struct OwnerNames {
+    users: RefCell<HashMap<u32, Option<String>>>,
+    groups: RefCell<HashMap<u32, Option<String>>>,
+}
+
+impl OwnerNames {
+    /// Name of the user with the given ID. `None` if the user has no name on this system; like the
+    /// users of a container, which aren't in the user database of the host.
+    pub(crate) fn user_name(&self, user_id: u32) -> Option<String> {
+        cached(&self.users, user_id, |id| {
+            User::from_uid(Uid::from_raw(id)).map(|user| user.map(|user| user.name))
+        })
+    }
+
+    /// Name of the group with the given ID. `None` if the group has no name on this system.
+    pub(crate) fn group_name(&self, group_id: u32) -> Option<String> {
+        cached(&self.groups, group_id, |id| {
+            Group::from_gid(Gid::from_raw(id)).map(|group| group.map(|group| group.name))
+        })
+    }
+}
+
+/// Get the name of the ID from the cache, or look it up. A failed lookup is the same as no name.
+fn cached(
+    cache: &RefCell<HashMap<u32, Option<String>>>,
+    id: u32,
+    lookup: fn(u32) -> nix::Result<Option<String>>,
+) -> Option<String> {
+    cache
+        .borrow_mut()
+        .entry(id)
+        .or_insert_with(|| {
+            lookup(id).unwrap_or_else(|err| {
+                log::debug!("Unable to look up the name of ID {}: {}", id, err);
+                None
+            })
+        })
+        .clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_owner_names() {
+        let names = OwnerNames::default();
+        assert_eq!(names.user_name(0).as_deref(), Some("root"));
+        assert_eq!(names.group_name(0).as_deref(), Some("root"));
+        // IDs without a name are cached as well
+        assert_eq!(names.user_name(4_000_000_000), None);
+        assert_eq!(names.users.borrow().len(), 2);
+    }
+}
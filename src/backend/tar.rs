@@ -3,25 +3,64 @@
 //! archive.
 
 mod byteorder;
+mod compression;
+mod extension;
 mod header;
 
 use std::{
-    fs::File,
-    io::{BufReader, BufWriter, Write},
+    io::{ErrorKind, Read, Seek, SeekFrom, Write},
     path::PathBuf,
 };
 
-use super::{AsHeader, PackerBackend};
-use anyhow;
-use header::Header;
+use super::owner_names::OwnerNames;
+use super::{AsHeader, FileType, PackerBackend};
+use crate::archive::file::read_file_slice_chunked;
+use anyhow::{self, Context};
+use extension::Extensions;
+use header::{Header, TypeFlag, BLOCK_SIZE};
+
+pub use compression::{TarCompression, TarEncoder};
 
 const EOF_MARKER: [u8; 1024] = [0; 1024];
+const PADDING: [u8; BLOCK_SIZE] = [0; BLOCK_SIZE];
+
+/// The packer backend for the TAR archive format.
+#[derive(Default)]
+pub struct TarArchive {
+    /// Format of the headers to write.
+    format: TarFormat,
+    /// Attributes of pax global headers read so far, which apply to all the following entries.
+    globals: Extensions,
+    /// Names of the owners of the files being packed.
+    owner_names: OwnerNames,
+}
 
-pub struct TarArchive;
+/// The flavour of tar headers to write. Archives of all flavours can be read.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum TarFormat {
+    /// Plain POSIX ustar. Packing fails if a file can't be stored in a ustar header, e.g. if its
+    /// path is too long.
+    Ustar,
+    /// GNU tar format. Long paths and link targets are stored in GNU longname/longlink records, and
+    /// large numbers in base-256.
+    Gnu,
+    /// POSIX pax format. Anything that doesn't fit in a ustar header (long paths and link targets,
+    /// large sizes, sub-second times, non-ASCII names etc.) is stored in a pax extended header.
+    #[default]
+    Pax,
+}
 
 impl TarArchive {
     pub fn new() -> Self {
-        Self
+        Self::default()
+    }
+
+    /// Create a tar backend, which writes headers in the given format.
+    pub fn with_format(format: TarFormat) -> Self {
+        Self {
+            format,
+            ..Self::default()
+        }
     }
 }
 
@@ -29,13 +68,27 @@ impl AsHeader for Header {
     fn get_metadata(&self) -> super::FileMetadata {
         super::FileMetadata {
             file_name: self.file_name.clone(),
+            file_type: match self.type_flag {
+                TypeFlag::Regular => FileType::Regular,
+                TypeFlag::HardLink => FileType::HardLink,
+                TypeFlag::SymLink => FileType::SymLink,
+                TypeFlag::CharDevice => FileType::CharDevice,
+                TypeFlag::BlockDevice => FileType::BlockDevice,
+                TypeFlag::Directory => FileType::Directory,
+                TypeFlag::Fifo => FileType::Fifo,
+            },
             file_size: self.file_size,
             file_mode: self.file_mode,
             user_id: self.user_id,
             group_id: self.group_id,
+            user_name: Some(self.user_name.clone()).filter(|name| !name.is_empty()),
+            group_name: Some(self.group_name.clone()).filter(|name| !name.is_empty()),
             created_at: 0,
             last_modified: self.last_modified,
-            link_name: None,
+            last_modified_nanos: self.last_modified_nanos,
+            link_name: self.link_name.clone(),
+            dev_major: self.dev_major,
+            dev_minor: self.dev_minor,
         }
     }
 }
@@ -44,49 +97,117 @@ impl PackerBackend for TarArchive {
     type Header = Header;
     type EOAMarker = [u8; 1024];
 
-    fn write_prologue(&self, _writer: &mut BufWriter<File>) -> anyhow::Result<()> {
+    fn write_prologue<W: Write>(&self, _writer: &mut W) -> anyhow::Result<()> {
         Ok(())
     }
 
-    fn pack_header(
+    fn pack_header<W: Write>(
         &self,
-        writer: &mut BufWriter<File>,
+        writer: &mut W,
         file: &super::FilePath,
         metadata: std::fs::Metadata,
-        _link_name: Option<PathBuf>,
+        file_type: FileType,
+        link_name: Option<PathBuf>,
     ) -> anyhow::Result<u64> {
-        let header = Header::new(&file.archive_path, metadata)?;
+        let header = Header::new(
+            &file.archive_path,
+            metadata,
+            file_type,
+            link_name,
+            &self.owner_names,
+        )?;
         let file_size = header.file_size;
         // log::debug!("Created header: {:?}", header);
         // log::trace!("Serializing header data..");
-        let header_data = header.serialize()?;
+        let header_data = header.serialize(self.format)?;
         // log::trace!("Writing header data..");
         writer.write_all(&header_data)?;
         Ok(file_size)
     }
 
-    fn write_epilogue(&self, writer: &mut BufWriter<File>) -> anyhow::Result<()> {
+    fn pack_data<W: Write, D: Read>(
+        &self,
+        writer: &mut W,
+        data: &mut D,
+        file_size: u64,
+    ) -> anyhow::Result<()> {
+        read_file_slice_chunked(data, file_size, |buffer| {
+            writer.write_all(buffer)?;
+            Ok(())
+        })?;
+        // file data is padded to a multiple of the block size
+        writer.write_all(&PADDING[..padding_size(file_size)])?;
+        Ok(())
+    }
+
+    fn write_epilogue<W: Write>(&self, writer: &mut W) -> anyhow::Result<()> {
         writer.write_all(&EOF_MARKER)?;
         Ok(())
     }
 
-    fn read_prologue(&self, _reader: &mut BufReader<File>) -> anyhow::Result<()> {
+    fn read_prologue<R: Read>(&mut self, _reader: &mut R) -> anyhow::Result<()> {
+        // global headers apply only to the archive they are in, not to the next one read
+        self.globals = Extensions::default();
         Ok(())
     }
 
-    fn unpack_header(
-        &self,
-        _reader: &mut BufReader<File>,
+    fn unpack_header<R: Read>(
+        &mut self,
+        reader: &mut R,
         header_buffer: &[u8],
     ) -> anyhow::Result<Self::Header> {
-        Header::deserialize(header_buffer)
+        Header::read(reader, header_buffer, &mut self.globals)
     }
 
-    fn is_eoa(&self, _reader: &mut BufReader<File>, header_buffer: &[u8]) -> bool {
-        header_buffer == [0u8; 512]
+    fn unpack_data<R: Read, O: Write>(
+        &self,
+        reader: &mut R,
+        header: &Header,
+        out: &mut O,
+    ) -> anyhow::Result<()> {
+        read_file_slice_chunked(reader, header.file_size, |buffer| {
+            out.write_all(buffer)?;
+            Ok(())
+        })?;
+        let mut padding = [0u8; BLOCK_SIZE];
+        reader
+            .read_exact(&mut padding[..padding_size(header.file_size)])
+            .with_context(|| "Reading padding of file data")?;
+        Ok(())
+    }
+
+    fn seek_data<R: Read + Seek>(&self, reader: &mut R, header: &Header) -> anyhow::Result<()> {
+        let data_size = header.file_size + padding_size(header.file_size) as u64;
+        reader.seek(SeekFrom::Current(i64::try_from(data_size)?))?;
+        Ok(())
+    }
+
+    fn is_eoa<R: Read>(&self, _reader: &mut R, header_buffer: &[u8]) -> bool {
+        header_buffer == [0u8; BLOCK_SIZE]
+    }
+
+    fn read_epilogue<R: Read>(&self, reader: &mut R) -> anyhow::Result<()> {
+        // the EOA marker is two 512 byte blocks of zeros; the first one is already read as a
+        // header block. Some tar implementations write only one block, so a missing second block
+        // is not an error.
+        let mut marker_buffer = [0u8; BLOCK_SIZE];
+        match reader.read_exact(&mut marker_buffer) {
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => Ok(()),
+            result => Ok(result?),
+        }
     }
 
     fn header_block_size(&self) -> usize {
-        512
+        BLOCK_SIZE
+    }
+}
+
+/// Number of padding bytes required after file data of the given size, to fill up the last block.
+fn padding_size(file_size: u64) -> usize {
+    let remainder = (file_size % BLOCK_SIZE as u64) as usize;
+    if remainder == 0 {
+        0
+    } else {
+        BLOCK_SIZE - remainder
     }
 }
@@ -1,76 +1,114 @@
-use std::path::PathBuf;
-/// This module contains functions to convert Rust values (mostly primitive values) into byte arrays.
-/// This is used for binary serialization/deserialization.
-use std::str;
+//! This module contains functions to convert Rust values into the byte arrays of a ustar header, and
+//! back. Numeric values are stored as NUL terminated octal ASCII strings, and strings (paths, user
+//! names etc.) are stored as NUL padded byte arrays.
 
-// convert a u32 value to a [u8; 8] padded with zeros if required.
-pub fn u32_to_bytes(value: u32) -> [u8; 8] {
-    let bytes = value.to_le_bytes(); // Convert the u32 to a 4-byte array (little-endian)
-    let mut padded = [0u8; 8]; // Create an 8-byte array initialized with zeros
-    padded[..4].copy_from_slice(&bytes); // Copy the 4 bytes into the first half
-    padded
-}
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
 
-pub fn bytes_to_u32(input: [u8; 8]) -> u32 {
-    let mut buffer = [0u8; 4]; // Create an 4-byte buffer
-    buffer.copy_from_slice(&input[..4]); // Take the first 8 bytes of the input
-    u32::from_le_bytes(buffer) // Convert to u32 in little-endian order
-}
+use anyhow::{anyhow, bail};
 
-// convert a u64 value to a [u8; 12] padded with zeros if required.
-pub fn u64_to_bytes(value: u64) -> [u8; 12] {
-    let mut padded = [0u8; 12]; // Create an 12-byte array initialized with zeros
-    let bytes = value.to_le_bytes(); // Convert the u64 to a 8-byte array (little-endian)
-    padded[..8].copy_from_slice(&bytes); // Copy the 8 bytes into the first half
-    padded
+/// Convert an unsigned value to a NUL terminated octal ASCII string, left padded with zeros, of N
+/// bytes. Fails if the value doesn't fit in N - 1 octal digits.
+pub fn u64_to_octal<const N: usize>(value: u64) -> anyhow::Result<[u8; N]> {
+    let digits = format!("{:0width$o}", value, width = N - 1);
+    if digits.len() > N - 1 {
+        bail!(
+            "Value {} is too large for a {} byte numeric field",
+            value,
+            N
+        );
+    }
+    let mut buffer = [0u8; N];
+    buffer[..N - 1].copy_from_slice(digits.as_bytes());
+    Ok(buffer)
 }
 
-pub fn bytes_to_u64(input: [u8; 12]) -> u64 {
-    let mut buffer = [0u8; 8]; // Create an 8-byte buffer
-    buffer.copy_from_slice(&input[..8]); // Take the first 8 bytes of the input
-    u64::from_le_bytes(buffer) // Convert to u64 in little-endian order
+/// Convert a signed value to a base-256 numeric field of N bytes. This is an extension (used by GNU
+/// tar and others) for values which don't fit in octal. The high bit of the first byte is set for
+/// positive values; negative values are stored in two's complement, so their first byte is 0xff.
+pub fn i64_to_base256<const N: usize>(value: i64) -> [u8; N] {
+    let mut buffer = if value < 0 { [0xffu8; N] } else { [0u8; N] };
+    let bytes = value.to_be_bytes();
+    buffer[N - bytes.len()..].copy_from_slice(&bytes);
+    if value >= 0 {
+        buffer[0] |= 0x80;
+    }
+    buffer
 }
 
-// convert a i64 value to a [u8; 12] padded with zeros if required.
-pub fn i64_to_bytes(value: i64) -> [u8; 12] {
-    let mut padded = [0u8; 12]; // Create an 12-byte array initialized with zeros
-    let bytes = value.to_le_bytes(); // Convert the i64 to a 8-byte array (little-endian)
-    padded[..8].copy_from_slice(&bytes); // Copy the 8 bytes into the first half
-    padded
+/// Parse an unsigned numeric field of a header. See [`octal_to_i64`].
+pub fn octal_to_u64(field: &[u8]) -> anyhow::Result<u64> {
+    let value = octal_to_i64(field)?;
+    u64::try_from(value).map_err(|_| anyhow!("Unexpected negative numeric field: {}", value))
 }
 
-pub fn bytes_to_i64(input: [u8; 12]) -> i64 {
-    let mut buffer = [0u8; 8]; // Create an 8-byte buffer
-    buffer.copy_from_slice(&input[..8]); // Take the first 8 bytes of the input
-    i64::from_le_bytes(buffer) // Convert to i64 in little-endian order
+/// Parse a numeric field of a header. Numeric fields are octal ASCII strings, optionally padded
+/// with leading spaces and terminated by NUL or space. As an extension (used by GNU tar and
+/// others) a field can also be stored in base-256, which is signalled by the high bit of the first
+/// byte.
+pub fn octal_to_i64(field: &[u8]) -> anyhow::Result<i64> {
+    if field.first().is_some_and(|byte| byte & 0x80 != 0) {
+        return base256_to_i64(field);
+    }
+    let digits = field
+        .iter()
+        .skip_while(|&&byte| byte == b' ')
+        .take_while(|&&byte| byte != 0 && byte != b' ')
+        .copied()
+        .collect::<Vec<_>>();
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    let digits = std::str::from_utf8(&digits)?;
+    i64::from_str_radix(digits, 8)
+        .map_err(|err| anyhow!("Invalid octal numeric field {:?}: {}", digits, err))
 }
 
-// TODO: gets the filepath upto first 100 bytes
-pub fn path_to_bytes(path: PathBuf) -> [u8; 100] {
-    let mut buffer = [0u8; 100]; // Create a 100-byte array initialized with zeros
-    if let Some(path_str) = path.to_str() {
-        let path_bytes = path_str.as_bytes();
-        // Copy the bytes, truncating if the path is too long
-        let len = path_bytes.len().min(100);
-        buffer[..len].copy_from_slice(&path_bytes[..len]);
+fn base256_to_i64(field: &[u8]) -> anyhow::Result<i64> {
+    let negative = field[0] == 0xff;
+    let mut value: i64 = if negative {
+        -1
+    } else {
+        (field[0] & 0x7f) as i64
+    };
+    for &byte in &field[1..] {
+        value = value
+            .checked_mul(256)
+            .and_then(|v| v.checked_add(byte as i64))
+            .ok_or_else(|| anyhow!("Base-256 numeric field is too large"))?;
     }
-    buffer
+    Ok(value)
 }
 
-pub fn bytes_to_path(array: &[u8; 100]) -> PathBuf {
-    // Find the first null terminator (0u8) to handle null-padded strings
-    let valid_length = array.iter().position(|&byte| byte == 0).unwrap_or(100);
+/// Copy a byte string into a NUL padded field of N bytes. The string does not need a terminating
+/// NUL if it fills the whole field. Fails if the string is too long.
+pub fn str_to_bytes<const N: usize>(value: &[u8]) -> anyhow::Result<[u8; N]> {
+    if value.len() > N {
+        bail!(
+            "{:?} is too long for a {} byte field",
+            String::from_utf8_lossy(value),
+            N
+        );
+    }
+    let mut buffer = [0u8; N];
+    buffer[..value.len()].copy_from_slice(value);
+    Ok(buffer)
+}
 
-    // Convert the valid part of the byte array to a UTF-8 string
-    let path_str = str::from_utf8(&array[..valid_length]).unwrap_or("");
+/// Get the contents of a NUL padded string field.
+pub fn bytes_to_str(field: &[u8]) -> &[u8] {
+    let valid_length = field
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(field.len());
+    &field[..valid_length]
+}
 
-    // Convert the string to a PathBuf
-    PathBuf::from(path_str)
+pub fn path_to_bytes(path: &Path) -> &[u8] {
+    path.as_os_str().as_bytes()
 }
 
-//fn to_bytes<T: ToBytes, const N: usize>(value: T) -> [u8; N] {
-//    let mut padded = [0u8; N]; // Create an N-byte array initialized with zeros
-//    let bytes = value.to_le_bytes(); // Convert the T to a N-byte array (little-endian)
-//    padded[..N].copy_from_slice(&bytes); // Copy the N bytes into the first half
-//    padded
-//}
+pub fn bytes_to_path(bytes: &[u8]) -> PathBuf {
+    PathBuf::from(OsStr::from_bytes(bytes))
+}
@@ -0,0 +1,213 @@
+//! Compression of a whole tar archive, like `.tar.gz` or `.tar.zst`.
+//!
+//! Unlike the BAG format, tar has no notion of compression; the whole archive stream is compressed
+//! instead. So this is a layer around the writer/reader of the archive, which the tar backend
+//! itself doesn't know about.
+
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use anyhow::bail;
+
+use crate::DetectedFormat;
+
+/// Compression of a whole tar archive.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TarCompression {
+    /// A plain tar archive.
+    #[default]
+    None,
+    /// gzip; `.tar.gz` or `.tgz`. Levels 0 to 9, defaults to 6.
+    Gzip,
+    /// Zstandard; `.tar.zst` or `.tzst`. Levels 1 to 22, defaults to 3.
+    Zstd,
+    /// xz; `.tar.xz` or `.txz`. Levels 0 to 9, defaults to 6.
+    Xz,
+    /// bzip2; `.tar.bz2` or `.tbz2`. Levels 1 to 9, defaults to 9.
+    Bzip2,
+}
+
+/// File name suffixes of tar archives, and their compression.
+const EXTENSIONS: [(&str, TarCompression); 10] = [
+    (".tar", TarCompression::None),
+    (".tar.gz", TarCompression::Gzip),
+    (".tgz", TarCompression::Gzip),
+    (".tar.zst", TarCompression::Zstd),
+    (".tzst", TarCompression::Zstd),
+    (".tar.xz", TarCompression::Xz),
+    (".txz", TarCompression::Xz),
+    (".tar.bz2", TarCompression::Bzip2),
+    (".tbz2", TarCompression::Bzip2),
+    (".tbz", TarCompression::Bzip2),
+];
+
+impl TarCompression {
+    /// Get the compression from the extension of a tar archive's file name, like `.tar.gz`.
+    /// Returns `None` if the file name doesn't have a tar extension.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_str()?.to_ascii_lowercase();
+        EXTENSIONS
+            .iter()
+            .find(|(extension, _)| name.ends_with(extension))
+            .map(|(_, compression)| *compression)
+    }
+
+    /// Get the compression of a stream from its detected format. Returns `None` if the format is
+    /// not a compression supported here.
+    pub fn from_detected(format: DetectedFormat) -> Option<Self> {
+        match format {
+            DetectedFormat::Gzip => Some(Self::Gzip),
+            DetectedFormat::Zstd => Some(Self::Zstd),
+            DetectedFormat::Xz => Some(Self::Xz),
+            DetectedFormat::Bzip2 => Some(Self::Bzip2),
+            DetectedFormat::Bag | DetectedFormat::Tar | DetectedFormat::Zip => None,
+        }
+    }
+
+    /// Check that the compression level is valid for this compression.
+    pub fn validate_level(&self, level: i32) -> anyhow::Result<()> {
+        let valid = match self {
+            Self::None => bail!("An uncompressed archive has no levels"),
+            Self::Zstd => zstd::compression_level_range().contains(&level),
+            Self::Gzip | Self::Xz => (0..=9).contains(&level),
+            Self::Bzip2 => (1..=9).contains(&level),
+        };
+        if !valid {
+            bail!("Invalid compression level {} for {:?}", level, self);
+        }
+        Ok(())
+    }
+
+    /// Wrap a writer, to compress everything written to it. [`TarEncoder::finish`] has to be
+    /// called after the archive is written.
+    pub fn encoder<W: Write>(
+        &self,
+        writer: W,
+        level: Option<i32>,
+    ) -> anyhow::Result<TarEncoder<W>> {
+        if let Some(level) = level {
+            self.validate_level(level)?;
+        }
+        let encoder = match self {
+            Self::None => TarEncoder::None(writer),
+            Self::Gzip => {
+                let level = flate2::Compression::new(level.unwrap_or(6) as u32);
+                TarEncoder::Gzip(flate2::write::GzEncoder::new(writer, level))
+            }
+            Self::Zstd => {
+                let level = level.unwrap_or(zstd::DEFAULT_COMPRESSION_LEVEL);
+                TarEncoder::Zstd(zstd::Encoder::new(writer, level)?)
+            }
+            Self::Xz => {
+                let level = level.unwrap_or(6) as u32;
+                TarEncoder::Xz(xz2::write::XzEncoder::new(writer, level))
+            }
+            Self::Bzip2 => {
+                let level = bzip2::Compression::new(level.unwrap_or(9) as u32);
+                TarEncoder::Bzip2(bzip2::write::BzEncoder::new(writer, level))
+            }
+        };
+        Ok(encoder)
+    }
+
+    /// Wrap a reader, to decompress everything read from it. Streams of multiple concatenated
+    /// members (as written by e.g. `pigz` or `pbzip2`) are read as one.
+    pub fn decoder<'a, R: Read + 'a>(&self, reader: R) -> anyhow::Result<Box<dyn Read + 'a>> {
+        let decoder: Box<dyn Read + 'a> = match self {
+            Self::None => Box::new(reader),
+            Self::Gzip => Box::new(flate2::read::MultiGzDecoder::new(reader)),
+            Self::Zstd => Box::new(zstd::Decoder::new(reader)?),
+            Self::Xz => Box::new(xz2::read::XzDecoder::new_multi_decoder(reader)),
+            Self::Bzip2 => Box::new(bzip2::read::MultiBzDecoder::new(reader)),
+        };
+        Ok(decoder)
+    }
+}
+
+/// A writer which compresses the archive written to it. Created by [`TarCompression::encoder`].
+pub enum TarEncoder<W: Write> {
+    None(W),
+    Gzip(flate2::write::GzEncoder<W>),
+    Zstd(zstd::Encoder<'static, W>),
+    Xz(xz2::write::XzEncoder<W>),
+    Bzip2(bzip2::write::BzEncoder<W>),
+}
+
+impl<W: Write> TarEncoder<W> {
+    /// Write the end of the compressed stream, and get back the inner writer.
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            Self::None(writer) => Ok(writer),
+            Self::Gzip(encoder) => encoder.finish(),
+            Self::Zstd(encoder) => encoder.finish(),
+            Self::Xz(encoder) => encoder.finish(),
+            Self::Bzip2(encoder) => encoder.finish(),
+        }
+    }
+
+    fn inner(&mut self) -> &mut dyn Write {
+        match self {
+            Self::None(writer) => writer,
+            Self::Gzip(encoder) => encoder,
+            Self::Zstd(encoder) => encoder,
+            Self::Xz(encoder) => encoder,
+            Self::Bzip2(encoder) => encoder,
+        }
+    }
+}
+
+impl<W: Write> Write for TarEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner().flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestDir;
+    use crate::{detect_format, list, pack, TarArchive};
+
+    #[test]
+    fn test_from_path() {
+        let compression = |path: &str| TarCompression::from_path(Path::new(path));
+        assert_eq!(compression("out/release.tar"), Some(TarCompression::None));
+        assert_eq!(compression("release.tar.gz"), Some(TarCompression::Gzip));
+        assert_eq!(compression("release.TGZ"), Some(TarCompression::Gzip));
+        assert_eq!(compression("release.tar.zst"), Some(TarCompression::Zstd));
+        assert_eq!(compression("release.tar.xz"), Some(TarCompression::Xz));
+        assert_eq!(compression("release.tbz2"), Some(TarCompression::Bzip2));
+        assert_eq!(compression("release.bag"), None);
+        assert_eq!(compression("release.gz"), None);
+    }
+
+    #[test]
+    fn test_compressed_tar_roundtrip() -> anyhow::Result<()> {
+        let dir = TestDir::new("packer_compressed_tar")?;
+        dir.write_files(&[("file.txt", &"hello world\n".repeat(1000))])?;
+
+        for compression in [
+            TarCompression::Gzip,
+            TarCompression::Zstd,
+            TarCompression::Xz,
+            TarCompression::Bzip2,
+        ] {
+            let packer = TarArchive::new();
+            let mut encoder = compression.encoder(Vec::new(), None)?;
+            pack(&packer, &mut encoder, &[dir.path().to_path_buf()])?;
+            let archive = encoder.finish()?;
+
+            let detected = detect_format(&archive).and_then(TarCompression::from_detected);
+            assert_eq!(detected, Some(compression));
+            let decoder = compression.decoder(archive.as_slice())?;
+            let entries = list(&mut TarArchive::new(), decoder)?;
+            assert_eq!(entries.len(), 2, "{:?}", compression);
+            assert_eq!(entries[1].file_size, 12000);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,235 @@
+//! Extended header attributes of the TAR archive format. These carry values which don't fit in a
+//! ustar header, like long paths and link targets, large sizes or sub-second timestamps.
+//!
+//! Two kinds of extended headers are supported -
+//! - **GNU** : `L` (long name) and `K` (long link name) records. The data of the record is the
+//!   NUL terminated name.
+//! - **pax** : `x` (for the next entry) and `g` (for all the following entries) headers. The data
+//!   of the header is a list of records of the form `"<length> <key>=<value>\n"`, where length is
+//!   the length of the whole record in decimal.
+
+use std::path::PathBuf;
+
+use anyhow::{anyhow, bail, Context};
+
+use super::byteorder::{bytes_to_path, path_to_bytes};
+
+/// Attributes from extended headers, which override the values in the ustar header of an entry.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Extensions {
+    pub path: Option<PathBuf>,
+    pub link_path: Option<PathBuf>,
+    pub size: Option<u64>,
+    /// Modified time as seconds and nanoseconds.
+    pub mtime: Option<(i64, u32)>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub user_name: Option<String>,
+    pub group_name: Option<String>,
+}
+
+impl Extensions {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Parse the records of a pax extended header, and apply them. A record with an empty value
+    /// removes the attribute.
+    pub fn apply_pax_records(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        let mut rest = data;
+        while !rest.is_empty() {
+            let space = rest
+                .iter()
+                .position(|&byte| byte == b' ')
+                .with_context(|| "Invalid pax record: missing length")?;
+            let length: usize = std::str::from_utf8(&rest[..space])?
+                .parse()
+                .with_context(|| "Invalid pax record: invalid length")?;
+            if length <= space + 1 || length > rest.len() || rest[length - 1] != b'\n' {
+                bail!("Invalid pax record: length {} is out of bounds", length);
+            }
+            let record = &rest[space + 1..length - 1];
+            let equals = record
+                .iter()
+                .position(|&byte| byte == b'=')
+                .with_context(|| "Invalid pax record: missing '='")?;
+            self.apply_pax_record(&record[..equals], &record[equals + 1..])?;
+            rest = &rest[length..];
+        }
+        Ok(())
+    }
+
+    fn apply_pax_record(&mut self, key: &[u8], value: &[u8]) -> anyhow::Result<()> {
+        let text = || {
+            std::str::from_utf8(value)
+                .map_err(|_| anyhow!("Invalid pax record: value of {:?} is not UTF-8", key))
+        };
+        let number = || -> anyhow::Result<u64> {
+            text()?.parse().map_err(|_| {
+                anyhow!(
+                    "Invalid pax record: value of {} is not a number",
+                    String::from_utf8_lossy(key)
+                )
+            })
+        };
+        let present = !value.is_empty();
+        match key {
+            b"path" => self.path = present.then(|| bytes_to_path(value)),
+            b"linkpath" => self.link_path = present.then(|| bytes_to_path(value)),
+            b"size" => self.size = present.then(number).transpose()?,
+            b"mtime" => self.mtime = present.then(|| parse_pax_time(text()?)).transpose()?,
+            b"uid" => {
+                self.uid = present
+                    .then(number)
+                    .transpose()?
+                    .map(u32::try_from)
+                    .transpose()?
+            }
+            b"gid" => {
+                self.gid = present
+                    .then(number)
+                    .transpose()?
+                    .map(u32::try_from)
+                    .transpose()?
+            }
+            b"uname" => self.user_name = present.then(|| text().map(str::to_owned)).transpose()?,
+            b"gname" => self.group_name = present.then(|| text().map(str::to_owned)).transpose()?,
+            _ => log::trace!(
+                "Ignoring pax record: {}={}",
+                String::from_utf8_lossy(key),
+                String::from_utf8_lossy(value)
+            ),
+        }
+        Ok(())
+    }
+
+    /// Serialize the attributes into the records of a pax extended header.
+    pub fn to_pax_records(&self) -> Vec<u8> {
+        let mut records = Vec::new();
+        if let Some(path) = &self.path {
+            records.extend(pax_record("path", path_to_bytes(path)));
+        }
+        if let Some(link_path) = &self.link_path {
+            records.extend(pax_record("linkpath", path_to_bytes(link_path)));
+        }
+        if let Some(size) = self.size {
+            records.extend(pax_record("size", size.to_string().as_bytes()));
+        }
+        if let Some((secs, nanos)) = self.mtime {
+            records.extend(pax_record("mtime", format_pax_time(secs, nanos).as_bytes()));
+        }
+        if let Some(uid) = self.uid {
+            records.extend(pax_record("uid", uid.to_string().as_bytes()));
+        }
+        if let Some(gid) = self.gid {
+            records.extend(pax_record("gid", gid.to_string().as_bytes()));
+        }
+        if let Some(user_name) = &self.user_name {
+            records.extend(pax_record("uname", user_name.as_bytes()));
+        }
+        if let Some(group_name) = &self.group_name {
+            records.extend(pax_record("gname", group_name.as_bytes()));
+        }
+        records
+    }
+}
+
+/// Create a pax record. The length of the record includes the digits of the length itself, so
+/// keep adding digits till the length is stable.
+fn pax_record(key: &str, value: &[u8]) -> Vec<u8> {
+    // " " + key + "=" + value + "\n"
+    let rest_length = key.len() + value.len() + 3;
+    let mut length = rest_length + 1;
+    while length != rest_length + length.to_string().len() {
+        length = rest_length + length.to_string().len();
+    }
+    let mut record = format!("{} {}=", length, key).into_bytes();
+    record.extend_from_slice(value);
+    record.push(b'\n');
+    record
+}
+
+/// Format a timestamp as decimal seconds with a fraction, e.g. `1633072800.5`.
+fn format_pax_time(secs: i64, nanos: u32) -> String {
+    if nanos == 0 {
+        return secs.to_string();
+    }
+    // negative times are written as -(whole).(fraction), where the fraction counts away from 0
+    let (sign, whole, fraction) = if secs < 0 {
+        ("-", (secs + 1).unsigned_abs(), 1_000_000_000 - nanos)
+    } else {
+        ("", secs.unsigned_abs(), nanos)
+    };
+    let fraction = format!("{:09}", fraction);
+    format!("{}{}.{}", sign, whole, fraction.trim_end_matches('0'))
+}
+
+/// Parse a timestamp of decimal seconds with an optional fraction, into seconds and nanoseconds.
+fn parse_pax_time(value: &str) -> anyhow::Result<(i64, u32)> {
+    let invalid = || anyhow!("Invalid pax time: {}", value);
+    let (negative, unsigned) = match value.strip_prefix('-') {
+        Some(unsigned) => (true, unsigned),
+        None => (false, value),
+    };
+    let (whole, fraction) = unsigned.split_once('.').unwrap_or((unsigned, ""));
+    let whole: i64 = whole.parse().map_err(|_| invalid())?;
+    if !fraction.bytes().all(|byte| byte.is_ascii_digit()) {
+        return Err(invalid());
+    }
+    // only nanosecond precision is kept
+    let nanos: u32 = format!("{:0<9}", &fraction[..fraction.len().min(9)])
+        .parse()
+        .map_err(|_| invalid())?;
+    if !negative {
+        Ok((whole, nanos))
+    } else if nanos == 0 {
+        Ok((-whole, 0))
+    } else {
+        Ok((-whole - 1, 1_000_000_000 - nanos))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pax_records_roundtrip() -> anyhow::Result<()> {
+        let extensions = Extensions {
+            path: Some(PathBuf::from(format!("{}/ünïcödé.txt", "d".repeat(200)))),
+            link_path: Some(PathBuf::from("l".repeat(150))),
+            size: Some(10_000_000_000),
+            mtime: Some((1633072800, 500_000_000)),
+            uid: Some(4_000_000),
+            gid: Some(4_000_001),
+            user_name: Some("u".repeat(40)),
+            group_name: Some("g".repeat(40)),
+        };
+        let records = extensions.to_pax_records();
+        let mut parsed = Extensions::default();
+        parsed.apply_pax_records(&records)?;
+        assert_eq!(parsed, extensions);
+        Ok(())
+    }
+
+    #[test]
+    fn test_pax_record_length() {
+        assert_eq!(pax_record("path", b"foo"), b"12 path=foo\n");
+        // the length grows a digit because of the length itself
+        let record = pax_record("path", &[b'a'; 92]);
+        assert_eq!(record.len(), 102);
+        assert!(record.starts_with(b"102 path="));
+    }
+
+    #[test]
+    fn test_pax_time() -> anyhow::Result<()> {
+        assert_eq!(parse_pax_time("1633072800")?, (1633072800, 0));
+        assert_eq!(parse_pax_time("1633072800.25")?, (1633072800, 250_000_000));
+        assert_eq!(parse_pax_time("-1.5")?, (-2, 500_000_000));
+        assert_eq!(format_pax_time(-2, 500_000_000), "-1.5");
+        assert_eq!(format_pax_time(-1, 250_000_000), "-0.75");
+        assert_eq!(parse_pax_time("-0.75")?, (-1, 250_000_000));
+        assert!(parse_pax_time("abc").is_err());
+        Ok(())
+    }
+}
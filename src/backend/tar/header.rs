@@ -1,47 +1,132 @@
-use std::io::Write;
+//! All header definitions for the TAR archive format. Headers follow the POSIX ustar format, so
+//! that archives can be read and written by other tar implementations.
+
+/*
+ * Layout of the ustar header -
+ *
+ * +-------------+---------------+--------+------------------------------------------------------+
+ * | Field       |  Size(bytes)  | Offset |  Remarks                                             |
+ * +-------------+---------------+--------+------------------------------------------------------+
+ * | <name>      |  100          |  0     |  File name, NUL padded                               |
+ * | <mode>      |  8            |  100   |  File permissions, octal                             |
+ * | <uid>       |  8            |  108   |  uid of the file owner, octal                        |
+ * | <gid>       |  8            |  116   |  gid of the file group, octal                        |
+ * | <size>      |  12           |  124   |  Size of the file, octal                             |
+ * | <mtime>     |  12           |  136   |  Last modified time of file, octal                   |
+ * | <chksum>    |  8            |  148   |  Sum of all header bytes, with chksum set to spaces  |
+ * | <typeflag>  |  1            |  156   |  Flag indicating file type                           |
+ * | <linkname>  |  100          |  157   |  Link target if file is a link, NUL padded           |
+ * | <magic>     |  6            |  257   |  "ustar\0"                                           |
+ * | <version>   |  2            |  263   |  "00"                                                |
+ * | <uname>     |  32           |  265   |  User name of the file owner, NUL padded             |
+ * | <gname>     |  32           |  297   |  Group name of the file group, NUL padded            |
+ * | <devmajor>  |  8            |  329   |  Major device number, octal                          |
+ * | <devminor>  |  8            |  337   |  Minor device number, octal                          |
+ * | <prefix>    |  155          |  345   |  Prefix of the file name, if it doesn't fit in name  |
+ * +-------------+---------------+--------+------------------------------------------------------+
+ *
+ * This header data is of 500 bytes. The header block is padded with 0s to make it 512 bytes. The
+ * file data follows the header, and is padded with 0s to a multiple of 512 bytes.
+ */
+
+use std::io::{self, Read, Write};
 use std::path::PathBuf;
 use std::{fs, os::unix::fs::MetadataExt, path::Path};
 
 use anyhow::anyhow;
 use anyhow::bail;
-use crc_any::CRCu32;
+use anyhow::Context;
+use nix::sys::stat::{major, minor};
 
 use super::byteorder::{
-    bytes_to_i64, bytes_to_path, bytes_to_u32, bytes_to_u64, i64_to_bytes, path_to_bytes,
-    u32_to_bytes, u64_to_bytes,
+    bytes_to_path, bytes_to_str, i64_to_base256, octal_to_i64, octal_to_u64, path_to_bytes,
+    str_to_bytes, u64_to_octal,
 };
+use super::extension::Extensions;
+use super::{padding_size, TarFormat};
+use crate::backend::owner_names::OwnerNames;
+use crate::backend::FileType;
 
-#[derive(Debug)]
+/// Size of a header block, and the unit in which file data is padded.
+pub const BLOCK_SIZE: usize = 512;
+
+const USTAR_MAGIC: &[u8; 6] = b"ustar\0";
+const USTAR_VERSION: &[u8; 2] = b"00";
+/// Magic and version of the GNU tar format.
+const GNU_MAGIC: &[u8; 6] = b"ustar ";
+const GNU_VERSION: &[u8; 2] = b" \0";
+
+/// Name of the GNU longname/longlink records.
+const GNU_LONG_NAME: &[u8] = b"././@LongLink";
+
+/// Extended headers larger than this are rejected, as they are most likely corrupted.
+const MAX_EXTENSION_SIZE: u64 = 1024 * 1024;
+
+#[derive(Debug, Clone)]
 pub struct Header {
     pub file_name: PathBuf,
     pub file_mode: u32,
     pub user_id: u32,
     pub group_id: u32,
+    pub user_name: String,
+    pub group_name: String,
     pub file_size: u64,
     pub last_modified: i64,
+    pub last_modified_nanos: u32,
     pub type_flag: TypeFlag,
-    // pub link_name: PathBuf,
+    pub link_name: Option<PathBuf>,
+    pub dev_major: u32,
+    pub dev_minor: u32,
 }
 
 impl Header {
-    pub fn new(file_name: &Path, metadata: fs::Metadata) -> anyhow::Result<Self> {
-        let file_name = file_name.to_owned();
-        let file_mode = metadata.mode();
+    pub(crate) fn new(
+        file_name: &Path,
+        metadata: fs::Metadata,
+        file_type: FileType,
+        link_name: Option<PathBuf>,
+        owner_names: &OwnerNames,
+    ) -> anyhow::Result<Self> {
+        let mut file_name = file_name.to_owned();
+        // by convention, names of directories end with a `/`
+        if file_type == FileType::Directory {
+            file_name.as_mut_os_string().push("/");
+        }
+        let file_mode = metadata.mode() & 0o7777;
         let user_id = metadata.uid();
         let group_id = metadata.gid();
-        let file_size = metadata.len();
+        let user_name = owner_names.user_name(user_id).unwrap_or_default();
+        let group_name = owner_names.group_name(group_id).unwrap_or_default();
+        // only regular files have data in the archive
+        let file_size = if file_type == FileType::Regular {
+            metadata.len()
+        } else {
+            0
+        };
         let last_modified = metadata.mtime();
-        let type_flag = TypeFlag::new(metadata);
+        let last_modified_nanos = metadata.mtime_nsec() as u32;
+        let type_flag = TypeFlag::from(file_type);
+        let (dev_major, dev_minor) = match file_type {
+            FileType::CharDevice | FileType::BlockDevice => {
+                let rdev = metadata.rdev();
+                (major(rdev) as u32, minor(rdev) as u32)
+            }
+            _ => (0, 0),
+        };
         Ok(Self {
             file_name,
             file_mode,
             user_id,
             group_id,
+            user_name,
+            group_name,
             file_size,
             last_modified,
+            last_modified_nanos,
             type_flag,
-            // TODO: handle links
-            // link_name: PathBuf::new(),
+            link_name,
+            dev_major,
+            dev_minor,
         })
     }
 
@@ -50,84 +135,259 @@ impl Header {
         log::debug!("File metadata");
         log::debug!("-------------");
         log::debug!(">> File name: {}", self.file_name.display());
-        log::debug!(">> File mode: {}", self.file_mode);
-        log::debug!(">> uid: {}", self.user_id);
-        log::debug!(">> gid: {}", self.group_id);
+        log::debug!(">> File mode: {:o}", self.file_mode);
+        log::debug!(">> uid: {} ({})", self.user_id, self.user_name);
+        log::debug!(">> gid: {} ({})", self.group_id, self.group_name);
         log::debug!(">> file size: {}", self.file_size);
-        log::debug!(">> mtime: {}", self.last_modified);
+        log::debug!(
+            ">> mtime: {}.{:09}",
+            self.last_modified,
+            self.last_modified_nanos
+        );
         log::debug!(">> typeflag: {:?}", self.type_flag);
+        log::debug!(">> device: {},{}", self.dev_major, self.dev_minor);
+        log::debug!(
+            ">> link name: {}",
+            self.link_name
+                .as_ref()
+                .map(|ln| ln.display().to_string())
+                .unwrap_or("<N/A>".to_string())
+        );
         log::debug!("-------------");
     }
 
-    pub fn serialize(self) -> anyhow::Result<[u8; 512]> {
-        let mut header_ll = HeaderLL::new(self)?;
+    /// Serialize the header in the given format. This gives the header block, preceded by any
+    /// extended headers required to store values which don't fit in the header block.
+    pub fn serialize(self, format: TarFormat) -> anyhow::Result<Vec<u8>> {
+        let mut extensions = Extensions::default();
+        let mut header_ll = HeaderLL::new(&self, format, &mut extensions)?;
         let checksum = header_ll.calculate_checksum()?;
-        header_ll.set_checksum(checksum);
-        header_ll.serialize()
+        header_ll.set_checksum(checksum)?;
+
+        let mut blocks = Vec::new();
+        match format {
+            TarFormat::Gnu => {
+                if let Some(link_path) = &extensions.link_path {
+                    blocks.extend(extension_header(b'K', path_to_bytes(link_path))?);
+                }
+                if let Some(path) = &extensions.path {
+                    blocks.extend(extension_header(b'L', path_to_bytes(path))?);
+                }
+            }
+            TarFormat::Pax if !extensions.is_empty() => {
+                blocks.extend(extension_header(b'x', &extensions.to_pax_records())?);
+            }
+            TarFormat::Pax | TarFormat::Ustar => {}
+        }
+        blocks.extend_from_slice(&header_ll.to_bytes()?);
+        Ok(blocks)
     }
 
+    /// Deserialize a single header block.
     pub fn deserialize(bytes: &[u8]) -> anyhow::Result<Self> {
-        let mut ll = HeaderLL::from_bytes(bytes)?;
-        // get the stored checksum
-        let stored_checksum = bytes_to_u32(ll.checksum);
-        // set the checksum to empty in low-level header object
-        ll.set_checksum(0);
-        // now calculate the checksum of deserialized header
-        let calc_checksum = ll.calculate_checksum()?;
-        // check if checksum matches
-        if calc_checksum != stored_checksum {
-            bail!(
-                "ERROR: Checksums don't match for file {}. Stored checksum: {}, calculated checksum: {}",
-                bytes_to_path(&ll.file_name).display(),
-                stored_checksum,
-                calc_checksum
-            )
+        let ll = HeaderLL::from_bytes(bytes)?;
+        ll.verify_checksum()?;
+        Self::from_ll(ll, Extensions::default())
+    }
+
+    /// Read a header, starting from the given header block. If the block is an extended header
+    /// (GNU longname/longlink records, or pax headers), its data and the following blocks are read
+    /// from the reader till the actual header block is found. The attributes of pax global headers
+    /// are recorded in `globals`, as they apply to all the following entries of the archive.
+    ///
+    /// GNU volume labels and multi-volume continuations are skipped, as they are not files. A GNU
+    /// dumpdir is read as a directory; skipping its data, which lists the names in the directory.
+    pub fn read<R: Read>(
+        reader: &mut R,
+        header_buffer: &[u8],
+        globals: &mut Extensions,
+    ) -> anyhow::Result<Self> {
+        let mut extensions = globals.clone();
+        let mut block = [0u8; BLOCK_SIZE];
+        block.copy_from_slice(header_buffer);
+        loop {
+            let ll = HeaderLL::from_bytes(&block)?;
+            ll.verify_checksum()?;
+            if matches!(ll.type_flag, b'V' | b'M') {
+                log::warn!(
+                    "Skipping GNU {} entry: {}",
+                    if ll.type_flag == b'V' {
+                        "volume label"
+                    } else {
+                        "multi-volume continuation"
+                    },
+                    String::from_utf8_lossy(bytes_to_str(&ll.file_name))
+                );
+                skip_entry_data(reader, octal_to_u64(&ll.file_size)?)?;
+                extensions = globals.clone();
+                reader
+                    .read_exact(&mut block)
+                    .with_context(|| "Reading header")?;
+                continue;
+            }
+            if ll.type_flag == b'D' {
+                let mut header = Self::from_ll(ll, extensions)?;
+                skip_entry_data(reader, header.file_size)?;
+                header.file_size = 0;
+                return Ok(header);
+            }
+            if !matches!(ll.type_flag, b'L' | b'K' | b'x' | b'g') {
+                return Self::from_ll(ll, extensions);
+            }
+
+            let size = octal_to_u64(&ll.file_size)?;
+            if size > MAX_EXTENSION_SIZE {
+                bail!("Extended header of size {} is too large", size);
+            }
+            let mut data = vec![0u8; size as usize + padding_size(size)];
+            reader
+                .read_exact(&mut data)
+                .with_context(|| "Reading extended header")?;
+            data.truncate(size as usize);
+            log::trace!(
+                "Extended header '{}': {:?}",
+                ll.type_flag as char,
+                String::from_utf8_lossy(&data)
+            );
+            match ll.type_flag {
+                b'L' => extensions.path = Some(bytes_to_path(bytes_to_str(&data))),
+                b'K' => extensions.link_path = Some(bytes_to_path(bytes_to_str(&data))),
+                b'x' => extensions.apply_pax_records(&data)?,
+                _ => {
+                    globals.apply_pax_records(&data)?;
+                    extensions.apply_pax_records(&data)?;
+                }
+            }
+
+            reader
+                .read_exact(&mut block)
+                .with_context(|| "Reading header")?;
         }
+    }
+
+    fn from_ll(ll: HeaderLL, extensions: Extensions) -> anyhow::Result<Self> {
+        let link_name = bytes_to_str(&ll.link_name);
+        let link_name = extensions
+            .link_path
+            .or_else(|| (!link_name.is_empty()).then(|| bytes_to_path(link_name)));
+        let (last_modified, last_modified_nanos) = match extensions.mtime {
+            Some(mtime) => mtime,
+            None => (octal_to_i64(&ll.last_modified)?, 0),
+        };
+        let file_name = extensions.path.map_or_else(|| ll.file_name(), Ok)?;
+        // unknown types are read as regular files, as POSIX asks for
+        let type_flag = TypeFlag::from_byte(ll.type_flag).unwrap_or_else(|| {
+            log::warn!(
+                "Unknown type '{}' of {}, reading it as a regular file",
+                ll.type_flag.escape_ascii(),
+                file_name.display()
+            );
+            TypeFlag::Regular
+        });
         Ok(Self {
-            file_name: bytes_to_path(&ll.file_name),
-            file_mode: bytes_to_u32(ll.file_mode),
-            user_id: bytes_to_u32(ll.user_id),
-            group_id: bytes_to_u32(ll.group_id),
-            file_size: bytes_to_u64(ll.file_size),
-            last_modified: bytes_to_i64(ll.last_modified),
-            type_flag: TypeFlag::from_byte(ll.type_flag)?,
-            // link_name: bytes_to_path(&ll.link_name),
+            file_name,
+            file_mode: octal_to_u64(&ll.file_mode)? as u32,
+            user_id: extensions
+                .uid
+                .map_or_else(|| octal_to_u64(&ll.user_id).map(|v| v as u32), Ok)?,
+            group_id: extensions
+                .gid
+                .map_or_else(|| octal_to_u64(&ll.group_id).map(|v| v as u32), Ok)?,
+            user_name: extensions.user_name.unwrap_or_else(|| {
+                String::from_utf8_lossy(bytes_to_str(&ll.user_name)).into_owned()
+            }),
+            group_name: extensions.group_name.unwrap_or_else(|| {
+                String::from_utf8_lossy(bytes_to_str(&ll.group_name)).into_owned()
+            }),
+            file_size: extensions
+                .size
+                .map_or_else(|| octal_to_u64(&ll.file_size), Ok)?,
+            last_modified,
+            last_modified_nanos,
+            type_flag,
+            link_name,
+            dev_major: octal_to_u64(&ll.dev_major)? as u32,
+            dev_minor: octal_to_u64(&ll.dev_minor)? as u32,
         })
     }
 }
 
-#[derive(Debug)]
+/// Skip over the data of an entry of the given size, along with its padding.
+fn skip_entry_data<R: Read>(reader: &mut R, size: u64) -> anyhow::Result<()> {
+    let data_size = size + padding_size(size) as u64;
+    let skipped = io::copy(&mut reader.take(data_size), &mut io::sink())?;
+    if skipped < data_size {
+        bail!(
+            "Unexpected end of archive, while skipping {} bytes",
+            data_size
+        );
+    }
+    Ok(())
+}
+
+/// Create an extended header (a GNU longname/longlink record or a pax header) with the given data,
+/// padded to the block size.
+fn extension_header(type_flag: u8, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut header_ll = HeaderLL::extension(type_flag, data)?;
+    let checksum = header_ll.calculate_checksum()?;
+    header_ll.set_checksum(checksum)?;
+    let mut blocks = header_ll.to_bytes()?.to_vec();
+    blocks.extend_from_slice(data);
+    blocks.resize(blocks.len() + padding_size(data.len() as u64), 0);
+    Ok(blocks)
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum TypeFlag {
     Regular,
     HardLink,
     SymLink,
+    CharDevice,
+    BlockDevice,
+    Directory,
+    Fifo,
 }
 
-impl TypeFlag {
-    fn new(metadata: fs::Metadata) -> Self {
-        if metadata.is_symlink() {
-            TypeFlag::SymLink
-        } else if metadata.is_dir() {
-            TypeFlag::HardLink
-        } else {
-            TypeFlag::Regular
+impl From<FileType> for TypeFlag {
+    fn from(file_type: FileType) -> Self {
+        match file_type {
+            FileType::Regular => TypeFlag::Regular,
+            FileType::HardLink => TypeFlag::HardLink,
+            FileType::SymLink => TypeFlag::SymLink,
+            FileType::CharDevice => TypeFlag::CharDevice,
+            FileType::BlockDevice => TypeFlag::BlockDevice,
+            FileType::Directory => TypeFlag::Directory,
+            FileType::Fifo => TypeFlag::Fifo,
         }
     }
+}
 
+impl TypeFlag {
     fn as_byte(&self) -> u8 {
         match self {
             TypeFlag::Regular => b'0',
             TypeFlag::HardLink => b'1',
             TypeFlag::SymLink => b'2',
+            TypeFlag::CharDevice => b'3',
+            TypeFlag::BlockDevice => b'4',
+            TypeFlag::Directory => b'5',
+            TypeFlag::Fifo => b'6',
         }
     }
 
-    fn from_byte(byte: u8) -> anyhow::Result<Self> {
+    /// The type of a typeflag byte; `None` for unknown ones.
+    fn from_byte(byte: u8) -> Option<Self> {
         match byte {
-            b'0' | 0 => Ok(TypeFlag::Regular),
-            b'1' | 1 => Ok(TypeFlag::HardLink),
-            b'2' | 2 => Ok(TypeFlag::SymLink),
-            _ => Err(anyhow!("Invalid typeflag byte: {:?}", byte)),
+            // '7' is a contiguous file, which is treated as a regular file by all implementations
+            b'0' | 0 | b'7' => Some(TypeFlag::Regular),
+            b'1' => Some(TypeFlag::HardLink),
+            b'2' => Some(TypeFlag::SymLink),
+            b'3' => Some(TypeFlag::CharDevice),
+            b'4' => Some(TypeFlag::BlockDevice),
+            // 'D' is a GNU dumpdir; a directory along with the list of names in it
+            b'5' | b'D' => Some(TypeFlag::Directory),
+            b'6' => Some(TypeFlag::Fifo),
+            _ => None,
         }
     }
 }
@@ -144,44 +404,188 @@ pub struct HeaderLL {
     pub checksum: [u8; 8],
     pub type_flag: u8,
     pub link_name: [u8; 100],
+    pub magic: [u8; 6],
+    pub version: [u8; 2],
+    pub user_name: [u8; 32],
+    pub group_name: [u8; 32],
+    pub dev_major: [u8; 8],
+    pub dev_minor: [u8; 8],
+    pub prefix: [u8; 155],
 }
 
 impl HeaderLL {
-    pub fn new(header: Header) -> anyhow::Result<Self> {
-        Ok(Self {
-            file_name: path_to_bytes(header.file_name),
-            file_mode: u32_to_bytes(header.file_mode),
-            user_id: u32_to_bytes(header.user_id),
-            group_id: u32_to_bytes(header.group_id),
-            file_size: u64_to_bytes(header.file_size),
-            last_modified: i64_to_bytes(header.last_modified),
-            checksum: [0u8; 8],
+    /// Create the header block for the given header. Values which don't fit in the header block are
+    /// handled as per the format - GNU uses base-256 numbers and longname/longlink records, pax
+    /// stores them in `extensions`, and plain ustar fails.
+    pub fn new(
+        header: &Header,
+        format: TarFormat,
+        extensions: &mut Extensions,
+    ) -> anyhow::Result<Self> {
+        let path = path_to_bytes(&header.file_name);
+        let (prefix, file_name): (&[u8], &[u8]) = match format {
+            TarFormat::Ustar => split_path(path)?,
+            TarFormat::Gnu if path.len() > 100 => {
+                extensions.path = Some(header.file_name.clone());
+                (&[], &path[..100])
+            }
+            TarFormat::Gnu => (&[], path),
+            TarFormat::Pax => match split_path(path) {
+                Ok(split) if path.is_ascii() => split,
+                _ => {
+                    extensions.path = Some(header.file_name.clone());
+                    (&[], &path[..path.len().min(100)])
+                }
+            },
+        };
+
+        let link_path = header
+            .link_name
+            .as_deref()
+            .map(path_to_bytes)
+            .unwrap_or_default();
+        let link_name = match format {
+            TarFormat::Ustar => link_path,
+            _ if link_path.len() > 100 || (format == TarFormat::Pax && !link_path.is_ascii()) => {
+                extensions.link_path = header.link_name.clone();
+                &link_path[..link_path.len().min(100)]
+            }
+            _ => link_path,
+        };
+
+        let file_size = i64::try_from(header.file_size)?;
+        let (magic, version) = match format {
+            TarFormat::Gnu => (GNU_MAGIC, GNU_VERSION),
+            _ => (USTAR_MAGIC, USTAR_VERSION),
+        };
+        let header_ll = Self {
+            file_name: str_to_bytes(file_name)?,
+            file_mode: u64_to_octal(header.file_mode as u64)?,
+            user_id: numeric_field("uid", header.user_id as i64, format, || {
+                extensions.uid = Some(header.user_id)
+            })?,
+            group_id: numeric_field("gid", header.group_id as i64, format, || {
+                extensions.gid = Some(header.group_id)
+            })?,
+            file_size: numeric_field("Size", file_size, format, || {
+                extensions.size = Some(header.file_size)
+            })?,
+            last_modified: numeric_field("Modified time", header.last_modified, format, || {
+                extensions.mtime = Some((header.last_modified, header.last_modified_nanos))
+            })?,
+            checksum: [b' '; 8],
             type_flag: header.type_flag.as_byte(),
+            link_name: str_to_bytes(link_name)
+                .with_context(|| format!("Link target of {}", header.file_name.display()))?,
+            magic: *magic,
+            version: *version,
+            user_name: string_field(header.user_name.as_bytes(), format, || {
+                extensions.user_name = Some(header.user_name.clone())
+            })?,
+            group_name: string_field(header.group_name.as_bytes(), format, || {
+                extensions.group_name = Some(header.group_name.clone())
+            })?,
+            dev_major: u64_to_octal(header.dev_major as u64)?,
+            dev_minor: u64_to_octal(header.dev_minor as u64)?,
+            prefix: str_to_bytes(prefix)?,
+        };
+        // sub-second times can only be stored in pax headers
+        if format == TarFormat::Pax && header.last_modified_nanos != 0 {
+            extensions.mtime = Some((header.last_modified, header.last_modified_nanos));
+        }
+        Ok(header_ll)
+    }
+
+    /// Create the header block of an extended header, with data of the given size.
+    fn extension(type_flag: u8, data: &[u8]) -> anyhow::Result<Self> {
+        let (name, magic, version) = match type_flag {
+            b'L' | b'K' => (GNU_LONG_NAME, GNU_MAGIC, GNU_VERSION),
+            _ => (&b"././@PaxHeader"[..], USTAR_MAGIC, USTAR_VERSION),
+        };
+        Ok(Self {
+            file_name: str_to_bytes(name)?,
+            file_mode: u64_to_octal(0o644)?,
+            user_id: u64_to_octal(0)?,
+            group_id: u64_to_octal(0)?,
+            file_size: u64_to_octal(data.len() as u64)?,
+            last_modified: u64_to_octal(0)?,
+            checksum: [b' '; 8],
+            type_flag,
             link_name: [0u8; 100],
+            magic: *magic,
+            version: *version,
+            user_name: [0u8; 32],
+            group_name: [0u8; 32],
+            dev_major: u64_to_octal(0)?,
+            dev_minor: u64_to_octal(0)?,
+            prefix: [0u8; 155],
         })
     }
 
-    /// calculate the checksum of this header; this assumes the checksum field is set to 0
-    pub fn calculate_checksum(&self) -> anyhow::Result<u32> {
-        let mut crc = CRCu32::crc32();
-        let serialized = self.to_bytes()?;
-        crc.digest(&serialized);
-        Ok(crc.get_crc())
+    /// Get the full file name; joining the prefix and the name, if the prefix is present.
+    pub fn file_name(&self) -> anyhow::Result<PathBuf> {
+        let name = bytes_to_str(&self.file_name);
+        // the prefix field is only present in POSIX ustar headers. GNU tar uses that space for
+        // other things.
+        let prefix = if self.is_ustar() {
+            bytes_to_str(&self.prefix)
+        } else {
+            &[]
+        };
+        if prefix.is_empty() {
+            Ok(bytes_to_path(name))
+        } else {
+            Ok(bytes_to_path(prefix).join(bytes_to_path(name)))
+        }
+    }
+
+    /// Check if this is a POSIX ustar header. GNU tar headers have the magic "ustar  \0" instead.
+    fn is_ustar(&self) -> bool {
+        &self.magic == USTAR_MAGIC
     }
 
-    pub fn set_checksum(&mut self, checksum: u32) {
-        self.checksum = u32_to_bytes(checksum);
+    /// Verify the stored checksum against the calculated one. Some old tar implementations
+    /// calculated it with signed bytes, so accept that too.
+    pub fn verify_checksum(&self) -> anyhow::Result<()> {
+        let stored_checksum = octal_to_u64(&self.checksum)?;
+        let calc_checksum = self.calculate_checksum()?;
+        let calc_signed_checksum = self.calculate_signed_checksum()?;
+        if calc_checksum != stored_checksum && calc_signed_checksum != stored_checksum as i64 {
+            bail!(
+                "ERROR: Checksums don't match for file {}. Stored checksum: {}, calculated checksum: {}",
+                self.file_name()?.display(),
+                stored_checksum,
+                calc_checksum
+            )
+        }
+        Ok(())
     }
 
-    /// serialize the header into a 512 block
-    pub fn serialize(self) -> anyhow::Result<[u8; 512]> {
-        let mut padded = [0u8; 512];
-        let bytes = self.to_bytes()?;
-        padded[..257].copy_from_slice(&bytes);
-        Ok(padded)
+    /// calculate the checksum of this header; this is the sum of all bytes of the header, with the
+    /// checksum field treated as spaces.
+    pub fn calculate_checksum(&self) -> anyhow::Result<u64> {
+        let mut bytes = self.to_bytes()?;
+        bytes[148..156].fill(b' ');
+        Ok(bytes.iter().map(|&byte| byte as u64).sum())
+    }
+
+    /// calculate the checksum of this header, treating bytes as signed values.
+    pub fn calculate_signed_checksum(&self) -> anyhow::Result<i64> {
+        let mut bytes = self.to_bytes()?;
+        bytes[148..156].fill(b' ');
+        Ok(bytes.iter().map(|&byte| byte as i8 as i64).sum())
     }
 
-    fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+    /// set the checksum; it is stored as 6 octal digits, followed by a NUL and a space.
+    pub fn set_checksum(&mut self, checksum: u64) -> anyhow::Result<()> {
+        let digits: [u8; 7] = u64_to_octal(checksum)?;
+        self.checksum[..7].copy_from_slice(&digits);
+        self.checksum[7] = b' ';
+        Ok(())
+    }
+
+    /// serialize the header into a 512 block
+    pub fn to_bytes(&self) -> anyhow::Result<[u8; BLOCK_SIZE]> {
         let mut buffer = Vec::new();
         buffer.write_all(&self.file_name)?;
         buffer.write_all(&self.file_mode)?;
@@ -192,35 +596,360 @@ impl HeaderLL {
         buffer.write_all(&self.checksum)?;
         buffer.write_all(&[self.type_flag])?;
         buffer.write_all(&self.link_name)?;
-        Ok(buffer)
+        buffer.write_all(&self.magic)?;
+        buffer.write_all(&self.version)?;
+        buffer.write_all(&self.user_name)?;
+        buffer.write_all(&self.group_name)?;
+        buffer.write_all(&self.dev_major)?;
+        buffer.write_all(&self.dev_minor)?;
+        buffer.write_all(&self.prefix)?;
+        let mut padded = [0u8; BLOCK_SIZE];
+        padded[..500].copy_from_slice(&buffer);
+        Ok(padded)
     }
 
     pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
-        if bytes.len() != 512 {
+        if bytes.len() != BLOCK_SIZE {
             bail!(
                 "Invalid byte slice length: expected 512, got {}",
                 bytes.len()
             );
         }
-        let file_name = bytes[0..100].try_into().unwrap();
-        let file_mode = bytes[100..108].try_into().unwrap();
-        let user_id = bytes[108..116].try_into().unwrap();
-        let group_id = bytes[116..124].try_into().unwrap();
-        let file_size = bytes[124..136].try_into().unwrap();
-        let last_modified = bytes[136..148].try_into().unwrap();
-        let checksum = bytes[148..156].try_into().unwrap();
-        let type_flag = bytes[156];
-        let link_name = bytes[157..257].try_into().unwrap();
         Ok(Self {
-            file_name,
-            file_mode,
-            user_id,
-            group_id,
-            file_size,
-            last_modified,
-            checksum,
-            type_flag,
-            link_name,
+            file_name: bytes[0..100].try_into().unwrap(),
+            file_mode: bytes[100..108].try_into().unwrap(),
+            user_id: bytes[108..116].try_into().unwrap(),
+            group_id: bytes[116..124].try_into().unwrap(),
+            file_size: bytes[124..136].try_into().unwrap(),
+            last_modified: bytes[136..148].try_into().unwrap(),
+            checksum: bytes[148..156].try_into().unwrap(),
+            type_flag: bytes[156],
+            link_name: bytes[157..257].try_into().unwrap(),
+            magic: bytes[257..263].try_into().unwrap(),
+            version: bytes[263..265].try_into().unwrap(),
+            user_name: bytes[265..297].try_into().unwrap(),
+            group_name: bytes[297..329].try_into().unwrap(),
+            dev_major: bytes[329..337].try_into().unwrap(),
+            dev_minor: bytes[337..345].try_into().unwrap(),
+            prefix: bytes[345..500].try_into().unwrap(),
+        })
+    }
+}
+
+/// Encode a numeric field in octal. If the value doesn't fit, the GNU format falls back to
+/// base-256, the pax format stores it in an extended header (via `extend`) and plain ustar fails.
+fn numeric_field<const N: usize>(
+    name: &str,
+    value: i64,
+    format: TarFormat,
+    extend: impl FnOnce(),
+) -> anyhow::Result<[u8; N]> {
+    if let Some(Ok(field)) = u64::try_from(value).ok().map(u64_to_octal) {
+        return Ok(field);
+    }
+    match format {
+        TarFormat::Ustar => bail!("{} {} can't be stored in a ustar header", name, value),
+        TarFormat::Gnu => Ok(i64_to_base256(value)),
+        TarFormat::Pax => {
+            extend();
+            u64_to_octal(0)
+        }
+    }
+}
+
+/// Encode a string field. If the value is too long, the pax format stores it in an extended header
+/// (via `extend`), and other formats fail. The pax format also stores non-ASCII values in an
+/// extended header, as they are UTF-8 only there.
+fn string_field<const N: usize>(
+    value: &[u8],
+    format: TarFormat,
+    extend: impl FnOnce(),
+) -> anyhow::Result<[u8; N]> {
+    if format != TarFormat::Pax {
+        return str_to_bytes(value);
+    }
+    if value.len() > N || !value.is_ascii() {
+        extend();
+    }
+    Ok(str_to_bytes(value).unwrap_or([0u8; N]))
+}
+
+/// Split a path into the prefix and name fields of a ustar header. Paths up to 100 bytes go into
+/// the name field. Longer paths are split at a `/`, such that the prefix is at most 155 bytes and
+/// the name is at most 100 bytes.
+fn split_path(path: &[u8]) -> anyhow::Result<(&[u8], &[u8])> {
+    if path.len() <= 100 {
+        return Ok((&[], path));
+    }
+    path.iter()
+        .enumerate()
+        .filter(|(idx, &byte)| byte == b'/' && *idx <= 155 && path.len() - idx - 1 <= 100)
+        .map(|(idx, _)| (&path[..idx], &path[idx + 1..]))
+        .find(|(_, name)| !name.is_empty())
+        .ok_or_else(|| {
+            anyhow!(
+                "Path is too long to be stored in a ustar header: {}",
+                String::from_utf8_lossy(path)
+            )
         })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TarArchive;
+
+    fn sample_header(file_name: &str) -> Header {
+        Header {
+            file_name: PathBuf::from(file_name),
+            file_mode: 0o644,
+            user_id: 1000,
+            group_id: 1000,
+            user_name: "user".to_string(),
+            group_name: "group".to_string(),
+            file_size: 1024,
+            last_modified: 1633072800,
+            last_modified_nanos: 0,
+            type_flag: TypeFlag::Regular,
+            link_name: None,
+            dev_major: 0,
+            dev_minor: 0,
+        }
+    }
+
+    /// Read back a serialized header, including its extended headers.
+    fn read_header(serialized: &[u8]) -> anyhow::Result<Header> {
+        let mut rest = &serialized[BLOCK_SIZE..];
+        let header = Header::read(
+            &mut rest,
+            &serialized[..BLOCK_SIZE],
+            &mut Extensions::default(),
+        )?;
+        assert!(rest.is_empty());
+        Ok(header)
+    }
+
+    #[test]
+    fn test_header_serialization_deserialization() -> anyhow::Result<()> {
+        let header = sample_header("some/dir/test_file.txt");
+        let serialized = header.clone().serialize(TarFormat::Ustar)?;
+        assert_eq!(serialized.len(), BLOCK_SIZE);
+
+        // numeric fields are octal ASCII and the magic is present
+        assert_eq!(&serialized[100..108], b"0000644\0");
+        assert_eq!(&serialized[124..136], b"00000002000\0");
+        assert_eq!(&serialized[257..265], b"ustar\x0000");
+
+        let deserialized = Header::deserialize(&serialized)?;
+        assert_eq!(header.file_name, deserialized.file_name);
+        assert_eq!(header.file_mode, deserialized.file_mode);
+        assert_eq!(header.user_id, deserialized.user_id);
+        assert_eq!(header.group_id, deserialized.group_id);
+        assert_eq!(header.user_name, deserialized.user_name);
+        assert_eq!(header.group_name, deserialized.group_name);
+        assert_eq!(header.file_size, deserialized.file_size);
+        assert_eq!(header.last_modified, deserialized.last_modified);
+        assert_eq!(header.type_flag, deserialized.type_flag);
+        Ok(())
+    }
+
+    #[test]
+    fn test_checksum() -> anyhow::Result<()> {
+        let serialized = sample_header("test_file.txt").serialize(TarFormat::Ustar)?;
+        let mut bytes = serialized.clone();
+        bytes[148..156].fill(b' ');
+        let sum: u64 = bytes.iter().map(|&byte| byte as u64).sum();
+        assert_eq!(octal_to_u64(&serialized[148..156])?, sum);
+        assert_eq!(&serialized[154..156], b"\0 ");
+
+        let mut corrupted = serialized.clone();
+        corrupted[0] = b'x';
+        assert!(Header::deserialize(&corrupted).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_long_path_uses_prefix() -> anyhow::Result<()> {
+        let file_name = format!("{}/{}", "d".repeat(120), "f".repeat(90));
+        let serialized = sample_header(&file_name).serialize(TarFormat::Ustar)?;
+        assert_eq!(
+            bytes_to_str(&serialized[345..500]),
+            "d".repeat(120).as_bytes()
+        );
+        assert_eq!(bytes_to_str(&serialized[0..100]), "f".repeat(90).as_bytes());
+        let deserialized = Header::deserialize(&serialized)?;
+        assert_eq!(deserialized.file_name, PathBuf::from(file_name));
+
+        assert!(sample_header(&"f".repeat(101))
+            .serialize(TarFormat::Ustar)
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_symlink_serialization_deserialization() -> anyhow::Result<()> {
+        let mut header = sample_header("some/dir/link");
+        header.file_size = 0;
+        header.type_flag = TypeFlag::SymLink;
+        header.link_name = Some(PathBuf::from("../target/file.txt"));
+        let serialized = header.clone().serialize(TarFormat::Ustar)?;
+        assert_eq!(serialized[156], b'2');
+        assert_eq!(bytes_to_str(&serialized[157..257]), b"../target/file.txt");
+
+        let deserialized = Header::deserialize(&serialized)?;
+        assert_eq!(header.type_flag, deserialized.type_flag);
+        assert_eq!(header.link_name, deserialized.link_name);
+
+        // long link targets go into an extended header
+        header.link_name = Some(PathBuf::from("l".repeat(300)));
+        let serialized = header.clone().serialize(TarFormat::Pax)?;
+        let deserialized = read_header(&serialized)?;
+        assert_eq!(header.link_name, deserialized.link_name);
+        Ok(())
+    }
+
+    #[test]
+    fn test_device_serialization_deserialization() -> anyhow::Result<()> {
+        let mut header = sample_header("dev/sda1");
+        header.file_size = 0;
+        header.type_flag = TypeFlag::BlockDevice;
+        header.dev_major = 8;
+        header.dev_minor = 1;
+        let serialized = header.clone().serialize(TarFormat::Ustar)?;
+        assert_eq!(serialized[156], b'4');
+        assert_eq!(&serialized[329..337], b"0000010\0");
+        assert_eq!(&serialized[337..345], b"0000001\0");
+
+        let deserialized = Header::deserialize(&serialized)?;
+        assert_eq!(header.type_flag, deserialized.type_flag);
+        assert_eq!(header.dev_major, deserialized.dev_major);
+        assert_eq!(header.dev_minor, deserialized.dev_minor);
+        Ok(())
+    }
+
+    /// Serialize a header with the given typeflag and data.
+    fn entry_with_type_flag(header: Header, type_flag: u8, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let mut ll = HeaderLL::from_bytes(&header.serialize(TarFormat::Ustar)?)?;
+        ll.type_flag = type_flag;
+        ll.file_size = u64_to_octal(data.len() as u64)?;
+        let checksum = ll.calculate_checksum()?;
+        ll.set_checksum(checksum)?;
+        let mut entry = ll.to_bytes()?.to_vec();
+        entry.extend_from_slice(data);
+        entry.resize(entry.len() + padding_size(data.len() as u64), 0);
+        Ok(entry)
+    }
+
+    #[test]
+    fn test_unknown_typeflags() -> anyhow::Result<()> {
+        // a volume label is skipped, and the header after it is read
+        let mut serialized = entry_with_type_flag(sample_header("label"), b'V', b"")?;
+        let mut file = sample_header("file.txt");
+        file.file_size = 0;
+        serialized.extend(file.serialize(TarFormat::Ustar)?);
+        let header = read_header(&serialized)?;
+        assert_eq!(header.file_name, PathBuf::from("file.txt"));
+
+        // a dumpdir is a directory, with the names in it skipped
+        let serialized = entry_with_type_flag(sample_header("dir"), b'D', b"Yfile.txt\0\0")?;
+        let header = read_header(&serialized)?;
+        assert_eq!(header.type_flag, TypeFlag::Directory);
+        assert_eq!(header.file_size, 0);
+
+        // other unknown types are regular files, with their data left to be read
+        let serialized = entry_with_type_flag(sample_header("vendor"), b'A', b"")?;
+        let header = Header::deserialize(&serialized)?;
+        assert_eq!(header.type_flag, TypeFlag::Regular);
+        Ok(())
+    }
+
+    #[test]
+    fn test_gnu_long_names() -> anyhow::Result<()> {
+        let mut header = sample_header(&"f".repeat(150));
+        header.type_flag = TypeFlag::SymLink;
+        header.link_name = Some(PathBuf::from("l".repeat(120)));
+        header.user_id = 1 << 30;
+        let serialized = header.clone().serialize(TarFormat::Gnu)?;
+        // a longlink and a longname record, each with one block of data, and the header
+        assert_eq!(serialized.len(), 5 * BLOCK_SIZE);
+        assert_eq!(serialized[156], b'K');
+        assert_eq!(serialized[2 * BLOCK_SIZE + 156], b'L');
+
+        let deserialized = read_header(&serialized)?;
+        assert_eq!(deserialized.file_name, header.file_name);
+        assert_eq!(deserialized.link_name, header.link_name);
+        assert_eq!(deserialized.user_id, header.user_id);
+        Ok(())
+    }
+
+    #[test]
+    fn test_pax_extended_headers() -> anyhow::Result<()> {
+        let mut header = sample_header(&format!("{}/ünïcödé.txt", "d".repeat(300)));
+        header.file_size = 10_000_000_000;
+        header.last_modified_nanos = 123_456_789;
+        header.user_name = "u".repeat(40);
+        let serialized = header.clone().serialize(TarFormat::Pax)?;
+        assert_eq!(serialized[156], b'x');
+
+        let deserialized = read_header(&serialized)?;
+        assert_eq!(deserialized.file_name, header.file_name);
+        assert_eq!(deserialized.file_size, header.file_size);
+        assert_eq!(deserialized.last_modified, header.last_modified);
+        assert_eq!(deserialized.last_modified_nanos, header.last_modified_nanos);
+        assert_eq!(deserialized.user_name, header.user_name);
+
+        // a header that fits in ustar needs no extended header
+        let serialized = sample_header("test_file.txt").serialize(TarFormat::Pax)?;
+        assert_eq!(serialized.len(), BLOCK_SIZE);
+        Ok(())
+    }
+
+    #[test]
+    fn test_pax_global_headers() -> anyhow::Result<()> {
+        let mut archive = extension_header(b'g', b"16 uname=global\n")?;
+        archive.extend(sample_header("test_file.txt").serialize(TarFormat::Ustar)?);
+        let mut globals = Extensions::default();
+        let mut rest = &archive[BLOCK_SIZE..];
+        let header = Header::read(&mut rest, &archive[..BLOCK_SIZE], &mut globals)?;
+        assert_eq!(header.user_name, "global");
+        assert_eq!(globals.user_name.as_deref(), Some("global"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_pax_global_headers_per_archive() -> anyhow::Result<()> {
+        let archive = |global: bool| -> anyhow::Result<Vec<u8>> {
+            let mut archive = Vec::new();
+            if global {
+                archive.extend(extension_header(b'g', b"16 uname=global\n")?);
+            }
+            let mut header = sample_header("test_file.txt");
+            header.file_size = 0;
+            archive.extend(header.serialize(TarFormat::Ustar)?);
+            archive.extend([0; 2 * BLOCK_SIZE]);
+            Ok(archive)
+        };
+        // the globals of an archive don't apply to the next archive read by the same backend
+        let mut packer = TarArchive::new();
+        for (global, user_name) in [(true, "global"), (false, "user")] {
+            let entries = crate::list(&mut packer, archive(global)?.as_slice())?;
+            assert_eq!(entries[0].user_name.as_deref(), Some(user_name));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_ustar_limits() {
+        let mut header = sample_header("test_file.txt");
+        header.file_size = 10_000_000_000;
+        assert!(header.serialize(TarFormat::Ustar).is_err());
+
+        let mut header = sample_header("test_file.txt");
+        header.last_modified = -1;
+        assert!(header.serialize(TarFormat::Ustar).is_err());
+
+        let mut header = sample_header("test_file.txt");
+        header.link_name = Some(PathBuf::from("l".repeat(101)));
+        assert!(header.serialize(TarFormat::Ustar).is_err());
     }
 }
@@ -0,0 +1,45 @@
+//! Packer is a file archiving library, like tar and others.
+//!
+//! It packs up files, directories, symlinks, hard links and special files (FIFOs and devices) into a
+//! single archive file, and unpacks them back into a destination directory. Sockets are skipped.
+//! Each archive format is implemented by a backend (see [`PackerBackend`]). Currently supported
+//! formats are -
+//!
+//! - **bag** : a custom, compact archive format. See [`BagArchive`].
+//! - **tar** : the tar archive format. See [`TarArchive`].
+//!
+//! The functions [`pack`] and [`unpack`] take a backend and do the rest of the work.
+//!
+//! Archives are written to any [`std::io::Write`] and read from any [`std::io::Read`], so they
+//! don't have to touch the filesystem -
+//!
+//! ```no_run
+//! use std::path::PathBuf;
+//!
+//! use packer::BagArchive;
+//!
+//! fn main() -> anyhow::Result<()> {
+//!     let mut packer = BagArchive::new();
+//!     let files = vec![PathBuf::from("some/path/mydir")];
+//!     let mut archive = Vec::new();
+//!     packer::pack(&packer, &mut archive, &files)?;
+//!     packer::unpack(&mut packer, archive.as_slice(), PathBuf::from("/tmp/dest"))?;
+//!     Ok(())
+//! }
+//! ```
+
+pub mod archive;
+pub mod backend;
+#[cfg(test)]
+mod testing;
+
+pub use archive::{
+    detect_format, list, list_index, list_seekable, pack, pack_with_options, unpack,
+    unpack_seekable, unpack_with_options, verify, DetectedFormat, Overwrite, OwnerMap, PackOptions,
+    UnpackOptions, VerifyProblem, VerifyReport,
+};
+pub use backend::bag::{BagArchive, BagCompression, BagDigest};
+pub use backend::tar::{TarArchive, TarCompression, TarEncoder, TarFormat};
+pub use backend::{
+    AsHeader, DamagedData, FileMetadata, FilePath, FileType, IndexEntry, PackerBackend,
+};
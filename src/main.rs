@@ -1,25 +1,30 @@
-mod archive;
-mod backend;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Cursor, IsTerminal, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
 
-use std::path::PathBuf;
-
-use anyhow::{self, bail};
+use anyhow::{self, bail, Context};
+use chrono::{DateTime, Local};
 use clap::{Parser, Subcommand};
 use colog::format::CologStyle;
 
-use backend::bag::BagArchive;
-use backend::tar::TarArchive;
+use packer::backend::tar;
+use packer::{
+    archive, BagArchive, BagCompression, BagDigest, DetectedFormat, FileMetadata, FileType,
+    OwnerMap, PackerBackend, TarArchive, TarCompression, UnpackOptions,
+};
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 struct Cli {
-    /// Pack/Unpack an archive
+    /// Pack/Unpack/List/Verify an archive
     #[command(subcommand)]
     command: Command,
 
-    /// Archive format to use.
-    #[arg(short, long, default_value_t, value_enum)]
-    format: Format,
+    /// Archive format to use. When packing, defaults to tar if the output file has a tar extension
+    /// (like `.tar` or `.tar.gz`), and to bag otherwise. When unpacking or listing, the format is
+    /// detected from the archive, unless this is given.
+    #[arg(short, long, value_enum)]
+    format: Option<Format>,
 
     /// Turn debugging information on
     #[arg(short, long, default_value_t, value_enum)]
@@ -33,28 +38,179 @@ enum Command {
         /// List of files (i.e. their paths) to pack up.
         #[arg(short, long, required(true), num_args(1..))]
         input_files: Vec<PathBuf>,
-        /// Path to the output archive file.
+        /// Path to the output archive file. Use `-` to write the archive to stdout.
         #[arg(short, long)]
         output_path: PathBuf,
+        /// Format of the tar headers, when the tar archive format is used.
+        #[arg(long, default_value_t, value_enum)]
+        tar_format: TarFormat,
+        /// Compression of the archive. Bag archives compress the data of each file, tar archives
+        /// are compressed as a whole. For tar, defaults to the one of the output file's extension,
+        /// like `.tar.gz`.
+        #[arg(long, value_enum)]
+        compression: Option<Compression>,
+        /// Compression level. The default level of the compression is used if not given.
+        #[arg(long, allow_negative_numbers(true))]
+        level: Option<i32>,
+        /// Digest stored with the data of each file, to detect corrupted data when unpacking.
+        /// Only for bag archives; defaults to crc32c.
+        #[arg(long, value_enum)]
+        digest: Option<Digest>,
+        /// Don't write an index at the end of a bag archive. The index lets files be listed and
+        /// extracted from the archive without reading all of it.
+        #[arg(long)]
+        no_index: bool,
+        /// Leave out the files matching the glob pattern, like `*.log` or `mydir/cache`. A pattern
+        /// without a `/` matches a file by its name, at any depth. Excluded directories are not
+        /// read at all. Can be given multiple times.
+        #[arg(long, value_name = "PATTERN")]
+        exclude: Vec<String>,
+        /// Pack only the files matching the glob pattern (and the directories containing them);
+        /// matched like `--exclude`, which takes precedence. Can be given multiple times.
+        #[arg(long, value_name = "PATTERN")]
+        include: Vec<String>,
+        /// Read exclude patterns from the file; one on each line. Can be given multiple times.
+        #[arg(long, value_name = "FILE")]
+        exclude_from: Vec<PathBuf>,
+        /// Leave out the directories of version control systems (.git, .hg, .svn, .bzr) and
+        /// build outputs (target).
+        #[arg(long)]
+        exclude_vcs: bool,
+        /// Leave out the files ignored by git; by .gitignore and .ignore files, .git/info/exclude
+        /// and the global excludes file of git.
+        #[arg(long)]
+        respect_gitignore: bool,
     },
     /// Unpack files from an archive.
     Unpack {
-        /// File path to the bag archive file.
+        /// File path to the archive file. Use `-` to read the archive from stdin.
         #[arg(short, long)]
         input_path: PathBuf,
         /// Destination directory where all of the contents will be unpacked.
         #[arg(short, long)]
         output_path: PathBuf,
+        /// Paths or glob patterns (like `etc/*.conf`) of the files to unpack. A directory unpacks
+        /// everything inside it. By default all files are unpacked.
+        paths: Vec<String>,
+        /// Unpack paths with `..` components or a leading `/` as they are, and allow writing
+        /// through symlinks unpacked from the archive. By default such paths are refused, and the
+        /// leading `/` is removed. Only use this for trusted archives.
+        #[arg(long)]
+        allow_unsafe_paths: bool,
+        /// Restore the owner and group of the files. This is the default when running as root.
+        #[arg(long, overrides_with = "no_same_owner")]
+        same_owner: bool,
+        /// Don't restore the owner and group of the files; they are owned by the user unpacking
+        /// them. This is the default when not running as root.
+        #[arg(long, overrides_with = "same_owner")]
+        no_same_owner: bool,
+        /// Restore the owner and group by the numeric IDs stored in the archive, instead of by
+        /// the user and group names.
+        #[arg(long)]
+        numeric_owner: bool,
+        /// File mapping the owners in the archive to users of this system; with a mapping like
+        /// `alice bob` or `+1000 2000` (by numeric ID) on each line.
+        #[arg(long)]
+        owner_map: Option<PathBuf>,
+        /// File mapping the groups in the archive to groups of this system; with a mapping like
+        /// `staff users` or `+1000 2000` (by numeric ID) on each line.
+        #[arg(long)]
+        group_map: Option<PathBuf>,
+        /// Warn about files whose owner and group can't be restored for lack of permission.
+        #[arg(long)]
+        warn_owner: bool,
+        /// What to do with files that already exist in the destination directory. Existing
+        /// directories are unpacked into.
+        #[arg(long, value_enum, default_value_t = Overwrite::Always)]
+        overwrite: Overwrite,
+        /// Flush each file to disk before renaming it into place. Files are always written to a
+        /// temporary file first, so that they are never seen partially written.
+        #[arg(long)]
+        sync: bool,
+        /// Unpack into a staging directory, and rename it to the destination directory once
+        /// everything is unpacked. The destination has to be empty or not exist.
+        #[arg(long)]
+        staging: bool,
+    },
+    /// List the contents of an archive, without unpacking it.
+    List {
+        /// File path to the archive file. Use `-` to read the archive from stdin.
+        #[arg(short, long)]
+        input_path: PathBuf,
+        /// Use a long listing format, like `ls -l`, showing the type, mode, owner, size and
+        /// modified time of each file. By default only the names are listed.
+        #[arg(long)]
+        long: bool,
+    },
+    /// Check the integrity of an archive, without unpacking it. Exits with an error if any
+    /// problems are found.
+    Verify {
+        /// File path to the archive file. Use `-` to read the archive from stdin.
+        #[arg(short, long)]
+        input_path: PathBuf,
     },
 }
 
-#[derive(Clone, clap::ValueEnum, Default, Debug)]
+#[derive(Clone, Copy, clap::ValueEnum, Default, Debug)]
 enum Format {
     #[default]
     Bag,
     Tar,
 }
 
+#[derive(Clone, clap::ValueEnum, Default, Debug)]
+enum TarFormat {
+    /// Plain POSIX ustar; fails if a file can't be stored in a ustar header.
+    Ustar,
+    /// GNU tar format; uses GNU longname/longlink records for long paths.
+    Gnu,
+    /// POSIX pax format; uses pax extended headers for anything that doesn't fit in ustar.
+    #[default]
+    Pax,
+}
+
+#[derive(Clone, Copy, clap::ValueEnum, Default, Debug)]
+enum Compression {
+    #[default]
+    None,
+    /// Zstandard; levels 1 to 22.
+    Zstd,
+    /// Deflate, as used by gzip; levels 0 to 9.
+    #[value(alias("deflate"))]
+    Gzip,
+    /// xz; levels 0 to 9.
+    Xz,
+    /// LZ4; very fast, has no levels. Only for bag archives.
+    Lz4,
+    /// bzip2; levels 1 to 9. Only for tar archives.
+    Bzip2,
+}
+
+#[derive(Clone, Copy, clap::ValueEnum, Debug)]
+enum Overwrite {
+    /// Replace existing files.
+    Always,
+    /// Keep existing files.
+    Never,
+    /// Replace existing files older than the ones in the archive.
+    Newer,
+    /// Rename existing files to `<name>.orig` (or `<name>.orig.<n>`) first.
+    Backup,
+    /// Fail if a file already exists.
+    Error,
+}
+
+#[derive(Clone, Copy, clap::ValueEnum, Debug)]
+enum Digest {
+    None,
+    /// CRC32C; fast, detects accidental corruption.
+    Crc32c,
+    /// BLAKE3; a fast cryptographic hash.
+    Blake3,
+    /// SHA-256.
+    Sha256,
+}
+
 #[derive(Clone, clap::ValueEnum, Default, Debug)]
 enum LogLevel {
     Error,
@@ -93,10 +249,29 @@ fn main() -> anyhow::Result<()> {
         Command::Pack {
             input_files,
             output_path,
+            tar_format,
+            compression,
+            level,
+            digest,
+            no_index,
+            exclude,
+            include,
+            exclude_from,
+            exclude_vcs,
+            respect_gitignore,
         } => {
             if input_files.is_empty() {
                 bail!("No input files provided. Atleast one input file is required.");
             }
+            let mut options = archive::PackOptions {
+                exclude,
+                include,
+                exclude_vcs,
+                respect_gitignore,
+            };
+            for path in &exclude_from {
+                options.read_exclude_file(path)?;
+            }
 
             log::info!(
                 "Creating an archive at {}, for files: {}",
@@ -107,14 +282,36 @@ fn main() -> anyhow::Result<()> {
                     .collect::<Vec<_>>()
                     .join(", "),
             );
-            match cli.format {
+            let extension_compression = TarCompression::from_path(&output_path);
+            let format = cli
+                .format
+                .or(extension_compression.map(|_| Format::Tar))
+                .unwrap_or_default();
+            match format {
                 Format::Bag => {
-                    let packer = BagArchive::new();
-                    archive::pack(&packer, output_path, &input_files)?;
+                    let compression = mk_bag_compression(compression.unwrap_or_default())?;
+                    let digest = digest.map_or(BagDigest::default(), mk_bag_digest);
+                    let packer = BagArchive::with_compression(compression, level)?
+                        .with_digest(digest)
+                        .with_index(!no_index);
+                    let writer = open_output(&output_path)?;
+                    archive::pack_with_options(&packer, writer, &input_files, &options)?;
                 }
                 Format::Tar => {
-                    let packer = TarArchive::new();
-                    archive::pack(&packer, output_path, &input_files)?;
+                    if digest.is_some() {
+                        bail!("Digests are only supported for bag archives.");
+                    }
+                    let compression = match compression {
+                        Some(compression) => mk_tar_compression(compression)?,
+                        None => extension_compression.unwrap_or_default(),
+                    };
+                    if let Some(level) = level {
+                        compression.validate_level(level)?;
+                    }
+                    let packer = TarArchive::with_format(mk_tar_format(tar_format));
+                    let mut writer = compression.encoder(open_output(&output_path)?, level)?;
+                    archive::pack_with_options(&packer, &mut writer, &input_files, &options)?;
+                    writer.finish()?.flush()?;
                 }
             }
             log::info!("Done.");
@@ -122,11 +319,24 @@ fn main() -> anyhow::Result<()> {
         Command::Unpack {
             input_path,
             output_path,
+            paths,
+            allow_unsafe_paths,
+            same_owner,
+            no_same_owner,
+            numeric_owner,
+            owner_map,
+            group_map,
+            warn_owner,
+            overwrite,
+            sync,
+            staging,
         } => {
-            if !input_path.is_file() {
-                bail!("Input file has to be a bag archive.");
+            if !is_stdio(&input_path) && !input_path.is_file() {
+                bail!("Input file has to be an archive.");
             }
-            if !output_path.is_dir() {
+            // with a staging directory, the output directory may be created at the end
+            let created_later = staging && !output_path.exists();
+            if !output_path.is_dir() && !created_later {
                 bail!("Output path has to be a directory where all contents of the archive will be unpacked.");
             }
             log::info!(
@@ -134,23 +344,383 @@ fn main() -> anyhow::Result<()> {
                 input_path.display(),
                 output_path.display()
             );
-            match cli.format {
+            let options = UnpackOptions {
+                paths,
+                allow_unsafe_paths,
+                same_owner: match (same_owner, no_same_owner) {
+                    (true, _) => Some(true),
+                    (_, true) => Some(false),
+                    _ => None,
+                },
+                numeric_owner,
+                owner_map: owner_map
+                    .as_deref()
+                    .map(OwnerMap::read_users)
+                    .transpose()?
+                    .unwrap_or_default(),
+                group_map: group_map
+                    .as_deref()
+                    .map(OwnerMap::read_groups)
+                    .transpose()?
+                    .unwrap_or_default(),
+                warn_owner,
+                overwrite: mk_overwrite(overwrite),
+                sync,
+                staging,
+            };
+            let (format, input) = open_input(&input_path, cli.format)?;
+            match format {
                 Format::Bag => {
-                    let packer = BagArchive::new();
-                    archive::unpack(&packer, input_path, output_path)?;
+                    let mut packer = BagArchive::new();
+                    unpack_archive(&mut packer, input, output_path, &options)?;
                 }
                 Format::Tar => {
-                    let packer = TarArchive::new();
-                    archive::unpack(&packer, input_path, output_path)?;
+                    let mut packer = TarArchive::new();
+                    unpack_archive(&mut packer, input, output_path, &options)?;
                 }
             }
             log::info!("Done.");
         }
+        Command::List { input_path, long } => {
+            if !is_stdio(&input_path) && !input_path.is_file() {
+                bail!("Input file has to be an archive.");
+            }
+            let (format, input) = open_input(&input_path, cli.format)?;
+            let mut stdout = io::stdout().lock();
+            if long {
+                let entries = match format {
+                    Format::Bag => list_archive(&mut BagArchive::new(), input)?,
+                    Format::Tar => list_archive(&mut TarArchive::new(), input)?,
+                };
+                print_long_listing(&mut stdout, &entries)?;
+            } else {
+                let file_names = match format {
+                    Format::Bag => list_file_names(&mut BagArchive::new(), input)?,
+                    Format::Tar => list_file_names(&mut TarArchive::new(), input)?,
+                };
+                for file_name in &file_names {
+                    writeln!(stdout, "{}", file_name.display())?;
+                }
+            }
+        }
+        Command::Verify { input_path } => {
+            if !is_stdio(&input_path) && !input_path.is_file() {
+                bail!("Input file has to be an archive.");
+            }
+            let (format, input) = open_input(&input_path, cli.format)?;
+            let report = match format {
+                Format::Bag => archive::verify(&mut BagArchive::new(), input.into_reader()),
+                Format::Tar => archive::verify(&mut TarArchive::new(), input.into_reader()),
+            };
+            let mut stdout = io::stdout().lock();
+            for problem in &report.problems {
+                match &problem.file_name {
+                    Some(file_name) => {
+                        writeln!(stdout, "{}: {}", file_name.display(), problem.message)?
+                    }
+                    None => writeln!(stdout, "{}", problem.message)?,
+                }
+            }
+            if !report.is_ok() {
+                bail!(
+                    "Found {} problem(s) in the archive, after checking {} file(s).",
+                    report.problems.len(),
+                    report.entries.len()
+                );
+            }
+            log::info!(
+                "Verified {} file(s); no problems found.",
+                report.entries.len()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// List an archive. The data of the files is seeked past in files, and read in streams.
+fn list_archive<T: PackerBackend>(
+    packer: &mut T,
+    input: Input,
+) -> anyhow::Result<Vec<FileMetadata>> {
+    match input {
+        Input::File(reader) => archive::list_seekable(packer, reader),
+        Input::Stream(reader) => archive::list(packer, reader),
     }
+}
+
+/// List the paths of the files in an archive. They are read from the index of the archive if it has
+/// one, without reading the headers of the files.
+fn list_file_names<T: PackerBackend>(packer: &mut T, input: Input) -> anyhow::Result<Vec<PathBuf>> {
+    let input = match input {
+        Input::File(mut reader) => match archive::list_index(packer, &mut reader)? {
+            Some(index) => return Ok(index.into_iter().map(|entry| entry.file_name).collect()),
+            None => Input::File(reader),
+        },
+        input => input,
+    };
+    let entries = list_archive(packer, input)?;
+    Ok(entries.into_iter().map(|entry| entry.file_name).collect())
+}
+
+/// Print the entries in a format similar to `ls -l`; with the columns aligned.
+fn print_long_listing<W: Write>(out: &mut W, entries: &[FileMetadata]) -> anyhow::Result<()> {
+    let owners: Vec<String> = entries
+        .iter()
+        .map(|entry| {
+            let user = entry.user_name.clone();
+            let group = entry.group_name.clone();
+            format!(
+                "{}/{}",
+                user.unwrap_or_else(|| entry.user_id.to_string()),
+                group.unwrap_or_else(|| entry.group_id.to_string())
+            )
+        })
+        .collect();
+    let sizes: Vec<String> = entries
+        .iter()
+        .map(|entry| match entry.file_type {
+            FileType::CharDevice | FileType::BlockDevice => {
+                format!("{},{}", entry.dev_major, entry.dev_minor)
+            }
+            _ => entry.file_size.to_string(),
+        })
+        .collect();
+    let owner_width = owners.iter().map(String::len).max().unwrap_or(0);
+    let size_width = sizes.iter().map(String::len).max().unwrap_or(0);
 
+    for ((entry, owner), size) in entries.iter().zip(&owners).zip(&sizes) {
+        let mtime = DateTime::from_timestamp(entry.last_modified, entry.last_modified_nanos)
+            .map(|time| {
+                time.with_timezone(&Local)
+                    .format("%Y-%m-%d %H:%M")
+                    .to_string()
+            })
+            .unwrap_or_else(|| entry.last_modified.to_string());
+        write!(
+            out,
+            "{} {:<owner_width$} {:>size_width$} {} {}",
+            format_mode(entry.file_type, entry.file_mode),
+            owner,
+            size,
+            mtime,
+            entry.file_name.display(),
+        )?;
+        match (&entry.file_type, &entry.link_name) {
+            (FileType::SymLink, Some(target)) => write!(out, " -> {}", target.display())?,
+            (FileType::HardLink, Some(target)) => write!(out, " link to {}", target.display())?,
+            _ => {}
+        }
+        writeln!(out)?;
+    }
     Ok(())
 }
 
+/// Format the file type and mode like `ls -l`, e.g. `drwxr-xr-x`.
+fn format_mode(file_type: FileType, mode: u32) -> String {
+    let mut formatted = String::with_capacity(10);
+    formatted.push(match file_type {
+        FileType::Regular | FileType::HardLink => '-',
+        FileType::SymLink => 'l',
+        FileType::CharDevice => 'c',
+        FileType::BlockDevice => 'b',
+        FileType::Directory => 'd',
+        FileType::Fifo => 'p',
+    });
+    // permissions of user, group and others; along with the setuid, setgid and sticky bits, which
+    // are shown in place of the execute permission.
+    for (shift, special_bit, special_char) in [(6, 0o4000, 's'), (3, 0o2000, 's'), (0, 0o1000, 't')]
+    {
+        let bits = (mode >> shift) & 0o7;
+        formatted.push(if bits & 0o4 != 0 { 'r' } else { '-' });
+        formatted.push(if bits & 0o2 != 0 { 'w' } else { '-' });
+        formatted.push(match (mode & special_bit != 0, bits & 0o1 != 0) {
+            (true, true) => special_char,
+            (true, false) => special_char.to_ascii_uppercase(),
+            (false, true) => 'x',
+            (false, false) => '-',
+        });
+    }
+    formatted
+}
+
+/// The path `-` stands for stdin/stdout.
+fn is_stdio(path: &Path) -> bool {
+    path == Path::new("-")
+}
+
+/// Open the archive to write to; either a file or stdout.
+fn open_output(path: &Path) -> anyhow::Result<Box<dyn Write>> {
+    select_output(path, io::stdout().lock())
+}
+
+/// Select where to write the archive to by its path; `stdout` for `-`, or else a new file.
+fn select_output<S: Write + IsTerminal + 'static>(
+    path: &Path,
+    stdout: S,
+) -> anyhow::Result<Box<dyn Write>> {
+    if is_stdio(path) {
+        if stdout.is_terminal() {
+            bail!(
+                "Refusing to write the archive to a terminal. Redirect stdout or use a file path."
+            );
+        }
+        Ok(Box::new(BufWriter::new(stdout)))
+    } else {
+        Ok(Box::new(BufWriter::new(File::create(path)?)))
+    }
+}
+
+/// Unpack an archive. The data of skipped files is seeked past in files, and read in streams.
+fn unpack_archive<T: PackerBackend>(
+    packer: &mut T,
+    input: Input,
+    output_path: PathBuf,
+    options: &UnpackOptions,
+) -> anyhow::Result<()> {
+    match input {
+        Input::File(reader) => archive::unpack_seekable(packer, reader, output_path, options),
+        Input::Stream(reader) => archive::unpack_with_options(packer, reader, output_path, options),
+    }
+}
+
+/// The archive to read from. Files can be seeked, but stdin and decompressed archives can't be.
+enum Input {
+    File(BufReader<File>),
+    Stream(Box<dyn Read>),
+}
+
+impl Input {
+    fn into_reader(self) -> Box<dyn Read> {
+        match self {
+            Input::File(reader) => Box::new(reader),
+            Input::Stream(reader) => reader,
+        }
+    }
+}
+
+/// Open the archive to read from; either a file or stdin. See [`prepare_input`].
+fn open_input(path: &Path, format: Option<Format>) -> anyhow::Result<(Format, Input)> {
+    prepare_input(select_input(path, io::stdin().lock())?, format)
+}
+
+/// Select where to read the archive from by its path; `stdin` for `-`, which is read as a stream,
+/// or else the file.
+fn select_input<S: Read + 'static>(path: &Path, stdin: S) -> anyhow::Result<Input> {
+    if is_stdio(path) {
+        Ok(Input::Stream(Box::new(stdin)))
+    } else {
+        Ok(Input::File(BufReader::new(File::open(path)?)))
+    }
+}
+
+/// Get ready to read the archive. A compressed archive (like `.tar.gz`) is decompressed, and so is
+/// read as a stream. The format of the archive is detected from its first bytes, unless it is
+/// given.
+fn prepare_input(input: Input, format: Option<Format>) -> anyhow::Result<(Format, Input)> {
+    let (prefix, input) = match input {
+        Input::File(mut reader) => {
+            let prefix = archive::read_prefix(&mut reader)?;
+            reader.seek(SeekFrom::Start(0))?;
+            (prefix, Input::File(reader))
+        }
+        Input::Stream(mut reader) => {
+            let prefix = archive::read_prefix(&mut reader)?;
+            let reader = BufReader::new(Cursor::new(prefix.clone()).chain(reader));
+            (prefix, Input::Stream(Box::new(reader)))
+        }
+    };
+
+    let compression = archive::detect_format(&prefix).and_then(TarCompression::from_detected);
+    let Some(compression) = compression else {
+        return Ok((resolve_format(format, &prefix)?, input));
+    };
+    log::debug!("The archive is compressed with {:?}.", compression);
+    let mut decoder = compression.decoder(input.into_reader())?;
+    let prefix = archive::read_prefix(&mut decoder)
+        .with_context(|| format!("Unable to decompress the archive ({:?})", compression))?;
+    let format = resolve_format(format, &prefix)?;
+    let reader = BufReader::new(Cursor::new(prefix).chain(decoder));
+    Ok((format, Input::Stream(Box::new(reader))))
+}
+
+/// Get the format of an archive; the given format if any, otherwise the one detected from its
+/// first bytes.
+fn resolve_format(format: Option<Format>, prefix: &[u8]) -> anyhow::Result<Format> {
+    let detected = archive::detect_format(prefix);
+    if let Some(format) = format {
+        if let (Some(detected @ DetectedFormat::Bag), Format::Tar)
+        | (Some(detected @ DetectedFormat::Tar), Format::Bag) = (detected, format)
+        {
+            log::warn!(
+                "The archive looks like {:?}, but is read as {:?} as requested.",
+                detected,
+                format
+            );
+        }
+        return Ok(format);
+    }
+    match detected {
+        Some(DetectedFormat::Bag) => Ok(Format::Bag),
+        Some(DetectedFormat::Tar) => Ok(Format::Tar),
+        Some(DetectedFormat::Zip) => bail!("Zip archives are not supported."),
+        Some(compression) => bail!(
+            "The archive is compressed ({:?}) more than once, which is not supported.",
+            compression
+        ),
+        None => bail!("Unable to detect the format of the archive. Pass it with --format."),
+    }
+}
+
+fn mk_tar_format(format: TarFormat) -> tar::TarFormat {
+    match format {
+        TarFormat::Ustar => tar::TarFormat::Ustar,
+        TarFormat::Gnu => tar::TarFormat::Gnu,
+        TarFormat::Pax => tar::TarFormat::Pax,
+    }
+}
+
+fn mk_bag_compression(compression: Compression) -> anyhow::Result<BagCompression> {
+    match compression {
+        Compression::None => Ok(BagCompression::None),
+        Compression::Zstd => Ok(BagCompression::Zstd),
+        Compression::Gzip => Ok(BagCompression::Deflate),
+        Compression::Xz => Ok(BagCompression::Xz),
+        Compression::Lz4 => Ok(BagCompression::Lz4),
+        Compression::Bzip2 => bail!("bzip2 compression is not supported for bag archives."),
+    }
+}
+
+fn mk_overwrite(overwrite: Overwrite) -> archive::Overwrite {
+    match overwrite {
+        Overwrite::Always => archive::Overwrite::Always,
+        Overwrite::Never => archive::Overwrite::Never,
+        Overwrite::Newer => archive::Overwrite::Newer,
+        Overwrite::Backup => archive::Overwrite::Backup,
+        Overwrite::Error => archive::Overwrite::Error,
+    }
+}
+
+fn mk_bag_digest(digest: Digest) -> BagDigest {
+    match digest {
+        Digest::None => BagDigest::None,
+        Digest::Crc32c => BagDigest::Crc32c,
+        Digest::Blake3 => BagDigest::Blake3,
+        Digest::Sha256 => BagDigest::Sha256,
+    }
+}
+
+fn mk_tar_compression(compression: Compression) -> anyhow::Result<TarCompression> {
+    match compression {
+        Compression::None => Ok(TarCompression::None),
+        Compression::Zstd => Ok(TarCompression::Zstd),
+        Compression::Gzip => Ok(TarCompression::Gzip),
+        Compression::Xz => Ok(TarCompression::Xz),
+        Compression::Bzip2 => Ok(TarCompression::Bzip2),
+        Compression::Lz4 => bail!("lz4 compression is not supported for tar archives."),
+    }
+}
+
 fn mk_log_level_filter(level: LogLevel) -> log::LevelFilter {
     match level {
         LogLevel::Error => log::LevelFilter::Error,
@@ -160,3 +730,100 @@ fn mk_log_level_filter(level: LogLevel) -> log::LevelFilter {
         LogLevel::Trace => log::LevelFilter::Trace,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parse the unpack command with the given flags; returning its `same_owner` and
+    /// `no_same_owner` flags.
+    fn same_owner_flags(flags: &[&str]) -> anyhow::Result<(bool, bool)> {
+        let args = ["packer", "unpack", "-i", "in.bag", "-o", "out"];
+        let cli = Cli::try_parse_from(args.iter().chain(flags))?;
+        let Command::Unpack {
+            same_owner,
+            no_same_owner,
+            ..
+        } = cli.command
+        else {
+            bail!("Not parsed as unpack");
+        };
+        Ok((same_owner, no_same_owner))
+    }
+
+    #[test]
+    fn test_same_owner_flags() -> anyhow::Result<()> {
+        assert_eq!(same_owner_flags(&[])?, (false, false));
+        // the last one of the flags wins
+        assert_eq!(
+            same_owner_flags(&["--no-same-owner", "--same-owner"])?,
+            (true, false)
+        );
+        assert_eq!(
+            same_owner_flags(&["--same-owner", "--no-same-owner"])?,
+            (false, true)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_select_input() -> anyhow::Result<()> {
+        let root = std::env::temp_dir().join("packer_select_input");
+        let _ = std::fs::remove_dir_all(&root);
+        let input = root.join("input");
+        std::fs::create_dir_all(&input)?;
+        std::fs::write(input.join("file.txt"), b"data")?;
+        let mut bag = Vec::new();
+        archive::pack(&BagArchive::new(), &mut bag, std::slice::from_ref(&input))?;
+        let bag_path = root.join("archive.bag");
+        std::fs::write(&bag_path, &bag)?;
+        let mut encoder = TarCompression::Gzip.encoder(Vec::new(), None)?;
+        archive::pack(&TarArchive::new(), &mut encoder, &[input])?;
+        let tar_path = root.join("archive.tar.gz");
+        std::fs::write(&tar_path, encoder.finish()?)?;
+
+        // `-` is stdin, which is read as a stream, without seeking
+        let input = select_input(Path::new("-"), Cursor::new(bag))?;
+        let (format, input) = prepare_input(input, None)?;
+        assert!(matches!((format, &input), (Format::Bag, Input::Stream(_))));
+        assert_eq!(list_archive(&mut BagArchive::new(), input)?.len(), 2);
+
+        // files are seeked, unless they are compressed
+        let (format, input) = prepare_input(select_input(&bag_path, io::empty())?, None)?;
+        assert!(matches!((format, &input), (Format::Bag, Input::File(_))));
+        assert_eq!(list_archive(&mut BagArchive::new(), input)?.len(), 2);
+        let (format, input) = prepare_input(select_input(&tar_path, io::empty())?, None)?;
+        assert!(matches!((format, &input), (Format::Tar, Input::Stream(_))));
+        assert_eq!(list_archive(&mut TarArchive::new(), input)?.len(), 2);
+
+        std::fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_select_output() -> anyhow::Result<()> {
+        let root = std::env::temp_dir().join("packer_select_output");
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root)?;
+        let stdout_path = root.join("stdout");
+        let archive_path = root.join("archive.bag");
+
+        // `-` is stdout
+        let mut writer = select_output(Path::new("-"), File::create(&stdout_path)?)?;
+        writer.write_all(b"archive")?;
+        writer.flush()?;
+        drop(writer);
+        assert_eq!(std::fs::read(&stdout_path)?, b"archive");
+        assert!(!archive_path.exists());
+
+        let mut writer = select_output(&archive_path, File::create(&stdout_path)?)?;
+        writer.write_all(b"archive")?;
+        writer.flush()?;
+        drop(writer);
+        assert_eq!(std::fs::read(&archive_path)?, b"archive");
+        assert!(std::fs::read(&stdout_path)?.is_empty());
+
+        std::fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+}
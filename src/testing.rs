@@ -0,0 +1,91 @@
+//! Helpers shared by the tests of the crate.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::{list, pack, unpack, PackerBackend};
+
+/// A temporary directory for a test. It is created empty, and removed along with everything in it
+/// when dropped.
+pub(crate) struct TestDir {
+    root: PathBuf,
+}
+
+impl TestDir {
+    /// Create the directory with the given name in the temporary directory of the system. Anything
+    /// left there by a previous run is removed. The names have to be unique among the tests, as
+    /// they run in parallel.
+    pub(crate) fn new(name: &str) -> anyhow::Result<Self> {
+        let root = std::env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root)?;
+        Ok(Self { root })
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.root
+    }
+
+    pub(crate) fn join<P: AsRef<Path>>(&self, path: P) -> PathBuf {
+        self.root.join(path)
+    }
+
+    /// Write files with the given (path, contents) inside the directory, creating their parent
+    /// directories.
+    pub(crate) fn write_files(&self, files: &[(&str, &str)]) -> anyhow::Result<()> {
+        for (path, contents) in files {
+            let path = self.root.join(path);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(path, contents)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.root);
+    }
+}
+
+/// Pack the given files into an archive in memory.
+pub(crate) fn pack_files<T: PackerBackend>(
+    packer: &T,
+    files: &[PathBuf],
+) -> anyhow::Result<Vec<u8>> {
+    let mut archive = Vec::new();
+    pack(packer, &mut archive, files)?;
+    Ok(archive)
+}
+
+/// Pack the file (or directory) `input`, and unpack the archive into the directory `output`; which
+/// is created if it doesn't exist. Returns the archive.
+pub(crate) fn roundtrip<T: PackerBackend>(
+    packer: &mut T,
+    input: &Path,
+    output: &Path,
+) -> anyhow::Result<Vec<u8>> {
+    let archive = pack_files(packer, &[input.to_path_buf()])?;
+    fs::create_dir_all(output)?;
+    unpack(packer, archive.as_slice(), output.to_path_buf())?;
+    Ok(archive)
+}
+
+/// The paths of the entries of an archive, sorted. Tar stores directories with a trailing `/`,
+/// which is removed; so the paths are the same for all the backends.
+pub(crate) fn archive_paths<T: PackerBackend>(
+    packer: &mut T,
+    archive: &[u8],
+) -> anyhow::Result<Vec<String>> {
+    let mut paths: Vec<String> = list(packer, archive)?
+        .into_iter()
+        .map(|entry| {
+            let path = entry.file_name.display().to_string();
+            path.trim_end_matches('/').to_string()
+        })
+        .collect();
+    paths.sort();
+    Ok(paths)
+}